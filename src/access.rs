@@ -0,0 +1,23 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+/// Networks a [`Server`](crate::server::Server) accepts connections from, checked as soon
+/// as a connection is accepted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccessList {
+    /// Networks clients may connect from. Empty allows every address.
+    pub allow: Vec<IpNet>,
+    /// Networks clients may not connect from, even if `allow` covers them
+    pub deny: Vec<IpNet>,
+}
+
+impl AccessList {
+    /// Whether a client at `ip` may connect. IPv4 addresses mapped into IPv6 are checked
+    /// as IPv4.
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let allowed = self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip));
+        allowed && !self.deny.iter().any(|net| net.contains(&ip))
+    }
+}
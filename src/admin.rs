@@ -0,0 +1,279 @@
+use log::{info, warn};
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::Mutex,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    auth::{self, AuthConfig, Authentication},
+    codec::{self, FrameError, MAX_FRAME_LENGTH},
+    error::ProtocolError,
+    message::{
+        admin_request, admin_response, auth_request, AdminRequest, AdminResponse, ConnectionList,
+        Disconnected, ErrorCode, ErrorResponse,
+    },
+    registry::{ConnectionInfo, ConnectionRegistry},
+    server,
+};
+
+/// Listener answering [`AdminRequest`]s about the connections of a server, each admin
+/// connection on a thread of its own.
+pub(crate) struct AdminListener {
+    listener: TcpListener,
+    sessions: Mutex<Sessions>,
+    /// Credentials admin connections authenticate with, apart from the clients'
+    auth: Option<AuthConfig>,
+    /// Whether bearer tokens are accepted. They cross the network in cleartext unless the
+    /// listener is on loopback.
+    bearer_tokens: bool,
+}
+
+/// Admin connections being served, so `close` can end them.
+#[derive(Default)]
+struct Sessions {
+    streams: HashMap<usize, TcpStream>,
+    next_id: usize,
+    closed: bool,
+}
+
+impl AdminListener {
+    /// Binds `addr`, or the loopback address if `addr` is a bare port. Without `auth`,
+    /// anyone reaching the listener could disconnect clients, so only loopback addresses
+    /// are allowed then. Other addresses also need HMAC keys, as bearer tokens would cross
+    /// the network in cleartext.
+    pub(crate) fn bind(addr: &str, auth: Option<AuthConfig>) -> io::Result<Self> {
+        let addrs: Vec<SocketAddr> = match addr.parse::<u16>() {
+            Ok(port) => vec![SocketAddr::from((Ipv4Addr::LOCALHOST, port))],
+            Err(_) => addr.to_socket_addrs()?.collect(),
+        };
+        let open = addrs.iter().find(|addr| !addr.ip().is_loopback());
+        if let Some(open) = open.filter(|_| !auth.as_ref().is_some_and(AuthConfig::has_hmac_keys)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "admin listener on {} needs HMAC authentication, configure admin_auth with HMAC keys or bind it to loopback",
+                    open
+                ),
+            ));
+        }
+        Ok(AdminListener {
+            listener: TcpListener::bind(&addrs[..])?,
+            sessions: Mutex::default(),
+            bearer_tokens: open.is_none(),
+            auth,
+        })
+    }
+
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serves admin connections until [`close`](Self::close) is called, then waits for
+    /// every one of them to end
+    pub(crate) fn run(&self, connections: &ConnectionRegistry) {
+        if let Ok(addr) = self.local_addr() {
+            info!("Admin listener is running on {}", addr);
+        }
+        thread::scope(|scope| loop {
+            let stream = self.listener.accept();
+            if self.sessions.lock().unwrap().closed {
+                break;
+            }
+            match stream.and_then(|(stream, _)| self.open_session(stream)) {
+                Ok((session, stream)) => {
+                    scope.spawn(move || {
+                        serve(session, stream, connections, self);
+                        self.sessions.lock().unwrap().streams.remove(&session);
+                    });
+                }
+                Err(e) => {
+                    warn!("Failed to accept admin connection: {}", e);
+                    thread::sleep(Duration::from_millis(100));
+                }
+            }
+        });
+    }
+
+    /// Stops accepting admin connections and closes the open ones
+    pub(crate) fn close(&self) {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.closed = true;
+        for stream in sessions.streams.values() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        drop(sessions);
+
+        // Wakes the accept loop, which then sees the flag
+        let woken = self.local_addr().and_then(|addr| {
+            TcpStream::connect_timeout(&server::loopback(addr), Duration::from_secs(1)).map(drop)
+        });
+        if let Err(e) = woken {
+            warn!("Failed to wake admin listener: {}", e);
+        }
+    }
+
+    fn open_session(&self, stream: TcpStream) -> io::Result<(usize, TcpStream)> {
+        let handle = stream.try_clone()?;
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.closed {
+            // Accepted while `close` was ending the others
+            let _ = handle.shutdown(Shutdown::Both);
+        }
+        sessions.next_id += 1;
+        let session = sessions.next_id;
+        sessions.streams.insert(session, handle);
+        Ok((session, stream))
+    }
+}
+
+/// Answers requests on one admin connection until it closes
+fn serve(
+    session: usize,
+    mut stream: TcpStream,
+    connections: &ConnectionRegistry,
+    listener: &AdminListener,
+) {
+    let mut authentication = Authentication::new(listener.auth.as_ref());
+    loop {
+        let result = match codec::read_frame::<_, AdminRequest>(&mut stream, MAX_FRAME_LENGTH) {
+            Ok(request) => answer(session, connections, listener, &mut authentication, request),
+            Err(FrameError::Io(_)) => return,
+            Err(error) => Err(ProtocolError::from(error)),
+        };
+        let (response, keep_alive) = match result {
+            Ok(response) => (
+                AdminResponse {
+                    response: Some(response),
+                },
+                true,
+            ),
+            Err(error) => {
+                let keep_alive = !error.closes_connection();
+                (error_response(error), keep_alive)
+            }
+        };
+        if let Err(e) = codec::write_frame(&mut stream, &response) {
+            warn!("Failed to answer admin request: {}", e);
+            return;
+        }
+        if !keep_alive {
+            return;
+        }
+    }
+}
+
+/// Carries out `request` against the registry, once the connection has authenticated
+fn answer(
+    session: usize,
+    connections: &ConnectionRegistry,
+    listener: &AdminListener,
+    authentication: &mut Authentication,
+    request: AdminRequest,
+) -> Result<admin_response::Response, ProtocolError> {
+    match request.request {
+        Some(admin_request::Request::AuthRequest(request)) => {
+            let auth = listener.auth.as_ref().ok_or_else(|| {
+                ProtocolError::new(
+                    ErrorCode::InvalidArgument,
+                    "This admin listener doesn't authenticate connections",
+                )
+            })?;
+            if let (Some(auth_request::Method::BearerToken(_)), false) =
+                (&request.method, listener.bearer_tokens)
+            {
+                warn!(
+                    "Admin connection {} sent a bearer token off loopback",
+                    session
+                );
+                return Err(auth::unauthenticated(
+                    "Bearer tokens aren't accepted off loopback, authenticate with HMAC",
+                ));
+            }
+            let response = authentication
+                .authenticate(auth, &request)
+                .inspect_err(|error| {
+                    warn!(
+                        "Admin connection {} failed to authenticate: {}",
+                        session, error.message
+                    );
+                })?;
+            if let Some(principal) = authentication.principal() {
+                info!(
+                    "Admin connection {} authenticated as {}",
+                    session, principal
+                );
+            }
+            Ok(admin_response::Response::AuthResponse(response))
+        }
+        _ if !authentication.is_done() => Err(auth::unauthenticated(
+            "Authenticate before sending admin requests",
+        )),
+        Some(admin_request::Request::ListConnections(_)) => {
+            Ok(admin_response::Response::ConnectionList(ConnectionList {
+                connections: connections.list().iter().map(connection_info).collect(),
+            }))
+        }
+        Some(admin_request::Request::InspectConnection(inspect)) => client_id(inspect.client_id)
+            .and_then(|id| connections.inspect(id))
+            .map(|info| admin_response::Response::Connection(connection_info(&info)))
+            .ok_or_else(|| not_found(inspect.client_id)),
+        Some(admin_request::Request::DisconnectClient(disconnect)) => {
+            match client_id(disconnect.client_id) {
+                Some(id) if connections.disconnect(id) => {
+                    Ok(admin_response::Response::Disconnected(Disconnected {
+                        client_id: disconnect.client_id,
+                    }))
+                }
+                _ => Err(not_found(disconnect.client_id)),
+            }
+        }
+        None => Err(ProtocolError::new(
+            ErrorCode::UnsupportedMessage,
+            "Admin request with no request set",
+        )),
+    }
+}
+
+fn error_response(error: ProtocolError) -> AdminResponse {
+    AdminResponse {
+        response: Some(admin_response::Response::ErrorResponse(ErrorResponse {
+            code: error.code as i32,
+            message: error.message,
+            ..Default::default()
+        })),
+    }
+}
+
+fn client_id(id: u64) -> Option<usize> {
+    usize::try_from(id).ok()
+}
+
+fn not_found(id: u64) -> ProtocolError {
+    ProtocolError::new(
+        ErrorCode::NotFound,
+        format!("Client {} isn't connected", id),
+    )
+}
+
+fn connection_info(info: &ConnectionInfo) -> crate::message::ConnectionInfo {
+    crate::message::ConnectionInfo {
+        client_id: info.id as u64,
+        peer: info.peer(),
+        connected_at_ms: unix_millis(info.connected_at),
+        last_activity_ms: unix_millis(info.last_activity),
+        bytes_received: info.bytes_received,
+        bytes_sent: info.bytes_sent,
+        messages_received: info.messages_received,
+        messages_sent: info.messages_sent,
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
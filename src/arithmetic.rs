@@ -0,0 +1,197 @@
+use crate::{
+    error::ProtocolError,
+    message::{ArithmeticOperator, ErrorCode, OverflowMode},
+};
+
+/// Integer types the arithmetic requests operate on
+pub trait Integer: Copy + PartialEq + Default + std::fmt::Display {
+    fn checked_add(self, rhs: Self) -> Option<Self>;
+    fn wrapping_add(self, rhs: Self) -> Self;
+    fn saturating_add(self, rhs: Self) -> Self;
+    fn checked_sub(self, rhs: Self) -> Option<Self>;
+    fn wrapping_sub(self, rhs: Self) -> Self;
+    fn saturating_sub(self, rhs: Self) -> Self;
+    fn checked_mul(self, rhs: Self) -> Option<Self>;
+    fn wrapping_mul(self, rhs: Self) -> Self;
+    fn saturating_mul(self, rhs: Self) -> Self;
+    fn checked_div(self, rhs: Self) -> Option<Self>;
+    fn wrapping_div(self, rhs: Self) -> Self;
+    fn saturating_div(self, rhs: Self) -> Self;
+    fn wrapping_rem(self, rhs: Self) -> Self;
+    fn checked_pow(self, exp: u32) -> Option<Self>;
+    fn wrapping_pow(self, exp: u32) -> Self;
+    fn saturating_pow(self, exp: u32) -> Self;
+}
+
+macro_rules! impl_integer {
+    ($t:ty; $($method:ident($rhs:ty) -> $ret:ty),* $(,)?) => {
+        impl Integer for $t {
+            $(
+                fn $method(self, rhs: $rhs) -> $ret {
+                    <$t>::$method(self, rhs)
+                }
+            )*
+        }
+    };
+    ($($t:ty),*) => {$(
+        impl_integer!($t;
+            checked_add(Self) -> Option<Self>,
+            wrapping_add(Self) -> Self,
+            saturating_add(Self) -> Self,
+            checked_sub(Self) -> Option<Self>,
+            wrapping_sub(Self) -> Self,
+            saturating_sub(Self) -> Self,
+            checked_mul(Self) -> Option<Self>,
+            wrapping_mul(Self) -> Self,
+            saturating_mul(Self) -> Self,
+            checked_div(Self) -> Option<Self>,
+            wrapping_div(Self) -> Self,
+            saturating_div(Self) -> Self,
+            wrapping_rem(Self) -> Self,
+            checked_pow(u32) -> Option<Self>,
+            wrapping_pow(u32) -> Self,
+            saturating_pow(u32) -> Self,
+        );
+    )*};
+}
+
+impl_integer!(i32, i64);
+
+/// Picks the overflow mode for a request: its own if set, otherwise the server default
+pub fn resolve_overflow_mode(
+    requested: i32,
+    default: OverflowMode,
+) -> Result<OverflowMode, ProtocolError> {
+    match OverflowMode::try_from(requested) {
+        Ok(OverflowMode::Unspecified) => Ok(default),
+        Ok(mode) => Ok(mode),
+        Err(_) => Err(ProtocolError::new(
+            ErrorCode::InvalidArgument,
+            format!("Unknown overflow mode {}", requested),
+        )),
+    }
+}
+
+/// Applies `mode` to an operation given its checked, wrapping and saturating forms
+fn with_overflow_mode<T: Integer>(
+    mode: OverflowMode,
+    checked: impl FnOnce() -> Option<T>,
+    wrapping: impl FnOnce() -> T,
+    saturating: impl FnOnce() -> T,
+    expression: impl FnOnce() -> String,
+) -> Result<T, ProtocolError> {
+    match mode {
+        OverflowMode::Wrapping => Ok(wrapping()),
+        OverflowMode::Saturating => Ok(saturating()),
+        // Callers resolve the server default first, an unresolved mode is treated as checked
+        OverflowMode::Checked | OverflowMode::Unspecified => checked().ok_or_else(|| {
+            ProtocolError::new(
+                ErrorCode::ArithmeticOverflow,
+                format!("{} overflows", expression()),
+            )
+        }),
+    }
+}
+
+/// Refuses a zero `b`, naming the expression `a operator b` in the error
+fn check_divisor<T: Integer>(a: T, operator: &str, b: T) -> Result<(), ProtocolError> {
+    if b == T::default() {
+        return Err(ProtocolError::new(
+            ErrorCode::DivisionByZero,
+            format!("{} {} 0 is undefined", a, operator),
+        ));
+    }
+    Ok(())
+}
+
+/// Adds `a` and `b`, handling overflow the way `mode` says
+pub fn add<T: Integer>(a: T, b: T, mode: OverflowMode) -> Result<T, ProtocolError> {
+    with_overflow_mode(
+        mode,
+        || a.checked_add(b),
+        || a.wrapping_add(b),
+        || a.saturating_add(b),
+        || format!("{} + {}", a, b),
+    )
+}
+
+/// Subtracts `b` from `a`, handling overflow the way `mode` says
+pub fn subtract<T: Integer>(a: T, b: T, mode: OverflowMode) -> Result<T, ProtocolError> {
+    with_overflow_mode(
+        mode,
+        || a.checked_sub(b),
+        || a.wrapping_sub(b),
+        || a.saturating_sub(b),
+        || format!("{} - {}", a, b),
+    )
+}
+
+/// Multiplies `a` by `b`, handling overflow the way `mode` says
+pub fn multiply<T: Integer>(a: T, b: T, mode: OverflowMode) -> Result<T, ProtocolError> {
+    with_overflow_mode(
+        mode,
+        || a.checked_mul(b),
+        || a.wrapping_mul(b),
+        || a.saturating_mul(b),
+        || format!("{} * {}", a, b),
+    )
+}
+
+/// Divides `a` by `b`, truncating towards zero. Only `MIN / -1` can overflow.
+pub fn divide<T: Integer>(a: T, b: T, mode: OverflowMode) -> Result<T, ProtocolError> {
+    check_divisor(a, "/", b)?;
+    with_overflow_mode(
+        mode,
+        || a.checked_div(b),
+        || a.wrapping_div(b),
+        || a.saturating_div(b),
+        || format!("{} / {}", a, b),
+    )
+}
+
+/// Remainder of `a / b`, taking the sign of `a`. The result always fits, even for
+/// `MIN % -1` whose intermediate quotient doesn't, so `mode` doesn't matter.
+pub fn modulo<T: Integer>(a: T, b: T) -> Result<T, ProtocolError> {
+    check_divisor(a, "%", b)?;
+    Ok(a.wrapping_rem(b))
+}
+
+/// Raises `a` to the power of `exp`, handling overflow the way `mode` says
+pub fn power<T: Integer>(a: T, exp: u32, mode: OverflowMode) -> Result<T, ProtocolError> {
+    with_overflow_mode(
+        mode,
+        || a.checked_pow(exp),
+        || a.wrapping_pow(exp),
+        || a.saturating_pow(exp),
+        || format!("{} ^ {}", a, exp),
+    )
+}
+
+/// Computes `a <operator> b` for an `ArithmeticRequest`
+pub fn evaluate(
+    operator: ArithmeticOperator,
+    a: i64,
+    b: i64,
+    mode: OverflowMode,
+) -> Result<i64, ProtocolError> {
+    match operator {
+        ArithmeticOperator::Add => add(a, b, mode),
+        ArithmeticOperator::Subtract => subtract(a, b, mode),
+        ArithmeticOperator::Multiply => multiply(a, b, mode),
+        ArithmeticOperator::Divide => divide(a, b, mode),
+        ArithmeticOperator::Modulo => modulo(a, b),
+        ArithmeticOperator::Power => {
+            let exp = u32::try_from(b).map_err(|_| {
+                ProtocolError::new(
+                    ErrorCode::InvalidArgument,
+                    format!("Exponent {} is outside 0..={}", b, u32::MAX),
+                )
+            })?;
+            power(a, exp, mode)
+        }
+        ArithmeticOperator::Unspecified => Err(ProtocolError::new(
+            ErrorCode::InvalidArgument,
+            "Arithmetic request has no operator",
+        )),
+    }
+}
@@ -0,0 +1,335 @@
+use log::{info, warn};
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    task::{self, JoinSet},
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::{
+    async_server_handler::AsyncServerHandler,
+    codec,
+    config::{ConnectionCapPolicy, ServerConfig},
+    error::ProtocolError,
+    memory::MemoryBudget,
+    message::{ErrorCode, ServerMessage},
+    metrics::ServerMetrics,
+    rate_limit::RateLimiter,
+    router::Router,
+    server::{ShutdownReport, REJECT_LINGER},
+    tls::TlsConfig,
+};
+
+/// Tokio-based server speaking the same protocol as [`Server`](crate::server::Server).
+///
+/// Every client is served by a lightweight task instead of an OS thread, so idle
+/// connections cost next to nothing.
+pub struct AsyncServer {
+    listener: TcpListener,
+    is_running: AtomicBool,
+    stop_requested: watch::Sender<bool>,
+    config: Arc<ServerConfig>,
+    router: Arc<Router>,
+    memory: Arc<MemoryBudget>,
+    metrics: Arc<ServerMetrics>,
+    rate_limiter: Arc<RateLimiter>,
+    /// Set when connections are served over TLS
+    tls: Option<TlsAcceptor>,
+}
+
+impl AsyncServer {
+    /// Creates a new server instance. Must be called from within a tokio runtime.
+    pub async fn new(addr: &str, router: Router) -> io::Result<Self> {
+        Self::with_config(addr, ServerConfig::default(), router).await
+    }
+
+    /// Creates a new server instance with the given configuration. The worker pool
+    /// settings don't apply here, every client gets its own task. Fails if the TLS
+    /// certificate or key can't be loaded.
+    pub async fn with_config(addr: &str, config: ServerConfig, router: Router) -> io::Result<Self> {
+        let tls = config.tls.as_ref().map(TlsConfig::load).transpose()?;
+        let listener = TcpListener::bind(addr).await?;
+        let (stop_requested, _) = watch::channel(false);
+        Ok(AsyncServer {
+            listener,
+            is_running: AtomicBool::new(false),
+            stop_requested,
+            memory: Arc::new(MemoryBudget::new(config.memory_limit)),
+            rate_limiter: Arc::new(RateLimiter::new(config.rate_limits.clone())),
+            config: Arc::new(config),
+            router: Arc::new(router),
+            metrics: Arc::new(ServerMetrics::default()),
+            tls: tls.map(TlsAcceptor::from),
+        })
+    }
+
+    /// Returns the address the server is bound to, including the port picked by the OS
+    /// when binding to port 0
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Returns the live counters of this server. It has no worker pool, so the pool
+    /// counters stay at zero.
+    pub fn metrics(&self) -> Arc<ServerMetrics> {
+        self.metrics.clone()
+    }
+
+    /// Runs the server, accepting connections until [`stop`](Self::stop) is called,
+    /// then drains connected clients the same way [`Server::run`](crate::server::Server::run) does
+    pub async fn run(&self) -> io::Result<ShutdownReport> {
+        self.is_running.store(true, Ordering::SeqCst);
+        info!("Async server is running on {}", self.listener.local_addr()?);
+
+        let client_id = AtomicUsize::new(0);
+        let mut stop_requested = self.stop_requested.subscribe();
+        let mut clients = JoinSet::new();
+        let mut peers = Peers::default();
+
+        while !*stop_requested.borrow_and_update() {
+            // At the cap, reaping a finished client is what makes room again
+            let has_room = match (
+                self.config.max_connections,
+                self.config.connection_cap_policy,
+            ) {
+                (Some(limit), ConnectionCapPolicy::Backlog) => clients.len() < limit,
+                _ => true,
+            };
+            tokio::select! {
+                biased;
+                // Wakes the loop as soon as `stop` is called
+                _ = stop_requested.changed() => {}
+                // Reap finished clients so the set only holds live ones
+                Some(finished) = clients.join_next_with_id(), if !clients.is_empty() => {
+                    peers.remove(finished.map_or_else(|e| e.id(), |(task, _)| task));
+                }
+                accepted = self.listener.accept(), if has_room => match accepted {
+                    Ok((stream, peer)) if !self.config.access.permits(peer.ip()) => {
+                        self.deny(peer.ip(), stream)
+                    }
+                    Ok((stream, peer)) => {
+                        let id = client_id.fetch_add(1, Ordering::SeqCst) + 1;
+                        if let Some(reason) =
+                            self.over_connection_cap(clients.len(), peers.count(peer.ip()))
+                        {
+                            self.reject(id, stream, reason).await;
+                            continue;
+                        }
+                        let shutdown = self.stop_requested.subscribe();
+                        let shared = Shared {
+                            config: self.config.clone(),
+                            router: self.router.clone(),
+                            memory: self.memory.clone(),
+                            metrics: self.metrics.clone(),
+                            rate_limiter: self.rate_limiter.clone(),
+                            peer,
+                            shutdown: shutdown.clone(),
+                        };
+                        let client = match self.tls.clone() {
+                            // The handshake runs on the client's task, never in this loop
+                            Some(acceptor) => clients.spawn(async move {
+                                match acceptor.accept(stream).await {
+                                    Ok(stream) => {
+                                        serve_client(id, peer, shared.handler(stream), shutdown).await
+                                    }
+                                    Err(e) => {
+                                        warn!("TLS handshake with client {} failed: {}", id, e);
+                                        false
+                                    }
+                                }
+                            }),
+                            None => {
+                                clients.spawn(serve_client(id, peer, shared.handler(stream), shutdown))
+                            }
+                        };
+                        peers.insert(client.id(), peer.ip());
+                    }
+                    Err(e) => {
+                        warn!("Failed to accept connection: {}", e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                    }
+                },
+            }
+        }
+
+        let report = self.drain(clients).await;
+        self.is_running.store(false, Ordering::SeqCst);
+        info!(
+            "Async server stopped: {} client(s) drained, {} force-closed",
+            report.drained, report.force_closed
+        );
+        Ok(report)
+    }
+
+    /// Waits for clients to finish their in-flight request, aborting whoever is still
+    /// around at the drain deadline
+    async fn drain(&self, mut clients: JoinSet<bool>) -> ShutdownReport {
+        let mut drained = 0;
+        let all_closed = tokio::time::timeout(self.config.drain_timeout, async {
+            while let Some(closed_while_draining) = clients.join_next().await {
+                if let Ok(true) = closed_while_draining {
+                    drained += 1;
+                }
+            }
+        })
+        .await;
+
+        let force_closed = match all_closed {
+            Ok(()) => 0,
+            Err(_) => {
+                let remaining = clients.len();
+                clients.shutdown().await;
+                remaining
+            }
+        };
+        ShutdownReport {
+            drained,
+            force_closed,
+        }
+    }
+
+    /// Returns which connection cap a new connection is over, if any, given the `connected`
+    /// clients and the `from_peer` ones among them sharing its address
+    fn over_connection_cap(&self, connected: usize, from_peer: usize) -> Option<&'static str> {
+        if self
+            .config
+            .max_connections
+            .is_some_and(|limit| connected >= limit)
+        {
+            return Some("connection limit reached");
+        }
+        match self.config.max_connections_per_ip {
+            Some(limit) if from_peer >= limit => Some("connection limit of its address reached"),
+            _ => None,
+        }
+    }
+
+    /// Closes a connection from an address the access list doesn't permit, without a reply
+    fn deny(&self, ip: IpAddr, stream: TcpStream) {
+        self.metrics.connection_denied();
+        warn!("Refusing connection from {}: not in the access list", ip);
+        drop(stream);
+    }
+
+    /// Tells a client the server has no capacity left for it and closes the connection
+    async fn reject(&self, id: usize, mut stream: TcpStream, reason: &str) {
+        self.metrics.connection_rejected();
+        warn!("Rejecting client {}: {}", id, reason);
+
+        // A TLS client can't read a plaintext reply, and isn't worth a handshake
+        if self.tls.is_none() {
+            let response =
+                ProtocolError::new(ErrorCode::ServerBusy, "Server is busy, try again later");
+            let frame = codec::encode_frame(&ServerMessage::from(response));
+            if let Err(e) = stream.write_all(&frame).await {
+                warn!("Failed to notify rejected client {}: {}", id, e);
+            }
+        }
+        // Closing with the client's `Hello` unread would answer it with a reset, which may
+        // discard the reply before the client gets to read it
+        let _ = stream.shutdown().await;
+        tokio::spawn(async move {
+            let mut discarded = [0u8; 1024];
+            let _ = tokio::time::timeout(REJECT_LINGER, async {
+                while let Ok(1..) = stream.read(&mut discarded).await {}
+            })
+            .await;
+        });
+    }
+
+    /// Stops the server: wakes the accept loop and tells every client task to close once
+    /// its in-flight request is answered. A stop requested before `run` has started still
+    /// takes effect.
+    pub fn stop(&self) {
+        self.stop_requested.send_replace(true);
+        if self.is_running.load(Ordering::SeqCst) {
+            info!("Shutdown signal sent.");
+        } else {
+            warn!("Server was already stopped or not running.");
+        }
+    }
+}
+
+/// Addresses of the live clients, counted against `max_connections_per_ip`.
+#[derive(Default)]
+struct Peers {
+    tasks: HashMap<task::Id, IpAddr>,
+    connections: HashMap<IpAddr, usize>,
+}
+
+impl Peers {
+    fn insert(&mut self, task: task::Id, ip: IpAddr) {
+        self.tasks.insert(task, ip);
+        *self.connections.entry(ip).or_default() += 1;
+    }
+
+    fn remove(&mut self, task: task::Id) {
+        let Some(ip) = self.tasks.remove(&task) else {
+            return;
+        };
+        if let Some(count) = self.connections.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                self.connections.remove(&ip);
+            }
+        }
+    }
+
+    /// Live clients connected from `ip`
+    fn count(&self, ip: IpAddr) -> usize {
+        self.connections.get(&ip).copied().unwrap_or_default()
+    }
+}
+
+/// What the handler of every client shares with the server
+struct Shared {
+    config: Arc<ServerConfig>,
+    router: Arc<Router>,
+    memory: Arc<MemoryBudget>,
+    metrics: Arc<ServerMetrics>,
+    rate_limiter: Arc<RateLimiter>,
+    peer: SocketAddr,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl Shared {
+    fn handler<S: AsyncRead + AsyncWrite + Unpin>(self, stream: S) -> AsyncServerHandler<S> {
+        let mut handler = AsyncServerHandler::new(
+            stream,
+            self.config,
+            self.router,
+            self.memory,
+            self.metrics,
+            self.shutdown,
+        );
+        handler.set_rate_limiter(self.rate_limiter, self.peer.ip());
+        handler
+    }
+}
+
+async fn serve_client<S: AsyncRead + AsyncWrite + Unpin>(
+    id: usize,
+    peer: SocketAddr,
+    mut server_handler: AsyncServerHandler<S>,
+    shutdown: watch::Receiver<bool>,
+) -> bool {
+    info!("Accepted client {} from {}", id, peer);
+    if let Err(e) = server_handler.handle(id).await {
+        eprintln!("Error handling client {}: {}", id, e);
+    }
+
+    // Tells `drain` whether this client closed as part of a shutdown
+    let closed_while_draining = *shutdown.borrow();
+    closed_while_draining
+}
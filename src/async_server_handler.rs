@@ -0,0 +1,301 @@
+use crate::{
+    codec::{self, FrameFormat},
+    config::ServerConfig,
+    connection::{Action, Connection},
+    frame_decoder::FrameDecoder,
+    handshake::{self, Session},
+    heartbeat::{Heartbeat, Liveness},
+    memory::MemoryBudget,
+    message::ServerMessage,
+    metrics::ServerMetrics,
+    rate_limit::RateLimiter,
+    router::Router,
+    server_handler::{error_reply, ping, reap, Incoming},
+};
+use std::{
+    io::{self, ErrorKind},
+    net::IpAddr,
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{mpsc, watch, Semaphore},
+};
+
+/// Async counterpart of [`ServerHandler`](crate::server_handler::ServerHandler), serving one
+/// client over any tokio stream.
+pub struct AsyncServerHandler<S> {
+    stream: S,
+    config: Arc<ServerConfig>,
+    router: Arc<Router>,
+    memory: Arc<MemoryBudget>,
+    metrics: Arc<ServerMetrics>,
+    shutdown: watch::Receiver<bool>,
+    rate_limiter: Arc<RateLimiter>,
+    peer_ip: Option<IpAddr>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncServerHandler<S> {
+    /// Creates a handler that charges its request buffers to `memory`, counts its traffic in
+    /// `metrics` and closes the connection once `shutdown` turns `true`
+    pub fn new(
+        stream: S,
+        config: Arc<ServerConfig>,
+        router: Arc<Router>,
+        memory: Arc<MemoryBudget>,
+        metrics: Arc<ServerMetrics>,
+        shutdown: watch::Receiver<bool>,
+    ) -> Self {
+        AsyncServerHandler {
+            stream,
+            rate_limiter: Arc::new(RateLimiter::new(config.rate_limits.clone())),
+            peer_ip: None,
+            config,
+            router,
+            memory,
+            metrics,
+            shutdown,
+        }
+    }
+
+    /// Charges the connection's requests to the budgets of `rate_limiter`, including those
+    /// of `peer_ip`, instead of budgets of its own
+    pub(crate) fn set_rate_limiter(&mut self, rate_limiter: Arc<RateLimiter>, peer_ip: IpAddr) {
+        self.rate_limiter = rate_limiter;
+        self.peer_ip = Some(peer_ip);
+    }
+
+    pub async fn handle(&mut self, id: usize) -> io::Result<()> {
+        println!("Client {} connected", id);
+        let mut shutdown = self.shutdown.clone();
+        let mut decoder = FrameDecoder::with_limits(
+            self.config.max_frame_length,
+            self.config.connection_memory_limit,
+            self.memory.clone(),
+        );
+        let Some(session) = self.handshake(id, &mut decoder).await? else {
+            return Ok(());
+        };
+        decoder.set_format(session.format.clone());
+
+        let (config, router) = (self.config.clone(), self.router.clone());
+        let metrics = self.metrics.clone();
+        let in_flight = Arc::new(Semaphore::new(config.max_in_flight.max(1)));
+        let mut connection = Connection::new(
+            id,
+            config.clone(),
+            &router,
+            self.memory.clone(),
+            metrics.clone(),
+            &session,
+            self.rate_limiter.connection(self.peer_ip),
+        );
+        let (mut reader, mut writer) = tokio::io::split(&mut self.stream);
+
+        // Replies travel to the writer together with whether the connection may stay open
+        let (replies, mut outgoing) =
+            mpsc::channel::<(ServerMessage, bool)>(config.max_in_flight.max(1));
+
+        let read_requests = async move {
+            let mut heartbeat = Heartbeat::new(&config);
+            loop {
+                // Shutdown may abandon a request that is still arriving, but never one that
+                // has been read and is being processed
+                let message = tokio::select! {
+                    _ = shutdown.wait_for(|stop| *stop) => {
+                        println!("Client {} closed by server shutdown.", id);
+                        return Ok(());
+                    }
+                    message = read_message(&mut reader, &mut decoder, &mut heartbeat) => message,
+                };
+                let mut message = match message {
+                    Ok(Incoming::Message(message)) => message,
+                    Ok(Incoming::Ping(nonce)) => {
+                        let _ = replies.send((ping(nonce), true)).await;
+                        continue;
+                    }
+                    Ok(Incoming::Reap(reason)) => {
+                        reap(id, &metrics, reason);
+                        return Ok(());
+                    }
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                        println!("Client {} disconnected.", id);
+                        return Ok(());
+                    }
+                    Err(e) => return Err(e),
+                };
+
+                let action = loop {
+                    match connection.process(message) {
+                        Action::Delay(wait, delayed) => {
+                            tokio::time::sleep(wait).await;
+                            message = Ok(delayed);
+                        }
+                        action => break action,
+                    }
+                };
+                let dispatch = match action {
+                    Action::Reply(response) => {
+                        let _ = replies.send((response, true)).await;
+                        continue;
+                    }
+                    Action::ReplyAndClose(response) => {
+                        let _ = replies.send((response, false)).await;
+                        return Ok(());
+                    }
+                    Action::Dispatch(dispatch) => dispatch,
+                    Action::Delay(..) | Action::Skip => continue,
+                };
+
+                if !dispatch.pipelined {
+                    let reply = dispatch.respond(&config, &router, id);
+                    let keep_alive = reply.1;
+                    let _ = replies.send(reply).await;
+                    if !keep_alive {
+                        return Ok(());
+                    }
+                    continue;
+                }
+
+                let permit = in_flight
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("in-flight semaphore is never closed");
+                let (config, router, replies) = (config.clone(), router.clone(), replies.clone());
+                tokio::task::spawn_blocking(move || {
+                    let reply = dispatch.respond(&config, &router, id);
+                    let _ = replies.blocking_send(reply);
+                    drop(permit);
+                });
+            }
+        };
+
+        // Runs until every sender is gone, so in-flight requests are still answered after
+        // the reader stops, or until a reply closes the connection
+        let format = session.format.clone();
+        let write_replies = async move {
+            while let Some((response, keep_alive)) = outgoing.recv().await {
+                write_message(&mut writer, &response, &format).await?;
+                if !keep_alive {
+                    break;
+                }
+            }
+            Ok::<_, io::Error>(())
+        };
+
+        tokio::pin!(read_requests, write_replies);
+        tokio::select! {
+            result = &mut read_requests => {
+                result?;
+                write_replies.await
+            }
+            result = &mut write_replies => result,
+        }
+    }
+
+    /// Reads the connection's `Hello` through `decoder` and answers it. Returns what the
+    /// connection agreed on, or `None` once it has been refused or closed.
+    ///
+    /// A connection that fails its handshake is closed, whatever the error.
+    async fn handshake(
+        &mut self,
+        id: usize,
+        decoder: &mut FrameDecoder,
+    ) -> io::Result<Option<Session>> {
+        let mut shutdown = self.shutdown.clone();
+        let mut heartbeat = Heartbeat::for_handshake(&self.config);
+        let accepted = loop {
+            let message = tokio::select! {
+                _ = shutdown.wait_for(|stop| *stop) => {
+                    println!("Client {} closed by server shutdown.", id);
+                    return Ok(None);
+                }
+                message = read_message(&mut self.stream, decoder, &mut heartbeat) => message,
+            };
+            match message {
+                Ok(Incoming::Message(Ok(message))) => {
+                    break handshake::accept(&self.config, &self.metrics, &message)
+                        .map_err(|error| (message.request_id, error))
+                }
+                Ok(Incoming::Message(Err(error))) => break Err((0, error)),
+                Ok(Incoming::Ping(_)) => {}
+                Ok(Incoming::Reap(reason)) => {
+                    reap(id, &self.metrics, reason);
+                    return Ok(None);
+                }
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    println!("Client {} disconnected.", id);
+                    return Ok(None);
+                }
+                Err(e) => return Err(e),
+            }
+        };
+
+        let plain = FrameFormat::default();
+        match accepted {
+            Ok((ack, session)) => {
+                write_message(&mut self.stream, &ack, &plain).await?;
+                Ok(Some(session))
+            }
+            Err((request_id, error)) => {
+                let (response, _) = error_reply(id, request_id, error);
+                write_message(&mut self.stream, &response, &plain).await?;
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Returns the next frame, reading until one is complete or `heartbeat` calls for
+/// something else. The error is a transport failure.
+///
+/// Received bytes go straight into `decoder`, so dropping the future between reads loses
+/// nothing.
+async fn read_message<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    decoder: &mut FrameDecoder,
+    heartbeat: &mut Heartbeat,
+) -> io::Result<Incoming> {
+    let mut chunk = [0u8; 8 * 1024];
+    loop {
+        if let Some(message) = decoder.next_frame() {
+            heartbeat.frame_received();
+            return Ok(Incoming::Message(message));
+        }
+        match heartbeat.check(decoder.buffered() > 0) {
+            Liveness::Alive => {}
+            Liveness::Ping(nonce) => return Ok(Incoming::Ping(nonce)),
+            Liveness::Reap(reason) => return Ok(Incoming::Reap(reason)),
+        }
+        let read = match heartbeat.poll_interval() {
+            Some(interval) => match tokio::time::timeout(interval, reader.read(&mut chunk)).await {
+                Ok(read) => read?,
+                // Nothing arrived, time to check the deadlines again
+                Err(_) => continue,
+            },
+            None => reader.read(&mut chunk).await?,
+        };
+        if read == 0 {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                format!(
+                    "connection closed with {} bytes of an incomplete frame",
+                    decoder.buffered()
+                ),
+            ));
+        }
+        decoder.extend(&chunk[..read]);
+    }
+}
+
+async fn write_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    response: &ServerMessage,
+    format: &FrameFormat,
+) -> io::Result<()> {
+    writer
+        .write_all(&codec::encode_frame_with(response, format))
+        .await?;
+    writer.flush().await
+}
@@ -0,0 +1,319 @@
+use log::{info, warn};
+use std::{collections::HashMap, fmt, fs, io, path::Path, sync::Arc};
+
+use ring::{hmac, rand::SecureRandom};
+
+use crate::{
+    error::ProtocolError,
+    message::{
+        auth_request, client_message, server_message, AuthRequest, AuthResponse, ClientMessage,
+        ErrorCode, ServerMessage,
+    },
+};
+
+/// Length of the challenges sent for HMAC authentication
+pub const CHALLENGE_LENGTH: usize = 32;
+
+/// Credentials clients may authenticate with. Configuring any makes authentication
+/// mandatory.
+///
+/// Bearer tokens are sent as they are, so only offer them over TLS. HMAC authentication
+/// proves knowledge of a secret without sending it.
+#[derive(Clone)]
+pub struct AuthConfig {
+    /// Key the tokens are stored under, so they can be compared in constant time
+    token_key: hmac::Key,
+    tokens: Vec<BearerToken>,
+    hmac_keys: HashMap<String, hmac::Key>,
+    /// Key proofs for unknown principals are checked against, so they take as long to
+    /// reject as wrong proofs for known ones
+    unknown_principal_key: hmac::Key,
+}
+
+#[derive(Clone)]
+struct BearerToken {
+    principal: String,
+    tag: Vec<u8>,
+}
+
+impl AuthConfig {
+    /// Accepts no credentials yet
+    pub fn new() -> Self {
+        AuthConfig {
+            token_key: random_key(),
+            tokens: Vec::new(),
+            hmac_keys: HashMap::new(),
+            unknown_principal_key: random_key(),
+        }
+    }
+
+    /// Authenticates clients presenting `token` as `principal`
+    pub fn with_token(mut self, principal: impl Into<String>, token: &str) -> Self {
+        let tag = hmac::sign(&self.token_key, token.as_bytes());
+        self.tokens.push(BearerToken {
+            principal: principal.into(),
+            tag: tag.as_ref().to_vec(),
+        });
+        self
+    }
+
+    /// Authenticates clients proving they know `secret` as `principal`
+    pub fn with_hmac_key(mut self, principal: impl Into<String>, secret: &[u8]) -> Self {
+        self.hmac_keys
+            .insert(principal.into(), hmac::Key::new(hmac::HMAC_SHA256, secret));
+        self
+    }
+
+    /// Adds the bearer tokens in the file at `path`, one `principal token` pair a line
+    pub fn load_tokens(self, path: impl AsRef<Path>) -> io::Result<Self> {
+        read_entries(path.as_ref())?
+            .into_iter()
+            .try_fold(self, |config, (principal, token)| {
+                Ok(config.with_token(principal, &token))
+            })
+    }
+
+    /// Adds the HMAC secrets in the file at `path`, one `principal hex-secret` pair a line
+    pub fn load_hmac_keys(self, path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        read_entries(path)?
+            .into_iter()
+            .try_fold(self, |config, (principal, secret)| {
+                let secret = decode_hex(&secret).ok_or_else(|| {
+                    invalid_data(path, format!("secret of {} isn't hex", principal))
+                })?;
+                Ok(config.with_hmac_key(principal, &secret))
+            })
+    }
+
+    /// Whether any principal may authenticate with HMAC, which keeps its secret off the wire
+    pub(crate) fn has_hmac_keys(&self) -> bool {
+        !self.hmac_keys.is_empty()
+    }
+
+    /// The principal `token` belongs to. Every token is checked, in constant time, so the
+    /// reply time doesn't tell how close a guess was.
+    fn principal_of(&self, token: &str) -> Option<&str> {
+        self.tokens.iter().fold(None, |found, entry| {
+            let matches = hmac::verify(&self.token_key, token.as_bytes(), &entry.tag).is_ok();
+            found.or(matches.then_some(entry.principal.as_str()))
+        })
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for AuthConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthConfig")
+            .field(
+                "tokens",
+                &self.tokens.iter().map(|t| &t.principal).collect::<Vec<_>>(),
+            )
+            .field("hmac_keys", &self.hmac_keys.keys().collect::<Vec<_>>())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Where one connection stands with authentication.
+#[derive(Debug)]
+pub(crate) enum Authentication {
+    /// Nothing but the handshake is allowed yet
+    Pending,
+    /// An HMAC challenge was sent for `principal`
+    Challenged {
+        principal: String,
+        challenge: [u8; CHALLENGE_LENGTH],
+    },
+    /// Requests are allowed, on behalf of the principal if there is one
+    Done(Option<Arc<str>>),
+}
+
+impl Authentication {
+    /// The state of a new connection to a server configured with `auth`
+    pub(crate) fn new(auth: Option<&AuthConfig>) -> Self {
+        match auth {
+            Some(_) => Authentication::Pending,
+            None => Authentication::Done(None),
+        }
+    }
+
+    /// Whether requests are allowed yet
+    pub(crate) fn is_done(&self) -> bool {
+        matches!(self, Authentication::Done(_))
+    }
+
+    /// Who the connection authenticated as
+    pub(crate) fn principal(&self) -> Option<Arc<str>> {
+        match self {
+            Authentication::Done(principal) => principal.clone(),
+            _ => None,
+        }
+    }
+
+    /// Answers an `AuthRequest`, or refuses any other request until the connection has
+    /// authenticated. Returns `None` for requests to pass on to the router.
+    pub(crate) fn screen(
+        &mut self,
+        id: usize,
+        auth: Option<&AuthConfig>,
+        message: &ClientMessage,
+    ) -> Result<Option<ServerMessage>, ProtocolError> {
+        let request = match &message.message {
+            Some(client_message::Message::AuthRequest(request)) => request,
+            _ => match self {
+                Authentication::Done(_) => return Ok(None),
+                _ => return Err(unauthenticated("Authenticate before sending requests")),
+            },
+        };
+        let Some(auth) = auth else {
+            return Err(ProtocolError::new(
+                ErrorCode::InvalidArgument,
+                "This server doesn't authenticate clients",
+            ));
+        };
+
+        let response = self.authenticate(auth, request).inspect_err(|error| {
+            warn!("Client {} failed to authenticate: {}", id, error.message);
+        })?;
+        if let Authentication::Done(Some(principal)) = self {
+            info!("Client {} authenticated as {}", id, principal);
+        }
+        Ok(Some(ServerMessage {
+            request_id: message.request_id,
+            message: Some(server_message::Message::AuthResponse(response)),
+        }))
+    }
+
+    /// Answers `request`, moving the connection on to its next state
+    pub(crate) fn authenticate(
+        &mut self,
+        auth: &AuthConfig,
+        request: &AuthRequest,
+    ) -> Result<AuthResponse, ProtocolError> {
+        if let Authentication::Done(_) = self {
+            return Err(ProtocolError::new(
+                ErrorCode::InvalidArgument,
+                "The connection is already authenticated",
+            ));
+        }
+        match &request.method {
+            Some(auth_request::Method::BearerToken(token)) => {
+                let principal = auth
+                    .principal_of(token)
+                    .ok_or_else(|| unauthenticated("Unknown bearer token"))?;
+                Ok(self.done(principal))
+            }
+            // Unknown principals get a challenge too, so they can't be told apart from
+            // known ones
+            Some(auth_request::Method::HmacPrincipal(principal)) => {
+                let mut challenge = [0u8; CHALLENGE_LENGTH];
+                ring::rand::SystemRandom::new()
+                    .fill(&mut challenge)
+                    .map_err(|_| {
+                        ProtocolError::new(
+                            ErrorCode::InternalError,
+                            "No randomness for a challenge",
+                        )
+                    })?;
+                *self = Authentication::Challenged {
+                    principal: principal.clone(),
+                    challenge,
+                };
+                Ok(AuthResponse {
+                    principal: String::new(),
+                    challenge: challenge.to_vec(),
+                })
+            }
+            Some(auth_request::Method::HmacProof(proof)) => {
+                let Authentication::Challenged {
+                    principal,
+                    challenge,
+                } = &*self
+                else {
+                    return Err(unauthenticated("No challenge to answer"));
+                };
+                let (key, known) = match auth.hmac_keys.get(principal) {
+                    Some(key) => (key, true),
+                    None => (&auth.unknown_principal_key, false),
+                };
+                let verified = hmac::verify(key, challenge, proof).is_ok() && known;
+                if !verified {
+                    return Err(unauthenticated("Wrong answer to the challenge"));
+                }
+                let principal = principal.clone();
+                Ok(self.done(&principal))
+            }
+            None => Err(unauthenticated("Auth request with no method set")),
+        }
+    }
+
+    fn done(&mut self, principal: &str) -> AuthResponse {
+        *self = Authentication::Done(Some(principal.into()));
+        AuthResponse {
+            principal: principal.to_string(),
+            challenge: Vec::new(),
+        }
+    }
+}
+
+/// Computes the answer to an HMAC challenge, as a client does
+pub fn sign_challenge(secret: &[u8], challenge: &[u8]) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+    hmac::sign(&key, challenge).as_ref().to_vec()
+}
+
+fn random_key() -> hmac::Key {
+    let mut key = [0u8; 32];
+    ring::rand::SystemRandom::new()
+        .fill(&mut key)
+        .expect("the system's random number generator failed");
+    hmac::Key::new(hmac::HMAC_SHA256, &key)
+}
+
+pub(crate) fn unauthenticated(message: &str) -> ProtocolError {
+    ProtocolError::new(ErrorCode::Unauthenticated, message)
+}
+
+/// Reads the `principal value` pairs of a credentials file, skipping blank lines and
+/// `#` comments
+fn read_entries(path: &Path) -> io::Result<Vec<(String, String)>> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    contents
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(
+            |(number, line)| match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [principal, value] => Ok((principal.to_string(), value.to_string())),
+                _ => Err(invalid_data(
+                    path,
+                    format!("line {}: expected a principal and a value", number),
+                )),
+            },
+        )
+        .collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn invalid_data(path: &Path, message: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), message),
+    )
+}
@@ -0,0 +1,385 @@
+use std::{
+    borrow::Cow,
+    fmt,
+    io::{self, Read, Write},
+    marker::PhantomData,
+};
+
+use bytes::{Buf, BytesMut};
+use prost::Message;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::compression::FrameCompression;
+
+/// Length of the big-endian length prefix in front of every frame
+pub const LENGTH_PREFIX: usize = 4;
+
+/// Largest frame body accepted by default
+pub const MAX_FRAME_LENGTH: usize = 1024 * 1024;
+
+/// Length of the byte naming the compression algorithm, between the length prefix and the
+/// body of frames on a compressed connection
+pub const COMPRESSION_FLAG: usize = 1;
+
+/// Length of the CRC32C trailer of frames on a connection that agreed on checksums
+pub const CHECKSUM_LENGTH: usize = 4;
+
+/// Why a frame couldn't be read or written.
+#[derive(Debug)]
+pub enum FrameError {
+    /// The underlying stream failed
+    Io(io::Error),
+    /// The length prefix announced an empty body
+    Empty,
+    /// The length prefix announced a body larger than allowed. The body is left unread, so
+    /// the stream can't be resynchronised.
+    TooLarge { length: usize, max_length: usize },
+    /// The body isn't a valid message
+    Decode(prost::DecodeError),
+    /// The body couldn't be decompressed
+    Compression(String),
+    /// The frame doesn't match its CRC32C trailer
+    Checksum { expected: u32, actual: u32 },
+}
+
+impl FrameError {
+    /// Returns whether the stream is still positioned at the start of the next frame
+    pub fn is_recoverable(&self) -> bool {
+        matches!(
+            self,
+            FrameError::Empty
+                | FrameError::Decode(_)
+                | FrameError::Compression(_)
+                | FrameError::Checksum { .. }
+        )
+    }
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Io(e) => write!(f, "I/O error: {}", e),
+            FrameError::Empty => write!(f, "Invalid message length: empty frame"),
+            FrameError::TooLarge { length, max_length } => write!(
+                f,
+                "Invalid message length: {} bytes exceeds the {} byte limit",
+                length, max_length
+            ),
+            FrameError::Decode(e) => write!(f, "Failed to decode message: {}", e),
+            FrameError::Compression(e) => write!(f, "Failed to decompress message: {}", e),
+            FrameError::Checksum { expected, actual } => write!(
+                f,
+                "Frame checksum mismatch: trailer says {:08x}, frame hashes to {:08x}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FrameError::Io(e) => Some(e),
+            FrameError::Decode(e) => Some(e),
+            FrameError::Empty
+            | FrameError::TooLarge { .. }
+            | FrameError::Compression(_)
+            | FrameError::Checksum { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for FrameError {
+    fn from(error: io::Error) -> Self {
+        FrameError::Io(error)
+    }
+}
+
+impl From<prost::DecodeError> for FrameError {
+    fn from(error: prost::DecodeError) -> Self {
+        FrameError::Decode(error)
+    }
+}
+
+impl From<FrameError> for io::Error {
+    fn from(error: FrameError) -> Self {
+        match error {
+            FrameError::Io(e) => e,
+            other => io::Error::new(io::ErrorKind::InvalidData, other),
+        }
+    }
+}
+
+/// Checks a frame's length prefix before its body is read
+pub fn check_frame_length(length: usize, max_length: usize) -> Result<(), FrameError> {
+    if length == 0 {
+        return Err(FrameError::Empty);
+    }
+    if length > max_length {
+        return Err(FrameError::TooLarge { length, max_length });
+    }
+    Ok(())
+}
+
+/// How frames are laid out on one connection, as agreed in its `Hello`. The default is the
+/// plain format: the length prefix, then the body.
+#[derive(Debug, Clone, Default)]
+pub struct FrameFormat {
+    /// Compression of the bodies, each preceded by a byte naming its algorithm
+    pub compression: Option<FrameCompression>,
+    /// Whether every frame ends with a big-endian CRC32C of the frame up to that point,
+    /// length prefix included
+    pub checksum: bool,
+}
+
+impl FrameFormat {
+    /// Bytes a frame carries besides its body and length prefix
+    pub fn overhead(&self) -> usize {
+        let flag = if self.compression.is_some() {
+            COMPRESSION_FLAG
+        } else {
+            0
+        };
+        flag + self.checksum_length()
+    }
+
+    fn checksum_length(&self) -> usize {
+        if self.checksum {
+            CHECKSUM_LENGTH
+        } else {
+            0
+        }
+    }
+
+    /// Checks and strips the checksum and compression of `frame`, the bytes behind its
+    /// length prefix
+    fn unwrap<'a>(&self, frame: &'a [u8], max_length: usize) -> Result<Cow<'a, [u8]>, FrameError> {
+        let mut content = frame;
+        if self.checksum {
+            let split = frame
+                .len()
+                .checked_sub(CHECKSUM_LENGTH)
+                .ok_or(FrameError::Empty)?;
+            let (rest, trailer) = frame.split_at(split);
+            let expected = u32::from_be_bytes(trailer.try_into().expect("trailer is 4 bytes"));
+            let prefix = (frame.len() as u32).to_be_bytes();
+            let actual = crc32c::crc32c_append(crc32c::crc32c(&prefix), rest);
+            if actual != expected {
+                return Err(FrameError::Checksum { expected, actual });
+            }
+            content = rest;
+        }
+        match &self.compression {
+            Some(compression) => {
+                let (flag, wire) = content.split_first().ok_or(FrameError::Empty)?;
+                Ok(Cow::Owned(compression.decompress(*flag, wire, max_length)?))
+            }
+            None if content.is_empty() => Err(FrameError::Empty),
+            None => Ok(Cow::Borrowed(content)),
+        }
+    }
+}
+
+/// Encodes `message` as a complete frame, length prefix included
+pub fn encode_frame<M: Message>(message: &M) -> Vec<u8> {
+    let length = message.encoded_len();
+    let mut frame = Vec::with_capacity(LENGTH_PREFIX + length);
+    frame.extend_from_slice(&(length as u32).to_be_bytes());
+    message
+        .encode(&mut frame)
+        .expect("a Vec grows to fit any message");
+    frame
+}
+
+/// Encodes `message` as a complete frame in `format`
+pub fn encode_frame_with<M: Message>(message: &M, format: &FrameFormat) -> Vec<u8> {
+    let mut content = Vec::new();
+    match &format.compression {
+        Some(compression) => {
+            let (algorithm, body) = compression.compress(message.encode_to_vec());
+            content.push(algorithm as u8);
+            content.extend_from_slice(&body);
+        }
+        None => message
+            .encode(&mut content)
+            .expect("a Vec grows to fit any message"),
+    }
+
+    let length = content.len() + format.checksum_length();
+    let mut frame = Vec::with_capacity(LENGTH_PREFIX + length);
+    frame.extend_from_slice(&(length as u32).to_be_bytes());
+    frame.extend_from_slice(&content);
+    if format.checksum {
+        let checksum = crc32c::crc32c(&frame);
+        frame.extend_from_slice(&checksum.to_be_bytes());
+    }
+    frame
+}
+
+/// Takes the next frame off the front of `src`, or returns `Ok(None)` until it has
+/// arrived in full.
+///
+/// Bad frames are consumed so decoding can carry on with the next one: an empty frame
+/// and its prefix, an undecodable one with its body. An oversized frame only loses its
+/// prefix, see [`FrameError::TooLarge`].
+pub fn decode_frame<M: Message + Default>(
+    src: &mut BytesMut,
+    max_length: usize,
+) -> Result<Option<M>, FrameError> {
+    match decode_frame_bytes(src, max_length)? {
+        Some(body) => Ok(Some(M::decode(body)?)),
+        None => Ok(None),
+    }
+}
+
+/// Takes the body of the next frame off the front of `src`, consuming bad frames the way
+/// [`decode_frame`] does
+fn decode_frame_bytes(
+    src: &mut BytesMut,
+    max_length: usize,
+) -> Result<Option<BytesMut>, FrameError> {
+    let Some(prefix) = src.get(..LENGTH_PREFIX) else {
+        return Ok(None);
+    };
+    let length = u32::from_be_bytes(prefix.try_into().expect("prefix is 4 bytes")) as usize;
+
+    if let Err(error) = check_frame_length(length, max_length) {
+        src.advance(LENGTH_PREFIX);
+        return Err(error);
+    }
+    if src.len() < LENGTH_PREFIX + length {
+        return Ok(None);
+    }
+
+    src.advance(LENGTH_PREFIX);
+    Ok(Some(src.split_to(length)))
+}
+
+/// Counterpart of [`decode_frame`] for frames in `format`. `max_length` bounds the body
+/// both as sent and once decompressed.
+pub fn decode_frame_with<M: Message + Default>(
+    src: &mut BytesMut,
+    max_length: usize,
+    format: &FrameFormat,
+) -> Result<Option<M>, FrameError> {
+    let Some(frame) = decode_frame_bytes(src, format.overhead() + max_length)? else {
+        return Ok(None);
+    };
+    let body = format.unwrap(&frame, max_length)?;
+    Ok(Some(M::decode(&*body)?))
+}
+
+/// Writes `message` to `writer` as one frame
+pub fn write_frame<W: Write, M: Message>(writer: &mut W, message: &M) -> io::Result<()> {
+    writer.write_all(&encode_frame(message))?;
+    writer.flush()
+}
+
+/// Writes `message` to `writer` as one frame in `format`
+pub fn write_frame_with<W: Write, M: Message>(
+    writer: &mut W,
+    message: &M,
+    format: &FrameFormat,
+) -> io::Result<()> {
+    writer.write_all(&encode_frame_with(message, format))?;
+    writer.flush()
+}
+
+/// Reads one frame from `reader`, blocking until it has arrived in full
+pub fn read_frame<R: Read, M: Message + Default>(
+    reader: &mut R,
+    max_length: usize,
+) -> Result<M, FrameError> {
+    let body = read_frame_bytes(reader, max_length)?;
+    Ok(M::decode(body.as_slice())?)
+}
+
+/// Counterpart of [`read_frame`] for frames in `format`
+pub fn read_frame_with<R: Read, M: Message + Default>(
+    reader: &mut R,
+    max_length: usize,
+    format: &FrameFormat,
+) -> Result<M, FrameError> {
+    let frame = read_frame_bytes(reader, format.overhead() + max_length)?;
+    let body = format.unwrap(&frame, max_length)?;
+    Ok(M::decode(&*body)?)
+}
+
+fn read_frame_bytes<R: Read>(reader: &mut R, max_length: usize) -> Result<Vec<u8>, FrameError> {
+    let mut prefix = [0u8; LENGTH_PREFIX];
+    reader.read_exact(&mut prefix)?;
+    let length = u32::from_be_bytes(prefix) as usize;
+    check_frame_length(length, max_length)?;
+
+    // Grows with the bytes that actually arrive rather than trusting the prefix up front
+    let mut body = Vec::new();
+    reader.take(length as u64).read_to_end(&mut body)?;
+    if body.len() < length {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "frame body cut short").into());
+    }
+    Ok(body)
+}
+
+/// Tokio codec for the wire format: a protobuf body behind a 4-byte big-endian length
+/// prefix. Reads frames of `D` and writes frames of any message type.
+#[derive(Debug)]
+pub struct FrameCodec<D> {
+    max_length: usize,
+    format: FrameFormat,
+    _decodes: PhantomData<fn() -> D>,
+}
+
+impl<D> FrameCodec<D> {
+    /// Creates a codec rejecting bodies over [`MAX_FRAME_LENGTH`]
+    pub fn new() -> Self {
+        Self::with_max_length(MAX_FRAME_LENGTH)
+    }
+
+    /// Creates a codec rejecting bodies over `max_length` bytes
+    pub fn with_max_length(max_length: usize) -> Self {
+        FrameCodec {
+            max_length,
+            format: FrameFormat::default(),
+            _decodes: PhantomData,
+        }
+    }
+
+    /// Reads and writes frames in `format` from now on, once a `Hello` has agreed on it
+    pub fn set_format(&mut self, format: FrameFormat) {
+        self.format = format;
+    }
+}
+
+impl<D> Default for FrameCodec<D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<D> Clone for FrameCodec<D> {
+    fn clone(&self) -> Self {
+        FrameCodec {
+            max_length: self.max_length,
+            format: self.format.clone(),
+            _decodes: PhantomData,
+        }
+    }
+}
+
+impl<D: Message + Default> Decoder for FrameCodec<D> {
+    type Item = D;
+    type Error = FrameError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<D>, FrameError> {
+        decode_frame_with(src, self.max_length, &self.format)
+    }
+}
+
+impl<D, M: Message> Encoder<M> for FrameCodec<D> {
+    type Error = FrameError;
+
+    fn encode(&mut self, message: M, dst: &mut BytesMut) -> Result<(), FrameError> {
+        dst.extend_from_slice(&encode_frame_with(&message, &self.format));
+        Ok(())
+    }
+}
@@ -0,0 +1,267 @@
+use std::{
+    io::{self, Read},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use crate::{codec::FrameError, message::Compression};
+
+/// Algorithms frames can be compressed with, in the order the server prefers them
+pub const SUPPORTED: [Compression; 2] = [Compression::Zstd, Compression::Lz4];
+
+/// Frame bodies shorter than this are sent uncompressed by default
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+/// zstd level used for every frame, the library's default
+const ZSTD_LEVEL: i32 = 0;
+
+/// Bounds of the window sizes zstd decompression accepts, as powers of two
+const ZSTD_MIN_WINDOW_LOG: u32 = 10;
+const ZSTD_MAX_WINDOW_LOG: u32 = 31;
+
+/// Sizes of the frame bodies sent and received on compressed connections, before and after
+/// compression.
+#[derive(Debug, Default)]
+pub struct CompressionStats {
+    frames: AtomicU64,
+    compressed_frames: AtomicU64,
+    plain_bytes: AtomicU64,
+    wire_bytes: AtomicU64,
+}
+
+impl CompressionStats {
+    /// Number of frames sent or received on compressed connections
+    pub fn frames(&self) -> u64 {
+        self.frames.load(Ordering::Relaxed)
+    }
+
+    /// Number of those frames whose body was actually compressed
+    pub fn compressed_frames(&self) -> u64 {
+        self.compressed_frames.load(Ordering::Relaxed)
+    }
+
+    /// Total size of the frame bodies before compression
+    pub fn plain_bytes(&self) -> u64 {
+        self.plain_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Total size of the frame bodies as they went over the wire
+    pub fn wire_bytes(&self) -> u64 {
+        self.wire_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Wire bytes per plain byte, 1.0 before any frame was counted
+    pub fn ratio(&self) -> f64 {
+        match self.plain_bytes() {
+            0 => 1.0,
+            plain => self.wire_bytes() as f64 / plain as f64,
+        }
+    }
+
+    fn record(&self, plain: usize, wire: usize, compressed: bool) {
+        self.frames.fetch_add(1, Ordering::Relaxed);
+        if compressed {
+            self.compressed_frames.fetch_add(1, Ordering::Relaxed);
+        }
+        self.plain_bytes.fetch_add(plain as u64, Ordering::Relaxed);
+        self.wire_bytes.fetch_add(wire as u64, Ordering::Relaxed);
+    }
+}
+
+/// The compression a connection agreed on in its `Hello`, applied to each frame body.
+///
+/// Bodies shorter than the threshold, or that compression wouldn't shrink, are sent as
+/// they are. Either way, every frame is counted in the connection's [`CompressionStats`].
+#[derive(Debug, Clone)]
+pub struct FrameCompression {
+    algorithm: Compression,
+    threshold: usize,
+    stats: Arc<CompressionStats>,
+}
+
+impl FrameCompression {
+    /// Compresses bodies of at least `threshold` bytes with `algorithm`, counting frames in
+    /// `stats`
+    pub fn new(algorithm: Compression, threshold: usize, stats: Arc<CompressionStats>) -> Self {
+        FrameCompression {
+            algorithm,
+            threshold,
+            stats,
+        }
+    }
+
+    /// The algorithm agreed on
+    pub fn algorithm(&self) -> Compression {
+        self.algorithm
+    }
+
+    /// Sizes of the frames this connection sent and received so far
+    pub fn stats(&self) -> &CompressionStats {
+        &self.stats
+    }
+
+    /// Returns the algorithm `body` ended up compressed with, and the bytes to send
+    pub(crate) fn compress(&self, body: Vec<u8>) -> (Compression, Vec<u8>) {
+        let plain = body.len();
+        let (algorithm, wire) = match self.try_compress(&body) {
+            Some(compressed) if compressed.len() < plain => (self.algorithm, compressed),
+            _ => (Compression::None, body),
+        };
+        self.stats
+            .record(plain, wire.len(), algorithm != Compression::None);
+        (algorithm, wire)
+    }
+
+    fn try_compress(&self, body: &[u8]) -> Option<Vec<u8>> {
+        if body.len() < self.threshold {
+            return None;
+        }
+        match self.algorithm {
+            Compression::None => None,
+            Compression::Zstd => zstd::bulk::compress(body, ZSTD_LEVEL).ok(),
+            Compression::Lz4 => Some(lz4_flex::compress_prepend_size(body)),
+        }
+    }
+
+    /// Restores a body sent with the algorithm named by `flag`, refusing to inflate it past
+    /// `max_length` bytes. Only the agreed algorithm, or none, is accepted.
+    ///
+    /// The body grows as it is decompressed, never by the size its header claims, so a few
+    /// hostile bytes can't make the server reserve `max_length` up front.
+    pub(crate) fn decompress(
+        &self,
+        flag: u8,
+        wire: &[u8],
+        max_length: usize,
+    ) -> Result<Vec<u8>, FrameError> {
+        let algorithm = Compression::try_from(i32::from(flag))
+            .ok()
+            .filter(|algorithm| [Compression::None, self.algorithm].contains(algorithm))
+            .ok_or_else(|| {
+                FrameError::Compression(format!("frame compressed with unknown algorithm {}", flag))
+            })?;
+        let body = match algorithm {
+            Compression::None => wire.to_vec(),
+            Compression::Zstd => zstd_decompress(wire, max_length)?,
+            Compression::Lz4 => lz4_decompress(wire, max_length)?,
+        };
+        self.stats
+            .record(body.len(), wire.len(), algorithm != Compression::None);
+        Ok(body)
+    }
+}
+
+/// Inflates a zstd frame body of at most `max_length` bytes
+fn zstd_decompress(wire: &[u8], max_length: usize) -> Result<Vec<u8>, FrameError> {
+    let invalid = |e: io::Error| FrameError::Compression(format!("invalid zstd frame body: {}", e));
+    let mut decoder = zstd::stream::read::Decoder::with_buffer(wire).map_err(invalid)?;
+    // No body needs a window longer than itself, so a header can't ask for more
+    let window_log = usize::BITS - max_length.saturating_sub(1).leading_zeros();
+    decoder
+        .window_log_max(window_log.clamp(ZSTD_MIN_WINDOW_LOG, ZSTD_MAX_WINDOW_LOG))
+        .map_err(invalid)?;
+    let mut body = Vec::new();
+    decoder
+        .take(max_length as u64 + 1)
+        .read_to_end(&mut body)
+        .map_err(invalid)?;
+    if body.len() > max_length {
+        return Err(FrameError::TooLarge {
+            length: body.len(),
+            max_length,
+        });
+    }
+    Ok(body)
+}
+
+/// Inflates an lz4 block behind the little-endian size `lz4_flex::compress_prepend_size`
+/// puts in front of it, checking every sequence against that size before copying it
+fn lz4_decompress(wire: &[u8], max_length: usize) -> Result<Vec<u8>, FrameError> {
+    let mut block = Lz4Block(wire);
+    let size = block.bytes(4)?;
+    let length = u32::from_le_bytes(size.try_into().expect("took 4 bytes")) as usize;
+    if length > max_length {
+        return Err(FrameError::TooLarge { length, max_length });
+    }
+
+    let mut body = Vec::new();
+    loop {
+        let token = block.byte()?;
+        let literals = block.length(token >> 4)?;
+        if body.len() + literals > length {
+            return Err(invalid_lz4("block inflates past its size"));
+        }
+        body.extend_from_slice(block.bytes(literals)?);
+        // The last sequence is literals only
+        if block.0.is_empty() {
+            break;
+        }
+        let offset = block.bytes(2)?;
+        let offset = usize::from(u16::from_le_bytes([offset[0], offset[1]]));
+        if offset == 0 || offset > body.len() {
+            return Err(invalid_lz4("match before the start of the block"));
+        }
+        let matched = block.length(token & 15)? + 4;
+        if body.len() + matched > length {
+            return Err(invalid_lz4("block inflates past its size"));
+        }
+        // A match may overlap the bytes it produces, repeating them
+        let start = body.len() - offset;
+        for index in start..start + matched {
+            body.push(body[index]);
+        }
+    }
+    if body.len() != length {
+        return Err(invalid_lz4("block is shorter than its size"));
+    }
+    Ok(body)
+}
+
+/// The part of an lz4 block not decoded yet
+struct Lz4Block<'a>(&'a [u8]);
+
+impl<'a> Lz4Block<'a> {
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], FrameError> {
+        let (bytes, rest) = self
+            .0
+            .split_at_checked(count)
+            .ok_or_else(|| invalid_lz4("block ends early"))?;
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, FrameError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    /// A length starting with `nibble`. At 15 it goes on in bytes, each 255 calling for
+    /// one more.
+    fn length(&mut self, nibble: u8) -> Result<usize, FrameError> {
+        let mut length = usize::from(nibble);
+        if nibble == 15 {
+            loop {
+                let byte = self.byte()?;
+                length += usize::from(byte);
+                if byte != 255 {
+                    break;
+                }
+            }
+        }
+        Ok(length)
+    }
+}
+
+fn invalid_lz4(reason: &str) -> FrameError {
+    FrameError::Compression(format!("invalid lz4 frame body: {}", reason))
+}
+
+/// Picks the first algorithm in `offered` that is also `enabled`, or `COMPRESSION_NONE`
+pub fn negotiate(offered: &[i32], enabled: &[Compression]) -> Compression {
+    offered
+        .iter()
+        .filter_map(|&algorithm| Compression::try_from(algorithm).ok())
+        .find(|algorithm| *algorithm != Compression::None && enabled.contains(algorithm))
+        .unwrap_or(Compression::None)
+}
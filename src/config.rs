@@ -0,0 +1,145 @@
+use std::time::Duration;
+
+use crate::{
+    access::AccessList,
+    auth::AuthConfig,
+    codec::MAX_FRAME_LENGTH,
+    compression::{self, DEFAULT_COMPRESSION_THRESHOLD},
+    message::{Compression, OverflowMode},
+    policy::Policy,
+    rate_limit::RateLimitConfig,
+    tls::TlsConfig,
+};
+
+/// What the acceptor does with a new connection when no worker is idle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaturationPolicy {
+    /// Park the connection in the accept queue, rejecting it once the queue is full.
+    Queue,
+    /// Reply with a `ServerBusy` error and close the connection straight away.
+    Reject,
+    /// Stop accepting until there is room in the accept queue again.
+    Block,
+}
+
+/// What the acceptor does with a connection over `max_connections`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionCapPolicy {
+    /// Reply with a `ServerBusy` error and close the connection.
+    Reject,
+    /// Stop accepting until a connection closes, leaving new ones in the listen backlog.
+    Backlog,
+}
+
+/// Tunables for a [`Server`](crate::server::Server).
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Number of worker threads serving connections.
+    pub workers: usize,
+    /// Number of accepted connections allowed to wait for a free worker.
+    pub queue_depth: usize,
+    /// Behaviour once every worker is busy.
+    pub saturation_policy: SaturationPolicy,
+    /// How long `stop` waits for connected clients to finish before force-closing them.
+    pub drain_timeout: Duration,
+    /// Overflow handling for arithmetic requests that don't pick a mode themselves.
+    pub overflow_mode: OverflowMode,
+    /// Number of requests carrying a `request_id` processed concurrently per connection,
+    /// counting replies still waiting to be written.
+    pub max_in_flight: usize,
+    /// Number of worker threads processing requests carrying a `request_id`, shared by
+    /// every connection.
+    pub request_workers: usize,
+    /// Largest request body accepted, in bytes. Longer frames get `FRAME_TOO_LARGE`.
+    pub max_frame_length: usize,
+    /// Bytes one connection may buffer for requests that haven't fully arrived. Raised to fit
+    /// at least one frame of `max_frame_length`.
+    pub connection_memory_limit: usize,
+    /// Bytes all connections together may buffer for requests that haven't fully arrived.
+    pub memory_limit: usize,
+    /// Number of streams one connection may have open at once.
+    pub max_open_streams: usize,
+    /// Longest payload one stream may carry, in bytes.
+    pub max_stream_length: u64,
+    /// Compression algorithms a client may pick in its `Hello`. Empty disables compression.
+    pub compression: Vec<Compression>,
+    /// Frame bodies shorter than this are sent uncompressed, in bytes.
+    pub compression_threshold: usize,
+    /// Whether a client may ask for a CRC32C trailer on every frame in its `Hello`.
+    pub checksums: bool,
+    /// Silence after which the server sends a `Ping`. `None` disables heartbeats.
+    pub heartbeat_interval: Option<Duration>,
+    /// Silence after which a connection is closed, pinged or not. `None` keeps silent
+    /// connections open.
+    pub idle_timeout: Option<Duration>,
+    /// Time a client has to finish a frame once it has started sending it. `None` waits
+    /// forever.
+    pub read_timeout: Option<Duration>,
+    /// Serves every connection over TLS with this certificate and key. `None` serves plain
+    /// TCP.
+    pub tls: Option<TlsConfig>,
+    /// Permission bits of the socket file of a Unix socket listener, such as `0o660`. `None`
+    /// leaves them to the umask.
+    pub unix_socket_mode: Option<u32>,
+    /// Address of the admin listener, which lists and disconnects clients, such as
+    /// `127.0.0.1:7879`. A bare port binds loopback. Other addresses need `admin_auth` with
+    /// HMAC keys. `None` runs no admin listener.
+    pub admin_addr: Option<String>,
+    /// Credentials admin connections authenticate with, kept apart from `auth` so clients
+    /// can't administer the server. The admin listener serves plain TCP, so off loopback it
+    /// refuses bearer tokens. `None` lets every admin connection in, on loopback only.
+    pub admin_auth: Option<AuthConfig>,
+    /// Request rate and bandwidth budgets. Unlimited by default.
+    pub rate_limits: RateLimitConfig,
+    /// Connections served or queued at once. `None` accepts every connection.
+    pub max_connections: Option<usize>,
+    /// What happens to connections over `max_connections`.
+    pub connection_cap_policy: ConnectionCapPolicy,
+    /// Connections from one IP address at once. Connections over it always get
+    /// `ServerBusy`, as their address is only known once they have been accepted.
+    pub max_connections_per_ip: Option<usize>,
+    /// Addresses connections are accepted from. Others are closed without a reply.
+    pub access: AccessList,
+    /// Credentials clients have to authenticate with before sending requests. `None` serves
+    /// every client.
+    pub auth: Option<AuthConfig>,
+    /// Which principals may send which requests, and role overrides of the request size and
+    /// the per-connection rate limits. `None` allows every request.
+    pub policy: Option<Policy>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            workers: 8,
+            queue_depth: 64,
+            saturation_policy: SaturationPolicy::Queue,
+            drain_timeout: Duration::from_secs(5),
+            overflow_mode: OverflowMode::Checked,
+            max_in_flight: 32,
+            request_workers: 16,
+            max_frame_length: MAX_FRAME_LENGTH,
+            connection_memory_limit: 2 * MAX_FRAME_LENGTH,
+            memory_limit: 256 * MAX_FRAME_LENGTH,
+            max_open_streams: 8,
+            max_stream_length: 256 * 1024 * 1024,
+            compression: compression::SUPPORTED.to_vec(),
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            checksums: true,
+            heartbeat_interval: Some(Duration::from_secs(30)),
+            idle_timeout: Some(Duration::from_secs(90)),
+            read_timeout: Some(Duration::from_secs(30)),
+            tls: None,
+            unix_socket_mode: None,
+            admin_addr: None,
+            admin_auth: None,
+            rate_limits: RateLimitConfig::default(),
+            max_connections: None,
+            connection_cap_policy: ConnectionCapPolicy::Reject,
+            max_connections_per_ip: None,
+            access: AccessList::default(),
+            auth: None,
+            policy: None,
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use crate::{
+    auth::Authentication,
+    config::ServerConfig,
+    error::ProtocolError,
+    handshake::{self, Session},
+    memory::MemoryBudget,
+    message::{client_message, ClientMessage, ErrorCode, ServerMessage},
+    metrics::ServerMetrics,
+    rate_limit::ConnectionLimiter,
+    router::Router,
+    server_handler::{error_reply, pong, respond, Identity},
+    stream::{Payload, StreamTracker},
+    unix::PeerCredentials,
+};
+use prost::Message;
+use std::{sync::Arc, time::Duration};
+
+/// What a connection does with a message once [`Connection::process`] has seen it.
+pub(crate) enum Action {
+    /// Send this reply, then read the next message
+    Reply(ServerMessage),
+    /// Send this reply, then close the connection
+    ReplyAndClose(ServerMessage),
+    /// The message is over the rate limit: wait this long, then hand it back to `process`
+    Delay(Duration, ClientMessage),
+    /// Pass the request on to the router
+    Dispatch(Dispatch),
+    /// Nothing to answer
+    Skip,
+}
+
+/// A request that made it through every check, ready for the router.
+pub(crate) struct Dispatch {
+    message: ClientMessage,
+    payload: Option<Payload>,
+    identity: Identity,
+    /// Whether the request may be answered out of order, alongside those sent after it
+    pub(crate) pipelined: bool,
+}
+
+impl Dispatch {
+    /// Routes the request and returns its reply, along with whether the connection may
+    /// stay open
+    pub(crate) fn respond(
+        self,
+        config: &ServerConfig,
+        router: &Router,
+        id: usize,
+    ) -> (ServerMessage, bool) {
+        respond(
+            config,
+            router,
+            id,
+            &self.identity,
+            self.message,
+            self.payload.as_ref(),
+        )
+    }
+}
+
+/// The state of one connection after its handshake, which every decoded message passes
+/// through before it is dispatched: rate limits, authentication, the messages the
+/// connection answers itself, the access policy and stream tracking.
+///
+/// It does no I/O, so the blocking and the async handler both drive it and only differ in
+/// how they carry out the [`Action`] it returns.
+pub(crate) struct Connection {
+    id: usize,
+    config: Arc<ServerConfig>,
+    metrics: Arc<ServerMetrics>,
+    request_ids: bool,
+    peer_credentials: Option<PeerCredentials>,
+    auth: Authentication,
+    rate_limits: ConnectionLimiter,
+    streams: StreamTracker,
+    /// Whether the message being delayed has been counted as throttled already
+    throttled: bool,
+}
+
+impl Connection {
+    /// The state of client `id` once it agreed on `session`, charging its requests to
+    /// `rate_limits`
+    pub(crate) fn new(
+        id: usize,
+        config: Arc<ServerConfig>,
+        router: &Router,
+        memory: Arc<MemoryBudget>,
+        metrics: Arc<ServerMetrics>,
+        session: &Session,
+        mut rate_limits: ConnectionLimiter,
+    ) -> Self {
+        if let Some(policy) = &config.policy {
+            rate_limits.set_budget(&policy.budget(None, config.rate_limits.per_connection));
+        }
+        let streams = if router.reassembles() {
+            StreamTracker::reassembling(&config, memory)
+        } else {
+            StreamTracker::new(&config)
+        };
+        Connection {
+            id,
+            auth: Authentication::new(config.auth.as_ref()),
+            config,
+            metrics,
+            request_ids: session.request_ids,
+            peer_credentials: None,
+            rate_limits,
+            streams,
+            throttled: false,
+        }
+    }
+
+    /// Tells services the connection's peer is a local process with these credentials
+    pub(crate) fn set_peer_credentials(&mut self, peer_credentials: Option<PeerCredentials>) {
+        self.peer_credentials = peer_credentials;
+    }
+
+    /// Decides what to do with a decoded frame, or with the error decoding it
+    pub(crate) fn process(&mut self, message: Result<ClientMessage, ProtocolError>) -> Action {
+        let (id, config) = (self.id, self.config.clone());
+        let message = match message {
+            Ok(message) => message,
+            Err(error) => {
+                if error.code == ErrorCode::ChecksumMismatch {
+                    self.metrics.checksum_failed();
+                }
+                return reply(id, 0, error);
+            }
+        };
+
+        // Every message is charged, so nothing can flood the connection for free
+        match self
+            .rate_limits
+            .throttle(message.encoded_len(), &self.metrics, &mut self.throttled)
+        {
+            Ok(None) => self.throttled = false,
+            Ok(Some(wait)) => return Action::Delay(wait, message),
+            Err(error) => {
+                self.throttled = false;
+                return refuse(id, message.request_id, error);
+            }
+        }
+
+        // Answers our own Ping, its arrival was all that mattered. Connections are pinged
+        // while they authenticate too, so it doesn't have to wait for that.
+        if let Some(client_message::Message::Pong(_)) = message.message {
+            return Action::Skip;
+        }
+
+        match self.auth.screen(id, config.auth.as_ref(), &message) {
+            Ok(None) => {}
+            Ok(Some(response)) => {
+                // The principal's roles may come with limits of their own
+                if let (Some(policy), Some(principal)) = (&config.policy, self.auth.principal()) {
+                    let budget = policy.budget(Some(&principal), config.rate_limits.per_connection);
+                    self.rate_limits.set_budget(&budget);
+                }
+                return Action::Reply(response);
+            }
+            Err(error) => return reply(id, message.request_id, error),
+        }
+
+        match &message.message {
+            Some(client_message::Message::Hello(_)) => {
+                return refuse(id, message.request_id, handshake::repeated_hello());
+            }
+            Some(client_message::Message::Ping(ping)) => {
+                return Action::Reply(pong(message.request_id, ping.nonce));
+            }
+            _ => {}
+        }
+
+        if let Some(Err(error)) = config
+            .policy
+            .as_ref()
+            .map(|policy| policy.authorize(id, self.auth.principal().as_deref(), &message))
+        {
+            return refuse(id, message.request_id, error);
+        }
+
+        let is_stream = message
+            .message
+            .as_ref()
+            .is_some_and(StreamTracker::is_stream_message);
+        let payload = match message
+            .message
+            .as_ref()
+            .map(|body| self.streams.track(body))
+        {
+            Some(Err(error)) => return reply(id, message.request_id, error),
+            Some(Ok(payload)) => payload,
+            None => None,
+        };
+        if let Some(ack) = self.streams.acknowledge(&message) {
+            return Action::Reply(ack);
+        }
+
+        // Requests are answered in the order they arrive, unless the connection agreed on
+        // request IDs and the request has one. Stream messages always keep their order.
+        let pipelined = self.request_ids && message.request_id != 0 && !is_stream;
+        Action::Dispatch(Dispatch {
+            message,
+            payload,
+            identity: Identity {
+                peer_credentials: self.peer_credentials,
+                principal: self.auth.principal(),
+            },
+            pipelined,
+        })
+    }
+}
+
+/// Replies with `error`, closing the connection if the error calls for it
+fn reply(id: usize, request_id: u64, error: ProtocolError) -> Action {
+    match error_reply(id, request_id, error) {
+        (response, true) => Action::Reply(response),
+        (response, false) => Action::ReplyAndClose(response),
+    }
+}
+
+/// Refuses one request with `error`, leaving the connection open
+fn refuse(id: usize, request_id: u64, error: ProtocolError) -> Action {
+    Action::Reply(error_reply(id, request_id, error).0)
+}
@@ -0,0 +1,104 @@
+use std::{fmt, time::Duration};
+
+use crate::{
+    codec::FrameError,
+    message::{server_message, ErrorCode, ErrorResponse, ServerMessage},
+};
+
+/// A request the server could not serve, reported back to the client as an
+/// `ErrorResponse` instead of silently dropping the connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolError {
+    pub code: ErrorCode,
+    pub message: String,
+    /// When the request would succeed if sent again, for `RATE_LIMITED`
+    pub retry_after: Option<Duration>,
+}
+
+impl ProtocolError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ProtocolError {
+            code,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    /// Tells the client to retry after `retry_after`
+    pub fn with_retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = Some(retry_after);
+        self
+    }
+
+    /// Whether the connection has to be closed once this error has been reported
+    pub fn closes_connection(&self) -> bool {
+        self.code.closes_connection()
+    }
+}
+
+impl ErrorCode {
+    /// Errors that leave the stream out of sync (or the server unwilling to go on) close
+    /// the connection. The others are reported and the client may send its next request.
+    pub fn closes_connection(self) -> bool {
+        match self {
+            // The oversized body is never read, so the next frame boundary is unknown
+            ErrorCode::FrameTooLarge | ErrorCode::ServerBusy => true,
+            // Closing is what frees the memory
+            ErrorCode::ResourceExhausted => true,
+            // Nothing else may be sent without a handshake
+            ErrorCode::UnsupportedVersion | ErrorCode::HandshakeRequired => true,
+            // Wrong credentials aren't worth another guess on the same connection
+            ErrorCode::Unauthenticated => true,
+            ErrorCode::Unspecified
+            | ErrorCode::MalformedMessage
+            | ErrorCode::UnsupportedMessage
+            | ErrorCode::InternalError
+            | ErrorCode::ArithmeticOverflow
+            | ErrorCode::DivisionByZero
+            | ErrorCode::InvalidArgument
+            | ErrorCode::Unimplemented
+            | ErrorCode::ChecksumMismatch
+            | ErrorCode::NotFound
+            | ErrorCode::RateLimited
+            | ErrorCode::PermissionDenied => false,
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code.as_str_name(), self.message)
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<FrameError> for ProtocolError {
+    fn from(error: FrameError) -> Self {
+        let code = match error {
+            FrameError::Empty | FrameError::Decode(_) | FrameError::Compression(_) => {
+                ErrorCode::MalformedMessage
+            }
+            FrameError::TooLarge { .. } => ErrorCode::FrameTooLarge,
+            FrameError::Checksum { .. } => ErrorCode::ChecksumMismatch,
+            FrameError::Io(_) => ErrorCode::InternalError,
+        };
+        ProtocolError::new(code, error.to_string())
+    }
+}
+
+impl From<ProtocolError> for ServerMessage {
+    fn from(error: ProtocolError) -> Self {
+        ServerMessage {
+            message: Some(server_message::Message::ErrorResponse(ErrorResponse {
+                code: error.code as i32,
+                message: error.message,
+                // Rounded up, so retrying on time never comes too early
+                retry_after_ms: error.retry_after.map_or(0, |retry_after| {
+                    retry_after.as_nanos().div_ceil(1_000_000).max(1) as u64
+                }),
+            })),
+            ..Default::default()
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use bytes::BytesMut;
+use std::{
+    io::{self, Read},
+    sync::Arc,
+};
+
+use crate::{
+    codec::{
+        self, FrameFormat, CHECKSUM_LENGTH, COMPRESSION_FLAG, LENGTH_PREFIX, MAX_FRAME_LENGTH,
+    },
+    error::ProtocolError,
+    memory::MemoryBudget,
+    message::{ClientMessage, ErrorCode},
+};
+
+/// Size of the chunks [`FrameDecoder::read_from`] reads at a time
+const READ_CHUNK: usize = 8 * 1024;
+
+/// Buffer capacity kept around once a large frame has been decoded
+const RETAINED_CAPACITY: usize = 64 * 1024;
+
+/// Splits a byte stream into `ClientMessage` frames, however the bytes happen to be
+/// chunked on arrival.
+///
+/// Bytes are buffered until a whole frame is available, so a read that returns half a
+/// length prefix or half a body, or fails with `WouldBlock`, loses nothing. The buffer
+/// only grows as bytes actually arrive, whatever length a frame's prefix announces.
+#[derive(Debug)]
+pub struct FrameDecoder {
+    buffer: BytesMut,
+    max_frame_length: usize,
+    memory_limit: usize,
+    budget: Option<Arc<MemoryBudget>>,
+    charged: usize,
+    format: FrameFormat,
+}
+
+impl FrameDecoder {
+    /// Creates a decoder accepting frames up to [`MAX_FRAME_LENGTH`], without a memory budget
+    pub fn new() -> Self {
+        FrameDecoder {
+            buffer: BytesMut::new(),
+            max_frame_length: MAX_FRAME_LENGTH,
+            memory_limit: usize::MAX,
+            budget: None,
+            charged: 0,
+            format: FrameFormat::default(),
+        }
+    }
+
+    /// Creates a decoder accepting frame bodies up to `max_frame_length` bytes, buffering at
+    /// most `memory_limit` bytes and charging them to `budget`.
+    ///
+    /// The limit is raised to fit the largest frame plus one read, or no frame could ever
+    /// complete.
+    pub fn with_limits(
+        max_frame_length: usize,
+        memory_limit: usize,
+        budget: Arc<MemoryBudget>,
+    ) -> Self {
+        FrameDecoder {
+            buffer: BytesMut::new(),
+            max_frame_length,
+            memory_limit: memory_limit.max(
+                LENGTH_PREFIX + COMPRESSION_FLAG + CHECKSUM_LENGTH + max_frame_length + READ_CHUNK,
+            ),
+            budget: Some(budget),
+            charged: 0,
+            format: FrameFormat::default(),
+        }
+    }
+
+    /// Decodes frames in `format` from now on, once a `Hello` has agreed on it
+    pub fn set_format(&mut self, format: FrameFormat) {
+        self.format = format;
+    }
+
+    /// Appends bytes received from the peer
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Reads whatever `reader` has available into the buffer. Returns the number of bytes
+    /// read, 0 at end of stream. Errors, including `WouldBlock`, leave the buffer untouched.
+    pub fn read_from<R: Read>(&mut self, reader: &mut R) -> io::Result<usize> {
+        let mut chunk = [0u8; READ_CHUNK];
+        let read = reader.read(&mut chunk)?;
+        self.extend(&chunk[..read]);
+        Ok(read)
+    }
+
+    /// Number of bytes held for a frame that isn't complete yet
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Takes the next complete frame off the buffer, or returns `None` until more bytes
+    /// arrive.
+    ///
+    /// A frame that fails to decode is dropped and reported, later frames are unaffected.
+    /// A length prefix that is out of bounds is reported as soon as it is complete, without
+    /// waiting for the body. Buffering more than the memory limit, or more than is left of
+    /// the shared budget, is reported as `RESOURCE_EXHAUSTED`. If the error closes the
+    /// connection the stream can't be resynchronised and the decoder shouldn't be used
+    /// further.
+    pub fn next_frame(&mut self) -> Option<Result<ClientMessage, ProtocolError>> {
+        if let Err(error) = self.charge_buffer() {
+            return Some(Err(error));
+        }
+        let frame = codec::decode_frame_with(&mut self.buffer, self.max_frame_length, &self.format)
+            .map_err(ProtocolError::from)
+            .transpose();
+        self.refund_consumed();
+        frame
+    }
+
+    /// Charges the budget for bytes that arrived since the last call
+    fn charge_buffer(&mut self) -> Result<(), ProtocolError> {
+        let held = self.buffer.len();
+        if held <= self.charged {
+            return Ok(());
+        }
+        if held > self.memory_limit {
+            return Err(ProtocolError::new(
+                ErrorCode::ResourceExhausted,
+                format!(
+                    "Connection buffers {} bytes, over its {} byte limit",
+                    held, self.memory_limit
+                ),
+            ));
+        }
+        if let Some(budget) = &self.budget {
+            if !budget.try_charge(held - self.charged) {
+                return Err(ProtocolError::new(
+                    ErrorCode::ResourceExhausted,
+                    "Server is out of memory for incoming requests",
+                ));
+            }
+        }
+        self.charged = held;
+        Ok(())
+    }
+
+    /// Refunds the bytes of frames taken off the buffer, and lets go of the space a large
+    /// frame needed once nothing else is buffered
+    fn refund_consumed(&mut self) {
+        let held = self.buffer.len();
+        if let Some(budget) = &self.budget {
+            budget.refund(self.charged.saturating_sub(held));
+        }
+        self.charged = self.charged.min(held);
+        if held == 0 && self.buffer.capacity() > RETAINED_CAPACITY {
+            self.buffer = BytesMut::new();
+        }
+    }
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for FrameDecoder {
+    fn drop(&mut self) {
+        if let Some(budget) = &self.budget {
+            budget.refund(self.charged);
+        }
+    }
+}
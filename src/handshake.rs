@@ -0,0 +1,97 @@
+use crate::{
+    codec::FrameFormat,
+    compression::{self, FrameCompression},
+    config::ServerConfig,
+    error::ProtocolError,
+    message::{
+        client_message, server_message, Capability, ClientMessage, Compression, ErrorCode,
+        HelloAck, ServerMessage,
+    },
+    metrics::ServerMetrics,
+};
+
+/// Version of the schema in `proto/messages.proto`, sent in every `Hello`
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// What a connection agreed on in its `Hello`, and so how the rest of it is served.
+#[derive(Debug, Clone)]
+pub(crate) struct Session {
+    pub(crate) protocol_version: u32,
+    /// Compression and checksums of every frame after the `HelloAck`
+    pub(crate) format: FrameFormat,
+    /// Whether requests with a `request_id` may be processed concurrently
+    pub(crate) request_ids: bool,
+}
+
+/// Checks that `message` is a `Hello` the server can honour, and agrees on the features the
+/// connection uses from then on. Returns the `HelloAck` to send back, in the plain format.
+pub(crate) fn accept(
+    config: &ServerConfig,
+    metrics: &ServerMetrics,
+    message: &ClientMessage,
+) -> Result<(ServerMessage, Session), ProtocolError> {
+    let Some(client_message::Message::Hello(hello)) = &message.message else {
+        return Err(ProtocolError::new(
+            ErrorCode::HandshakeRequired,
+            "The first message of a connection must be a Hello",
+        ));
+    };
+    if hello.protocol_version != PROTOCOL_VERSION {
+        return Err(ProtocolError::new(
+            ErrorCode::UnsupportedVersion,
+            format!(
+                "Protocol version {} is not supported, this server speaks version {}",
+                hello.protocol_version, PROTOCOL_VERSION
+            ),
+        ));
+    }
+
+    let algorithm = compression::negotiate(&hello.compression, &config.compression);
+    let mut capabilities: Vec<Capability> = hello
+        .capabilities()
+        .filter(|capability| match capability {
+            Capability::RequestIds => true,
+            Capability::Checksums => config.checksums,
+            Capability::Unspecified => false,
+        })
+        .collect();
+    capabilities.sort_unstable_by_key(|&capability| capability as i32);
+    capabilities.dedup();
+
+    let compression = (algorithm != Compression::None).then(|| {
+        FrameCompression::new(
+            algorithm,
+            config.compression_threshold,
+            metrics.compression_stats(),
+        )
+    });
+    let session = Session {
+        protocol_version: hello.protocol_version,
+        format: FrameFormat {
+            compression,
+            checksum: capabilities.contains(&Capability::Checksums),
+        },
+        request_ids: capabilities.contains(&Capability::RequestIds),
+    };
+    let ack = ServerMessage {
+        request_id: message.request_id,
+        message: Some(server_message::Message::HelloAck(HelloAck {
+            protocol_version: session.protocol_version,
+            compression: algorithm as i32,
+            capabilities: capabilities
+                .iter()
+                .map(|&capability| capability as i32)
+                .collect(),
+            authentication_required: config.auth.is_some(),
+        })),
+    };
+    Ok((ack, session))
+}
+
+/// The error for a `Hello` arriving once the handshake is over
+pub(crate) fn repeated_hello() -> ProtocolError {
+    ProtocolError::new(
+        ErrorCode::InvalidArgument,
+        "Hello is only valid as the first message of a connection",
+    )
+}
@@ -0,0 +1,146 @@
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use crate::config::ServerConfig;
+
+/// Shortest and longest time a handler waits on a silent connection before checking its
+/// deadlines again
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Why the server closed a connection that had stopped talking to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReapReason {
+    /// Nothing arrived for `idle_timeout`, and heartbeats are disabled.
+    Idle,
+    /// Nothing arrived for `idle_timeout`, not even a reply to the server's `Ping`. The peer
+    /// is most likely gone without closing the connection.
+    Unresponsive,
+    /// A frame started arriving but wasn't complete within `read_timeout`.
+    ReadTimeout,
+}
+
+impl ReapReason {
+    /// Every reason, in the order [`ServerMetrics`](crate::metrics::ServerMetrics) counts them
+    pub const ALL: [ReapReason; 3] = [
+        ReapReason::Idle,
+        ReapReason::Unresponsive,
+        ReapReason::ReadTimeout,
+    ];
+}
+
+impl fmt::Display for ReapReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ReapReason::Idle => "idle for too long",
+            ReapReason::Unresponsive => "heartbeat went unanswered",
+            ReapReason::ReadTimeout => "frame not completed in time",
+        })
+    }
+}
+
+/// What a handler should do about a connection it hasn't heard from lately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Liveness {
+    Alive,
+    /// Send a `Ping` with this nonce
+    Ping(u64),
+    /// Close the connection
+    Reap(ReapReason),
+}
+
+/// Deadlines of one connection, pushed back by every frame it sends.
+#[derive(Debug)]
+pub(crate) struct Heartbeat {
+    heartbeat_interval: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    last_frame: Instant,
+    /// When the frame that is partly buffered started arriving
+    frame_started: Option<Instant>,
+    /// Whether a `Ping` was sent since the last frame
+    pinged: bool,
+    next_nonce: u64,
+}
+
+impl Heartbeat {
+    pub(crate) fn new(config: &ServerConfig) -> Self {
+        Heartbeat {
+            heartbeat_interval: config.heartbeat_interval,
+            idle_timeout: config.idle_timeout,
+            read_timeout: config.read_timeout,
+            last_frame: Instant::now(),
+            frame_started: None,
+            pinged: false,
+            next_nonce: 1,
+        }
+    }
+
+    /// Deadlines for a connection that hasn't sent its `Hello` yet. It can't be pinged, as
+    /// it hasn't agreed on a frame format.
+    pub(crate) fn for_handshake(config: &ServerConfig) -> Self {
+        Heartbeat {
+            heartbeat_interval: None,
+            ..Self::new(config)
+        }
+    }
+
+    /// How long to wait for data before calling [`check`](Self::check) again, `None` if
+    /// every deadline is disabled
+    pub(crate) fn poll_interval(&self) -> Option<Duration> {
+        [
+            self.heartbeat_interval,
+            self.idle_timeout,
+            self.read_timeout,
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+        .map(|shortest| (shortest / 4).clamp(MIN_POLL_INTERVAL, MAX_POLL_INTERVAL))
+    }
+
+    /// Records a complete frame, which counts as a sign of life whatever it holds
+    pub(crate) fn frame_received(&mut self) {
+        self.last_frame = Instant::now();
+        self.frame_started = None;
+        self.pinged = false;
+    }
+
+    /// Checks the deadlines. `partial_frame` tells whether part of a frame is buffered.
+    pub(crate) fn check(&mut self, partial_frame: bool) -> Liveness {
+        let now = Instant::now();
+        let frame_started = match (partial_frame, self.frame_started) {
+            (false, _) => None,
+            (true, None) => Some(now),
+            (true, started) => started,
+        };
+        self.frame_started = frame_started;
+
+        let silence = now.duration_since(self.last_frame);
+        if let (Some(started), Some(timeout)) = (frame_started, self.read_timeout) {
+            if now.duration_since(started) >= timeout {
+                return Liveness::Reap(ReapReason::ReadTimeout);
+            }
+        }
+        if self.idle_timeout.is_some_and(|timeout| silence >= timeout) {
+            return Liveness::Reap(if self.pinged {
+                ReapReason::Unresponsive
+            } else {
+                ReapReason::Idle
+            });
+        }
+        if !self.pinged
+            && self
+                .heartbeat_interval
+                .is_some_and(|interval| silence >= interval)
+        {
+            let nonce = self.next_nonce;
+            self.next_nonce += 1;
+            self.pinged = true;
+            return Liveness::Ping(nonce);
+        }
+        Liveness::Alive
+    }
+}
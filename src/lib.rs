@@ -1,5 +1,31 @@
+pub mod access;
+mod admin;
+pub mod arithmetic;
+pub mod async_server;
+pub mod async_server_handler;
+pub mod auth;
+pub mod codec;
+pub mod compression;
+pub mod config;
+mod connection;
+pub mod error;
+pub mod frame_decoder;
+pub mod handshake;
+pub mod heartbeat;
+pub mod memory;
+pub mod metrics;
+pub mod middleware;
+pub mod policy;
+pub mod rate_limit;
+pub mod registry;
+pub mod router;
 pub mod server;
-pub mod server_handler; 
+pub mod server_handler;
+mod stream;
+pub mod tls;
+pub mod transport;
+pub mod unix;
+mod worker_pool;
 
 pub mod message {
     include!(concat!(env!("OUT_DIR"), "/messages.rs"));
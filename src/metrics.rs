@@ -0,0 +1,121 @@
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
+
+use crate::{compression::CompressionStats, heartbeat::ReapReason, rate_limit::RateLimitScope};
+
+/// Live counters describing the state of a running server.
+#[derive(Debug, Default)]
+pub struct ServerMetrics {
+    pool_size: AtomicUsize,
+    busy_workers: AtomicUsize,
+    queue_capacity: AtomicUsize,
+    queued: AtomicUsize,
+    rejected: AtomicU64,
+    denied: AtomicU64,
+    checksum_failures: AtomicU64,
+    reaped: [AtomicU64; ReapReason::ALL.len()],
+    throttled: [AtomicU64; RateLimitScope::ALL.len()],
+    compression: Arc<CompressionStats>,
+}
+
+impl ServerMetrics {
+    /// Number of worker threads in the pool
+    pub fn pool_size(&self) -> usize {
+        self.pool_size.load(Ordering::Relaxed)
+    }
+
+    /// Number of workers currently serving a connection
+    pub fn busy_workers(&self) -> usize {
+        self.busy_workers.load(Ordering::Relaxed)
+    }
+
+    /// Maximum number of connections that may wait in the accept queue
+    pub fn queue_capacity(&self) -> usize {
+        self.queue_capacity.load(Ordering::Relaxed)
+    }
+
+    /// Number of connections waiting in the accept queue
+    pub fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Total number of connections turned away because the pool was saturated or a
+    /// connection cap was reached
+    pub fn rejected_connections(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Total number of connections closed because the access list doesn't permit their
+    /// address
+    pub fn denied_connections(&self) -> u64 {
+        self.denied.load(Ordering::Relaxed)
+    }
+
+    /// Total number of frames dropped because they didn't match their checksum
+    pub fn checksum_failures(&self) -> u64 {
+        self.checksum_failures.load(Ordering::Relaxed)
+    }
+
+    /// Total number of connections closed for going silent, for `reason`
+    pub fn reaped_connections(&self, reason: ReapReason) -> u64 {
+        self.reaped[reason as usize].load(Ordering::Relaxed)
+    }
+
+    /// Total number of requests that were over the rate limit of `scope`, whether they were
+    /// rejected or delayed
+    pub fn throttled_requests(&self, scope: RateLimitScope) -> u64 {
+        self.throttled[scope as usize].load(Ordering::Relaxed)
+    }
+
+    /// Sizes of the frames on compressed connections, before and after compression
+    pub fn compression(&self) -> &CompressionStats {
+        &self.compression
+    }
+
+    pub(crate) fn compression_stats(&self) -> Arc<CompressionStats> {
+        self.compression.clone()
+    }
+
+    pub(crate) fn set_pool_shape(&self, pool_size: usize, queue_capacity: usize) {
+        self.pool_size.store(pool_size, Ordering::Relaxed);
+        self.queue_capacity.store(queue_capacity, Ordering::Relaxed);
+    }
+
+    pub(crate) fn worker_started(&self) {
+        self.busy_workers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn worker_finished(&self) {
+        self.busy_workers.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn enqueued(&self) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn dequeued(&self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn checksum_failed(&self) {
+        self.checksum_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_reaped(&self, reason: ReapReason) {
+        self.reaped[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn request_throttled(&self, scope: RateLimitScope) {
+        self.throttled[scope as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_denied(&self) {
+        self.denied.fetch_add(1, Ordering::Relaxed);
+    }
+}
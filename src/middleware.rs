@@ -0,0 +1,143 @@
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+
+use crate::{
+    error::ProtocolError,
+    message::{ClientMessage, ErrorCode, ServerMessage},
+    router::{MessageKind, RequestContext, Router},
+};
+
+/// Behaviour wrapped around every request a [`Router`] handles.
+///
+/// A layer sees the request before the layers inside it and the reply after them. It may
+/// rewrite either, or answer on its own by returning without running `next`. Replies are
+/// tagged with the request's ID whatever the layers set.
+///
+/// Closures taking a [`RequestContext`], a `ClientMessage` and [`Next`] are layers too.
+pub trait Middleware: Send + Sync + 'static {
+    fn handle(
+        &self,
+        context: &RequestContext<'_>,
+        message: ClientMessage,
+        next: Next<'_>,
+    ) -> Result<ServerMessage, ProtocolError>;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(&RequestContext<'_>, ClientMessage, Next<'_>) -> Result<ServerMessage, ProtocolError>
+        + Send
+        + Sync
+        + 'static,
+{
+    fn handle(
+        &self,
+        context: &RequestContext<'_>,
+        message: ClientMessage,
+        next: Next<'_>,
+    ) -> Result<ServerMessage, ProtocolError> {
+        self(context, message, next)
+    }
+}
+
+/// The layers inside the current one, ending with the service the request is routed to.
+pub struct Next<'a> {
+    layers: &'a [Arc<dyn Middleware>],
+    router: &'a Router,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(layers: &'a [Arc<dyn Middleware>], router: &'a Router) -> Self {
+        Next { layers, router }
+    }
+
+    /// Passes `message` on to the next layer, or to its service once no layers are left
+    pub fn run(
+        self,
+        context: &RequestContext<'_>,
+        message: ClientMessage,
+    ) -> Result<ServerMessage, ProtocolError> {
+        match self.layers.split_first() {
+            Some((layer, layers)) => layer.handle(context, message, Next::new(layers, self.router)),
+            None => self.router.call_service(context, message),
+        }
+    }
+}
+
+/// Logs how long each request took, warning about those slower than `slow_threshold`.
+#[derive(Debug, Clone, Copy)]
+pub struct Timing {
+    pub slow_threshold: Duration,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Timing {
+            slow_threshold: Duration::from_millis(100),
+        }
+    }
+}
+
+impl Middleware for Timing {
+    fn handle(
+        &self,
+        context: &RequestContext<'_>,
+        message: ClientMessage,
+        next: Next<'_>,
+    ) -> Result<ServerMessage, ProtocolError> {
+        let kind = message.message.as_ref().and_then(MessageKind::of);
+        let started = Instant::now();
+        let result = next.run(context, message);
+        let elapsed = started.elapsed();
+        let principal = context
+            .principal
+            .map(|principal| format!(" ({})", principal))
+            .unwrap_or_default();
+
+        if elapsed > self.slow_threshold {
+            warn!(
+                "Client {}{}: {:?} request {} took {:?}",
+                context.client_id, principal, kind, context.request_id, elapsed
+            );
+        } else {
+            debug!(
+                "Client {}{}: {:?} request {} took {:?}",
+                context.client_id, principal, kind, context.request_id, elapsed
+            );
+        }
+        result
+    }
+}
+
+/// Answers requests whose handling panics with an `INTERNAL_ERROR` reply, so a bug in one
+/// service doesn't take down the connection or the worker serving it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CatchPanic;
+
+impl Middleware for CatchPanic {
+    fn handle(
+        &self,
+        context: &RequestContext<'_>,
+        message: ClientMessage,
+        next: Next<'_>,
+    ) -> Result<ServerMessage, ProtocolError> {
+        panic::catch_unwind(AssertUnwindSafe(|| next.run(context, message))).unwrap_or_else(
+            |payload| {
+                let reason = payload
+                    .downcast_ref::<&str>()
+                    .copied()
+                    .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("unknown cause");
+                Err(ProtocolError::new(
+                    ErrorCode::InternalError,
+                    format!("Request handler panicked: {}", reason),
+                ))
+            },
+        )
+    }
+}
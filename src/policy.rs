@@ -0,0 +1,327 @@
+use log::warn;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use prost::Message;
+
+use crate::{
+    error::ProtocolError,
+    message::{ClientMessage, ErrorCode},
+    rate_limit::{Budget, RateLimit},
+    router::MessageKind,
+};
+
+/// What the holders of a role may call.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Role {
+    operations: HashSet<MessageKind>,
+    max_request_size: Option<usize>,
+    rate: Budget,
+}
+
+impl Role {
+    /// Allows requests of the given kinds, of any size
+    pub fn new(operations: impl IntoIterator<Item = MessageKind>) -> Self {
+        Role {
+            operations: operations.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Allows only requests of at most `bytes` bytes
+    pub fn with_max_request_size(mut self, bytes: usize) -> Self {
+        self.max_request_size = Some(bytes);
+        self
+    }
+
+    /// Replaces the per-connection limits of `rate` for the role's holders
+    pub fn with_rate(mut self, rate: Budget) -> Self {
+        self.rate = rate;
+        self
+    }
+}
+
+/// Which principals may send which requests, checked before a request is dispatched.
+///
+/// Principals hold the roles granted to them plus the default roles, which every client
+/// holds, authenticated or not. A request is allowed if one of its sender's roles allows
+/// its kind at its size. Denials are logged under the `audit` target, and appended to the
+/// audit log if there is one.
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    roles: HashMap<String, Role>,
+    grants: HashMap<String, Vec<String>>,
+    default_roles: Vec<String>,
+    audit_log: Option<Arc<AuditLog>>,
+}
+
+impl Policy {
+    /// Allows nothing yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Defines the role `name`, replacing any role of that name
+    pub fn with_role(mut self, name: impl Into<String>, role: Role) -> Self {
+        self.roles.insert(name.into(), role);
+        self
+    }
+
+    /// Grants `roles` to `principal`. Roles that aren't defined grant nothing.
+    pub fn with_grant<S: Into<String>>(
+        mut self,
+        principal: impl Into<String>,
+        roles: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.grants
+            .entry(principal.into())
+            .or_default()
+            .extend(roles.into_iter().map(Into::into));
+        self
+    }
+
+    /// Grants `roles` to every client
+    pub fn with_default_roles<S: Into<String>>(
+        mut self,
+        roles: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.default_roles.extend(roles.into_iter().map(Into::into));
+        self
+    }
+
+    /// Appends denials to the file at `path`, creating it if needed
+    pub fn with_audit_log(mut self, path: impl AsRef<Path>) -> io::Result<Self> {
+        self.audit_log = Some(Arc::new(AuditLog::open(path.as_ref())?));
+        Ok(self)
+    }
+
+    /// Reads the policy file at `path`. Each line is one of
+    ///
+    /// - `role name operation... [max_request_size=bytes] [rate=per_second/burst]
+    ///   [bandwidth=per_second/burst]`, where the operations are [`MessageKind`] names
+    /// - `grant principal role...`
+    /// - `default role...`
+    /// - `audit path`
+    ///
+    /// Blank lines and `#` comments are skipped.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        let mut policy = Policy::new();
+        for (number, line) in contents
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let at_line =
+                |message: String| invalid_data(path, format!("line {}: {}", number, message));
+            policy = match fields[..] {
+                ["role", name, ref rest @ ..] => {
+                    policy.with_role(name, parse_role(rest).map_err(at_line)?)
+                }
+                ["grant", principal, ref roles @ ..] => {
+                    policy.with_grant(principal, roles.to_vec())
+                }
+                ["default", ref roles @ ..] => policy.with_default_roles(roles.to_vec()),
+                ["audit", audit_path] => policy.with_audit_log(audit_path)?,
+                _ => return Err(at_line(format!("can't parse \"{}\"", line))),
+            };
+        }
+
+        let granted = policy
+            .grants
+            .values()
+            .flatten()
+            .chain(&policy.default_roles);
+        if let Some(role) = granted
+            .into_iter()
+            .find(|role| !policy.roles.contains_key(*role))
+        {
+            return Err(invalid_data(path, format!("role {} isn't defined", role)));
+        }
+        Ok(policy)
+    }
+
+    /// Lets `message` through if one of the roles of `principal` allows it, or returns the
+    /// `PERMISSION_DENIED` error to reply with after auditing the denial
+    pub(crate) fn authorize(
+        &self,
+        id: usize,
+        principal: Option<&str>,
+        message: &ClientMessage,
+    ) -> Result<(), ProtocolError> {
+        // Messages the router can't place are left to it to refuse
+        let Some(kind) = message.message.as_ref().and_then(MessageKind::of) else {
+            return Ok(());
+        };
+        let who = principal.unwrap_or("Anonymous client");
+        let allowed: Vec<_> = self
+            .roles_of(principal)
+            .filter(|role| role.operations.contains(&kind))
+            .collect();
+        if allowed.is_empty() {
+            return Err(self.deny(
+                id,
+                principal,
+                kind,
+                format!("{} may not call {}", who, kind.name()),
+            ));
+        }
+
+        let size = message.encoded_len();
+        let largest = allowed
+            .iter()
+            .map(|role| role.max_request_size)
+            .max_by_key(|max| max.unwrap_or(usize::MAX))
+            .flatten();
+        match largest {
+            Some(max) if size > max => Err(self.deny(
+                id,
+                principal,
+                kind,
+                format!(
+                    "{} may not call {} with more than {} bytes, the request has {}",
+                    who,
+                    kind.name(),
+                    max,
+                    size
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// The per-connection budget of `principal`: `default`, with the most generous of the
+    /// overrides of its roles in each dimension that has any
+    pub(crate) fn budget(&self, principal: Option<&str>, default: Budget) -> Budget {
+        let rates: Vec<_> = self.roles_of(principal).map(|role| role.rate).collect();
+        let most_generous = |limits: &mut dyn Iterator<Item = RateLimit>| {
+            limits.max_by_key(|limit| (limit.per_second, limit.burst))
+        };
+        Budget {
+            messages: most_generous(&mut rates.iter().filter_map(|rate| rate.messages))
+                .or(default.messages),
+            bytes: most_generous(&mut rates.iter().filter_map(|rate| rate.bytes)).or(default.bytes),
+        }
+    }
+
+    fn roles_of<'a>(&'a self, principal: Option<&str>) -> impl Iterator<Item = &'a Role> {
+        let granted = principal
+            .and_then(|principal| self.grants.get(principal))
+            .into_iter()
+            .flatten();
+        self.default_roles
+            .iter()
+            .chain(granted)
+            .filter_map(|name| self.roles.get(name))
+    }
+
+    fn deny(
+        &self,
+        id: usize,
+        principal: Option<&str>,
+        kind: MessageKind,
+        reason: String,
+    ) -> ProtocolError {
+        let entry = format!(
+            "client={} principal={} operation={} denied: {}",
+            id,
+            principal.unwrap_or("-"),
+            kind.name(),
+            reason
+        );
+        warn!(target: "audit", "{}", entry);
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.record(&entry);
+        }
+        ProtocolError::new(ErrorCode::PermissionDenied, reason)
+    }
+}
+
+/// File the denials of a policy are appended to, one line each.
+#[derive(Debug)]
+struct AuditLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        Ok(AuditLog {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Appends `entry`, prefixed with the time in milliseconds since the Unix epoch
+    fn record(&self, entry: &str) {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = writeln!(file, "{} {}", millis, entry) {
+            warn!("Failed to write audit log {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Parses the operations and overrides of a `role` line
+fn parse_role(fields: &[&str]) -> Result<Role, String> {
+    let mut role = Role::default();
+    for field in fields {
+        match field.split_once('=') {
+            Some(("max_request_size", bytes)) => {
+                let bytes = bytes
+                    .parse()
+                    .map_err(|_| format!("{} isn't a size in bytes", bytes))?;
+                role = role.with_max_request_size(bytes);
+            }
+            Some(("rate", limit)) => role.rate.messages = Some(parse_rate_limit(limit)?),
+            Some(("bandwidth", limit)) => role.rate.bytes = Some(parse_rate_limit(limit)?),
+            Some((name, _)) => return Err(format!("unknown setting {}", name)),
+            None => {
+                let kind = MessageKind::ALL
+                    .into_iter()
+                    .find(|kind| kind.name() == *field)
+                    .ok_or_else(|| format!("unknown operation {}", field))?;
+                role.operations.insert(kind);
+            }
+        }
+    }
+    Ok(role)
+}
+
+/// Parses a `per_second/burst` pair
+fn parse_rate_limit(limit: &str) -> Result<RateLimit, String> {
+    limit
+        .split_once('/')
+        .and_then(|(per_second, burst)| {
+            Some(RateLimit::new(
+                per_second.parse().ok()?,
+                burst.parse().ok()?,
+            ))
+        })
+        .ok_or_else(|| format!("{} isn't a per_second/burst pair", limit))
+}
+
+fn invalid_data(path: &Path, message: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), message),
+    )
+}
@@ -0,0 +1,302 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{error::ProtocolError, message::ErrorCode, metrics::ServerMetrics};
+
+/// A token bucket refilling `per_second` tokens a second and holding at most `burst`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Sustained rate. Values below 1 count as 1.
+    pub per_second: u64,
+    /// Tokens that can be saved up and spent at once. Values below 1 count as 1.
+    pub burst: u64,
+}
+
+impl RateLimit {
+    pub fn new(per_second: u64, burst: u64) -> Self {
+        RateLimit { per_second, burst }
+    }
+}
+
+/// Message and byte budgets of one scope. `None` leaves that dimension unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Budget {
+    /// Requests a second
+    pub messages: Option<RateLimit>,
+    /// Bytes of request bodies a second
+    pub bytes: Option<RateLimit>,
+}
+
+/// What happens to a request that is over budget.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Throttle {
+    /// Reply with a `RATE_LIMITED` error saying when to retry. The connection stays open.
+    #[default]
+    Reject,
+    /// Hold the request until the budget allows it, reading nothing else from the
+    /// connection meanwhile.
+    Delay,
+}
+
+/// Rate limits of a [`Server`](crate::server::Server) or an
+/// [`AsyncServer`](crate::async_server::AsyncServer). Every request has to fit in all
+/// three scopes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RateLimitConfig {
+    /// Budget of each connection
+    pub per_connection: Budget,
+    /// Budget shared by the connections from one IP address. Unix sockets have none.
+    pub per_peer_ip: Budget,
+    /// Budget shared by every connection
+    pub global: Budget,
+    pub throttle: Throttle,
+}
+
+/// The budget a throttled request didn't fit in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitScope {
+    Connection,
+    PeerIp,
+    Global,
+}
+
+impl RateLimitScope {
+    /// Every scope, in the order [`ServerMetrics`] counts them
+    pub const ALL: [RateLimitScope; 3] = [
+        RateLimitScope::Connection,
+        RateLimitScope::PeerIp,
+        RateLimitScope::Global,
+    ];
+}
+
+impl fmt::Display for RateLimitScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RateLimitScope::Connection => "connection",
+            RateLimitScope::PeerIp => "peer IP",
+            RateLimitScope::Global => "server",
+        })
+    }
+}
+
+/// The budgets shared by a server's connections.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    config: RateLimitConfig,
+    global: Mutex<Buckets>,
+    peers: Mutex<HashMap<IpAddr, Peer>>,
+}
+
+/// Budget of one IP address, kept while it has connections or hasn't refilled since its last
+/// one closed, so reconnecting doesn't hand out a fresh budget.
+#[derive(Debug)]
+struct Peer {
+    buckets: Arc<Mutex<Buckets>>,
+    connections: usize,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(config: RateLimitConfig) -> Self {
+        RateLimiter {
+            global: Mutex::new(Buckets::new(&config.global)),
+            peers: Mutex::default(),
+            config,
+        }
+    }
+
+    /// Budgets of a new connection from `peer_ip`
+    pub(crate) fn connection(self: &Arc<Self>, peer_ip: Option<IpAddr>) -> ConnectionLimiter {
+        let peer = peer_ip.map(|ip| {
+            let mut peers = self.peers.lock().unwrap();
+            let now = Instant::now();
+            peers.retain(|_, peer| {
+                peer.connections > 0 || !peer.buckets.lock().unwrap().is_full(now)
+            });
+            let peer = peers.entry(ip).or_insert_with(|| Peer {
+                buckets: Arc::new(Mutex::new(Buckets::new(&self.config.per_peer_ip))),
+                connections: 0,
+            });
+            peer.connections += 1;
+            (ip, peer.buckets.clone())
+        });
+        ConnectionLimiter {
+            limiter: self.clone(),
+            own: Buckets::new(&self.config.per_connection),
+            peer,
+        }
+    }
+}
+
+/// The budgets one connection's requests are charged to.
+#[derive(Debug)]
+pub(crate) struct ConnectionLimiter {
+    limiter: Arc<RateLimiter>,
+    own: Buckets,
+    peer: Option<(IpAddr, Arc<Mutex<Buckets>>)>,
+}
+
+impl ConnectionLimiter {
+    /// Charges a request of `bytes` bytes to every budget. Over budget, it returns how
+    /// long to wait before trying again under [`Throttle::Delay`], or the `RATE_LIMITED`
+    /// error to reply with under [`Throttle::Reject`]. The request is counted as throttled
+    /// once, unless `counted` says an earlier attempt was.
+    pub(crate) fn throttle(
+        &mut self,
+        bytes: usize,
+        metrics: &ServerMetrics,
+        counted: &mut bool,
+    ) -> Result<Option<Duration>, ProtocolError> {
+        let (scope, wait) = match self.try_take(bytes as u64) {
+            Ok(()) => return Ok(None),
+            Err(over) => over,
+        };
+        if !*counted {
+            metrics.request_throttled(scope);
+            *counted = true;
+        }
+        match self.limiter.config.throttle {
+            Throttle::Delay => Ok(Some(wait)),
+            Throttle::Reject => Err(ProtocolError::new(
+                ErrorCode::RateLimited,
+                format!("Over the {} rate limit, retry in {:?}", scope, wait),
+            )
+            .with_retry_after(wait)),
+        }
+    }
+
+    /// Replaces the connection's own budget with a full one of `budget`
+    pub(crate) fn set_budget(&mut self, budget: &Budget) {
+        self.own = Buckets::new(budget);
+    }
+
+    /// Takes the request out of every bucket if all of them have room for it. Otherwise
+    /// takes nothing and returns the scope that has to wait longest, and for how long.
+    fn try_take(&mut self, bytes: u64) -> Result<(), (RateLimitScope, Duration)> {
+        let now = Instant::now();
+        let mut peer = self
+            .peer
+            .as_ref()
+            .map(|(_, buckets)| buckets.lock().unwrap());
+        let mut global = self.limiter.global.lock().unwrap();
+
+        let mut scopes = vec![(RateLimitScope::Connection, &mut self.own)];
+        if let Some(peer) = peer.as_deref_mut() {
+            scopes.push((RateLimitScope::PeerIp, peer));
+        }
+        scopes.push((RateLimitScope::Global, &mut global));
+
+        let longest = scopes
+            .iter_mut()
+            .map(|(scope, buckets)| (*scope, buckets.wait(now, bytes)))
+            .max_by_key(|(_, wait)| *wait)
+            .filter(|(_, wait)| !wait.is_zero());
+        if let Some(over) = longest {
+            return Err(over);
+        }
+        for (_, buckets) in scopes {
+            buckets.take(bytes);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ConnectionLimiter {
+    fn drop(&mut self) {
+        if let Some((ip, _)) = self.peer.take() {
+            let mut peers = self.limiter.peers.lock().unwrap();
+            // The budget stays until it has refilled, new connections sweep it out
+            if let Some(peer) = peers.get_mut(&ip) {
+                peer.connections -= 1;
+            }
+        }
+    }
+}
+
+/// The message and byte buckets of one scope.
+#[derive(Debug)]
+struct Buckets {
+    messages: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl Buckets {
+    fn new(budget: &Budget) -> Self {
+        Buckets {
+            messages: budget.messages.map(TokenBucket::new),
+            bytes: budget.bytes.map(TokenBucket::new),
+        }
+    }
+
+    /// How long until one message of `bytes` bytes fits, zero if it does now
+    fn wait(&mut self, now: Instant, bytes: u64) -> Duration {
+        let messages = self.messages.as_mut().map(|bucket| bucket.wait(now, 1));
+        let bytes = self.bytes.as_mut().map(|bucket| bucket.wait(now, bytes));
+        messages.into_iter().chain(bytes).max().unwrap_or_default()
+    }
+
+    /// Whether every bucket has refilled, so a fresh set would be no different
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.messages
+            .iter_mut()
+            .chain(&mut self.bytes)
+            .all(|bucket| bucket.is_full(now))
+    }
+
+    fn take(&mut self, bytes: u64) {
+        if let Some(bucket) = &mut self.messages {
+            bucket.take(1);
+        }
+        if let Some(bucket) = &mut self.bytes {
+            bucket.take(bytes);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    per_second: f64,
+    burst: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket
+    fn new(limit: RateLimit) -> Self {
+        TokenBucket {
+            per_second: limit.per_second.max(1) as f64,
+            burst: limit.burst.max(1) as f64,
+            tokens: limit.burst.max(1) as f64,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Refills the bucket, then returns how long until it holds `amount` tokens. Amounts
+    /// above the burst only need a full bucket, which they then leave in debt.
+    fn wait(&mut self, now: Instant, amount: u64) -> Duration {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.burst);
+        self.refilled = now;
+
+        let needed = (amount as f64).min(self.burst);
+        if self.tokens >= needed {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((needed - self.tokens) / self.per_second)
+        }
+    }
+
+    /// Refills the bucket, then returns whether it holds a full burst
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.wait(now, u64::MAX).is_zero()
+    }
+
+    fn take(&mut self, amount: u64) {
+        self.tokens -= amount as f64;
+    }
+}
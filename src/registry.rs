@@ -0,0 +1,277 @@
+use log::info;
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Shutdown, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{transport::Transport, unix::PeerCredentials};
+
+/// A snapshot of one live connection, as returned by
+/// [`Server::connections`](crate::server::Server::connections).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// Server-assigned client ID, the one services see as `RequestContext::client_id`
+    pub id: usize,
+    /// The peer's address. `None` for Unix domain sockets, whose peers have credentials
+    /// instead.
+    pub peer_addr: Option<SocketAddr>,
+    /// Who the peer is, for Unix domain sockets
+    pub peer_credentials: Option<PeerCredentials>,
+    pub connected_at: SystemTime,
+    /// When a frame was last received from or sent to the client
+    pub last_activity: SystemTime,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    pub messages_received: u64,
+    pub messages_sent: u64,
+}
+
+impl ConnectionInfo {
+    /// The peer's address, or its credentials for a Unix domain socket, for display
+    pub fn peer(&self) -> String {
+        match (self.peer_addr, self.peer_credentials) {
+            (Some(addr), _) => addr.to_string(),
+            (None, Some(peer)) => match peer.pid {
+                Some(pid) => format!("unix:uid={},gid={},pid={}", peer.uid, peer.gid, pid),
+                None => format!("unix:uid={},gid={}", peer.uid, peer.gid),
+            },
+            (None, None) => "unknown".to_string(),
+        }
+    }
+}
+
+/// Traffic counters of one connection, updated by its handler.
+#[derive(Debug)]
+pub(crate) struct ConnectionActivity {
+    connected: Instant,
+    connected_at: SystemTime,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    messages_received: AtomicU64,
+    messages_sent: AtomicU64,
+    /// Milliseconds between `connected` and the last frame
+    last_frame: AtomicU64,
+}
+
+impl Default for ConnectionActivity {
+    fn default() -> Self {
+        ConnectionActivity {
+            connected: Instant::now(),
+            connected_at: SystemTime::now(),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            messages_received: AtomicU64::new(0),
+            messages_sent: AtomicU64::new(0),
+            last_frame: AtomicU64::new(0),
+        }
+    }
+}
+
+impl ConnectionActivity {
+    pub(crate) fn bytes_received(&self, bytes: usize) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn message_received(&self) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+        self.touch();
+    }
+
+    /// Records a frame of `bytes` bytes sent to the client
+    pub(crate) fn message_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+        self.touch();
+    }
+
+    fn touch(&self) {
+        let elapsed = self.connected.elapsed().as_millis() as u64;
+        self.last_frame.fetch_max(elapsed, Ordering::Relaxed);
+    }
+}
+
+/// What the registry holds for each connection.
+struct Connection {
+    stream: Transport,
+    peer_addr: Option<SocketAddr>,
+    peer_credentials: Option<PeerCredentials>,
+    activity: Arc<ConnectionActivity>,
+}
+
+impl Connection {
+    fn info(&self, id: usize) -> ConnectionInfo {
+        let activity = &self.activity;
+        let last_frame = Duration::from_millis(activity.last_frame.load(Ordering::Relaxed));
+        ConnectionInfo {
+            id,
+            peer_addr: self.peer_addr,
+            peer_credentials: self.peer_credentials,
+            connected_at: activity.connected_at,
+            last_activity: activity.connected_at + last_frame,
+            bytes_received: activity.bytes_received.load(Ordering::Relaxed),
+            bytes_sent: activity.bytes_sent.load(Ordering::Relaxed),
+            messages_received: activity.messages_received.load(Ordering::Relaxed),
+            messages_sent: activity.messages_sent.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Keeps a handle on every live client connection so the server can reach them
+/// from outside the worker serving each one.
+#[derive(Default)]
+pub(crate) struct ConnectionRegistry {
+    state: Mutex<RegistryState>,
+    emptied: Condvar,
+    /// Signalled whenever a connection is unregistered
+    released: Condvar,
+}
+
+#[derive(Default)]
+struct RegistryState {
+    connections: HashMap<usize, Connection>,
+    /// Set once shutdown has begun, until the survivors are force-closed
+    draining: bool,
+    /// Connections that closed on their own while draining
+    drained: usize,
+}
+
+impl ConnectionRegistry {
+    /// Starts tracking the connection of client `id`
+    pub(crate) fn register(&self, id: usize, stream: &Transport) -> io::Result<()> {
+        let handle = stream.try_clone()?;
+        let mut state = self.state.lock().unwrap();
+        if state.draining {
+            // Accepted while `stop` was signalling the others
+            let _ = handle.shutdown(Shutdown::Read);
+        }
+        let connection = Connection {
+            peer_addr: handle.peer_addr(),
+            peer_credentials: handle.peer_credentials(),
+            stream: handle,
+            activity: Arc::default(),
+        };
+        state.connections.insert(id, connection);
+        Ok(())
+    }
+
+    /// The traffic counters of client `id`, for its handler to keep up to date. `None` if
+    /// the client isn't connected.
+    pub(crate) fn activity(&self, id: usize) -> Option<Arc<ConnectionActivity>> {
+        let state = self.state.lock().unwrap();
+        state
+            .connections
+            .get(&id)
+            .map(|connection| connection.activity.clone())
+    }
+
+    /// Every live connection, by client ID
+    pub(crate) fn list(&self) -> Vec<ConnectionInfo> {
+        let state = self.state.lock().unwrap();
+        let mut connections: Vec<_> = state
+            .connections
+            .iter()
+            .map(|(&id, connection)| connection.info(id))
+            .collect();
+        connections.sort_by_key(|info| info.id);
+        connections
+    }
+
+    /// The connection of client `id`, if it is live
+    pub(crate) fn inspect(&self, id: usize) -> Option<ConnectionInfo> {
+        let state = self.state.lock().unwrap();
+        state
+            .connections
+            .get(&id)
+            .map(|connection| connection.info(id))
+    }
+
+    /// Closes the connection of client `id`. Its handler sees the connection fail and
+    /// returns, abandoning whatever requests it was processing. Returns `false` if the
+    /// client isn't connected.
+    pub(crate) fn disconnect(&self, id: usize) -> bool {
+        let state = self.state.lock().unwrap();
+        match state.connections.get(&id) {
+            Some(connection) => {
+                info!("Disconnecting client {}", id);
+                let _ = connection.stream.shutdown(Shutdown::Both);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stops tracking client `id`, typically once its handler has returned
+    pub(crate) fn unregister(&self, id: usize) {
+        let mut state = self.state.lock().unwrap();
+        if state.connections.remove(&id).is_some() && state.draining {
+            state.drained += 1;
+        }
+        if state.connections.is_empty() {
+            self.emptied.notify_all();
+        }
+        self.released.notify_all();
+    }
+
+    /// Number of live connections, from `ip` only if given
+    pub(crate) fn count(&self, ip: Option<IpAddr>) -> usize {
+        let state = self.state.lock().unwrap();
+        match ip {
+            Some(ip) => state
+                .connections
+                .values()
+                .filter(|connection| connection.peer_addr.is_some_and(|addr| addr.ip() == ip))
+                .count(),
+            None => state.connections.len(),
+        }
+    }
+
+    /// Waits up to `timeout` for fewer than `limit` connections to be live.
+    /// Returns `true` if there is room.
+    pub(crate) fn wait_for_room(&self, limit: usize, timeout: Duration) -> bool {
+        let state = self.state.lock().unwrap();
+        let (state, _) = self
+            .released
+            .wait_timeout_while(state, timeout, |state| state.connections.len() >= limit)
+            .unwrap();
+        state.connections.len() < limit
+    }
+
+    /// Starts draining: closes the read half of every connection. Handlers finish the
+    /// request they are working on, then see end-of-stream on their next read and return.
+    pub(crate) fn begin_drain(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.draining = true;
+        for connection in state.connections.values() {
+            let _ = connection.stream.shutdown(Shutdown::Read);
+        }
+    }
+
+    /// Waits up to `timeout` for every connection to be unregistered.
+    /// Returns `true` if the registry emptied in time.
+    pub(crate) fn wait_until_empty(&self, timeout: Duration) -> bool {
+        let state = self.state.lock().unwrap();
+        let (state, _) = self
+            .emptied
+            .wait_timeout_while(state, timeout, |state| !state.connections.is_empty())
+            .unwrap();
+        state.connections.is_empty()
+    }
+
+    /// Ends draining by closing both halves of every remaining connection.
+    /// Returns how many connections drained on their own and how many were force-closed.
+    pub(crate) fn close_all(&self) -> (usize, usize) {
+        let mut state = self.state.lock().unwrap();
+        state.draining = false;
+        for connection in state.connections.values() {
+            let _ = connection.stream.shutdown(Shutdown::Both);
+        }
+        (state.drained, state.connections.len())
+    }
+}
@@ -0,0 +1,265 @@
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use crate::{
+    config::ServerConfig,
+    error::ProtocolError,
+    message::{client_message, server_message, ClientMessage, ErrorCode, ServerMessage},
+    middleware::{Middleware, Next},
+    server_handler::BuiltinService,
+    unix::PeerCredentials,
+};
+
+/// What a [`Service`] knows about the request it is answering.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestContext<'a> {
+    /// Server-assigned ID of the connection the request arrived on
+    pub client_id: usize,
+    /// The request's `request_id`, 0 if the client didn't set one
+    pub request_id: u64,
+    /// Configuration of the server handling the request
+    pub config: &'a ServerConfig,
+    /// Who the client is, for connections over a Unix domain socket
+    pub peer_credentials: Option<PeerCredentials>,
+    /// Who the client authenticated as, if the server requires authentication
+    pub principal: Option<&'a str>,
+    /// The whole payload of the stream a `StreamEnd` closes, if the router reassembles
+    /// streams
+    pub payload: Option<&'a [u8]>,
+}
+
+/// Answers the requests a [`Router`] sends its way.
+///
+/// Closures taking a [`RequestContext`] and a `client_message::Message` are services too.
+pub trait Service: Send + Sync + 'static {
+    fn call(
+        &self,
+        context: &RequestContext<'_>,
+        message: client_message::Message,
+    ) -> Result<server_message::Message, ProtocolError>;
+}
+
+impl<F> Service for F
+where
+    F: Fn(
+            &RequestContext<'_>,
+            client_message::Message,
+        ) -> Result<server_message::Message, ProtocolError>
+        + Send
+        + Sync
+        + 'static,
+{
+    fn call(
+        &self,
+        context: &RequestContext<'_>,
+        message: client_message::Message,
+    ) -> Result<server_message::Message, ProtocolError> {
+        self(context, message)
+    }
+}
+
+/// Answers streams a [`Router`] reassembles, once their whole payload has arrived.
+///
+/// Closures taking a [`RequestContext`], a stream ID and the payload are stream services
+/// too.
+pub trait StreamService: Send + Sync + 'static {
+    fn call(
+        &self,
+        context: &RequestContext<'_>,
+        stream_id: u64,
+        payload: &[u8],
+    ) -> Result<server_message::Message, ProtocolError>;
+}
+
+impl<F> StreamService for F
+where
+    F: Fn(&RequestContext<'_>, u64, &[u8]) -> Result<server_message::Message, ProtocolError>
+        + Send
+        + Sync
+        + 'static,
+{
+    fn call(
+        &self,
+        context: &RequestContext<'_>,
+        stream_id: u64,
+        payload: &[u8],
+    ) -> Result<server_message::Message, ProtocolError> {
+        self(context, stream_id, payload)
+    }
+}
+
+/// The request variants of `ClientMessage`, used as routing keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageKind {
+    Echo,
+    Add,
+    AddInt64,
+    Arithmetic,
+    /// `StreamStart`, `StreamChunk` and `StreamEnd`, which share one service
+    Stream,
+}
+
+impl MessageKind {
+    /// Every kind of request a client can send
+    pub const ALL: [MessageKind; 5] = [
+        MessageKind::Echo,
+        MessageKind::Add,
+        MessageKind::AddInt64,
+        MessageKind::Arithmetic,
+        MessageKind::Stream,
+    ];
+
+    /// Returns the kind of `message`, `None` for a `Hello`, `Ping`, `Pong` or `AuthRequest`,
+    /// which the connection handles itself and never routes
+    pub fn of(message: &client_message::Message) -> Option<Self> {
+        match message {
+            client_message::Message::EchoMessage(_) => Some(MessageKind::Echo),
+            client_message::Message::AddRequest(_) => Some(MessageKind::Add),
+            client_message::Message::AddInt64Request(_) => Some(MessageKind::AddInt64),
+            client_message::Message::ArithmeticRequest(_) => Some(MessageKind::Arithmetic),
+            client_message::Message::StreamStart(_)
+            | client_message::Message::StreamChunk(_)
+            | client_message::Message::StreamEnd(_) => Some(MessageKind::Stream),
+            client_message::Message::Hello(_)
+            | client_message::Message::Ping(_)
+            | client_message::Message::Pong(_)
+            | client_message::Message::AuthRequest(_) => None,
+        }
+    }
+
+    /// Name of the kind in policy files and audit logs, such as `add_int64`
+    pub fn name(self) -> &'static str {
+        match self {
+            MessageKind::Echo => "echo",
+            MessageKind::Add => "add",
+            MessageKind::AddInt64 => "add_int64",
+            MessageKind::Arithmetic => "arithmetic",
+            MessageKind::Stream => "stream",
+        }
+    }
+}
+
+/// Maps each kind of request to the [`Service`] answering it.
+///
+/// `Router::default()` serves every request the way the stock server does. Start from
+/// [`Router::empty`] to expose only the services registered with [`route`](Self::route).
+/// Cross-cutting behaviour is added with [`layer`](Self::layer); the first layer added is
+/// the outermost.
+#[derive(Clone)]
+pub struct Router {
+    routes: HashMap<MessageKind, Arc<dyn Service>>,
+    layers: Vec<Arc<dyn Middleware>>,
+    streams: Option<Arc<dyn StreamService>>,
+}
+
+impl Router {
+    /// Creates a router without any services, answering every request as unimplemented
+    pub fn empty() -> Self {
+        Router {
+            routes: HashMap::new(),
+            layers: Vec::new(),
+            streams: None,
+        }
+    }
+
+    /// Sends requests of `kind` to `service`, replacing the service previously registered for it
+    pub fn route(mut self, kind: MessageKind, service: impl Service) -> Self {
+        self.routes.insert(kind, Arc::new(service));
+        self
+    }
+
+    /// Wraps `middleware` around every request, inside the layers added before it
+    pub fn layer(mut self, middleware: impl Middleware) -> Self {
+        self.layers.push(Arc::new(middleware));
+        self
+    }
+
+    /// Buffers every stream until its `StreamEnd` and hands the whole payload to `service`,
+    /// through the layers. Starts and chunks are only acknowledged, so the service
+    /// registered for [`MessageKind::Stream`] no longer sees them.
+    pub fn reassemble(mut self, service: impl StreamService) -> Self {
+        self.streams = Some(Arc::new(service));
+        self
+    }
+
+    /// Returns whether a service is registered for `kind`
+    pub fn handles(&self, kind: MessageKind) -> bool {
+        self.routes.contains_key(&kind)
+    }
+
+    /// Returns whether streams are reassembled for a [`StreamService`]
+    pub fn reassembles(&self) -> bool {
+        self.streams.is_some()
+    }
+
+    /// Runs `message` through the layers, then the service registered for its kind
+    pub(crate) fn dispatch(
+        &self,
+        context: &RequestContext<'_>,
+        message: ClientMessage,
+    ) -> Result<ServerMessage, ProtocolError> {
+        let mut response = Next::new(&self.layers, self).run(context, message)?;
+        // A layer answering on its own may not have tagged its reply
+        response.request_id = context.request_id;
+        Ok(response)
+    }
+
+    /// Hands `message` to the service registered for its kind
+    pub(crate) fn call_service(
+        &self,
+        context: &RequestContext<'_>,
+        message: ClientMessage,
+    ) -> Result<ServerMessage, ProtocolError> {
+        let message = message.message.ok_or_else(|| {
+            ProtocolError::new(ErrorCode::UnsupportedMessage, "Unsupported message type")
+        })?;
+        if let (client_message::Message::StreamEnd(end), Some(service), Some(payload)) =
+            (&message, &self.streams, context.payload)
+        {
+            let response = service.call(context, end.stream_id, payload)?;
+            return Ok(ServerMessage {
+                request_id: context.request_id,
+                message: Some(response),
+            });
+        }
+        let kind = MessageKind::of(&message).ok_or_else(not_routed)?;
+        let service = self.routes.get(&kind).ok_or_else(|| {
+            ProtocolError::new(
+                ErrorCode::Unimplemented,
+                format!("No service registered for {:?} requests", kind),
+            )
+        })?;
+        let response = service.call(context, message)?;
+        Ok(ServerMessage {
+            request_id: context.request_id,
+            message: Some(response),
+        })
+    }
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        MessageKind::ALL
+            .into_iter()
+            .fold(Router::empty(), |router, kind| {
+                router.route(kind, BuiltinService)
+            })
+    }
+}
+
+impl fmt::Debug for Router {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Router")
+            .field("routes", &self.routes.keys())
+            .field("layers", &self.layers.len())
+            .field("reassembles", &self.streams.is_some())
+            .finish()
+    }
+}
+
+/// The error for a message the connection handles itself reaching a service
+pub(crate) fn not_routed() -> ProtocolError {
+    ProtocolError::new(
+        ErrorCode::UnsupportedMessage,
+        "Hello, Ping, Pong and AuthRequest are handled by the connection, not by a service",
+    )
+}
@@ -1,85 +1,484 @@
 use log::{info, warn};
 use std::{
-    io::{self},
-    net::TcpListener,
+    fmt,
+    io::{self, Read},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    os::unix::net::UnixListener,
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc, Mutex,
     },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use crate::server_handler::ServerHandler;
+use crate::{
+    admin::AdminListener,
+    codec,
+    config::{ConnectionCapPolicy, SaturationPolicy, ServerConfig},
+    error::ProtocolError,
+    memory::MemoryBudget,
+    message::{ErrorCode, ServerMessage},
+    metrics::ServerMetrics,
+    rate_limit::RateLimiter,
+    registry::{ConnectionInfo, ConnectionRegistry},
+    router::Router,
+    server_handler::ServerHandler,
+    tls::TlsConfig,
+    transport::{TlsStream, Transport},
+    unix::UnixAddr,
+    worker_pool::{Task, WorkerPool},
+};
+
+/// How often an acceptor waiting for room below `max_connections` checks for `stop`
+const CAP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a rejected client has to read its `ServerBusy` before the connection is closed
+pub(crate) const REJECT_LINGER: Duration = Duration::from_millis(500);
+
+/// Rejected connections kept open at once to let their client read the reply. Past it they
+/// are closed straight away.
+const MAX_LINGERING: usize = 32;
+
+/// Outcome of a server shutdown, returned by [`Server::run`] once it has stopped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// Clients that finished their in-flight request and closed within the drain deadline
+    pub drained: usize,
+    /// Clients still busy at the drain deadline whose connection was closed under them
+    pub force_closed: usize,
+}
 
 pub struct Server {
-    listener: TcpListener,
+    listener: Listener,
     is_running: Arc<AtomicBool>,
+    stop_requested: Arc<AtomicBool>,
+    config: Arc<ServerConfig>,
+    metrics: Arc<ServerMetrics>,
+    connections: Arc<ConnectionRegistry>,
+    router: Arc<Router>,
+    memory: Arc<MemoryBudget>,
+    rate_limiter: Arc<RateLimiter>,
+    /// Set when connections are served over TLS
+    tls: Option<Arc<rustls::ServerConfig>>,
+    /// Set when `config.admin_addr` is
+    admin: Option<Arc<AdminListener>>,
+    /// Rejected connections waiting for their client to read the reply
+    lingering: Arc<AtomicUsize>,
 }
 
 impl Server {
-    /// Creates a new server instance
-    pub fn new(addr: &str) -> io::Result<Self> {
-        let listener = TcpListener::bind(addr)?;
-        let is_running = Arc::new(AtomicBool::new(false));
+    /// Creates a new server instance answering requests through `router`
+    pub fn new(addr: &str, router: Router) -> io::Result<Self> {
+        Self::with_config(addr, ServerConfig::default(), router)
+    }
+
+    /// Creates a new server instance with the given configuration. Fails if the TLS
+    /// certificate or key can't be loaded.
+    pub fn with_config(addr: &str, config: ServerConfig, router: Router) -> io::Result<Self> {
+        let tls = config.tls.as_ref().map(TlsConfig::load).transpose()?;
+        let listener = Listener::Tcp(TcpListener::bind(addr)?);
+        Self::with_listener(listener, config, router, tls)
+    }
+
+    /// Creates a server listening on a Unix domain socket. The socket file, if any, gets
+    /// the permissions in `config.unix_socket_mode`. TLS isn't offered over Unix sockets.
+    pub fn unix(addr: UnixAddr, config: ServerConfig, router: Router) -> io::Result<Self> {
+        if config.tls.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "TLS is only served over TCP",
+            ));
+        }
+        let listener = Listener::Unix(addr.bind(config.unix_socket_mode)?, addr);
+        Self::with_listener(listener, config, router, None)
+    }
+
+    fn with_listener(
+        listener: Listener,
+        config: ServerConfig,
+        router: Router,
+        tls: Option<Arc<rustls::ServerConfig>>,
+    ) -> io::Result<Self> {
+        let admin = config
+            .admin_addr
+            .as_deref()
+            .map(|addr| AdminListener::bind(addr, config.admin_auth.clone()))
+            .transpose()?;
         Ok(Server {
             listener,
-            is_running,
+            is_running: Arc::new(AtomicBool::new(false)),
+            stop_requested: Arc::new(AtomicBool::new(false)),
+            memory: Arc::new(MemoryBudget::new(config.memory_limit)),
+            rate_limiter: Arc::new(RateLimiter::new(config.rate_limits.clone())),
+            config: Arc::new(config),
+            metrics: Arc::new(ServerMetrics::default()),
+            connections: Arc::new(ConnectionRegistry::default()),
+            router: Arc::new(router),
+            tls,
+            admin: admin.map(Arc::new),
+            lingering: Arc::default(),
         })
     }
 
-    /// Runs the server, listening for incoming connections and handling them
-    pub fn run(&self) -> io::Result<()> {
+    /// Returns the address the server is bound to. Binding to port 0 picks a free
+    /// port, this is how to find out which one. Fails for a Unix socket listener.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match &self.listener {
+            Listener::Tcp(listener) => listener.local_addr(),
+            Listener::Unix(..) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the server listens on a Unix socket",
+            )),
+        }
+    }
+
+    /// Returns the address of the admin listener, `None` if it has none
+    pub fn admin_addr(&self) -> Option<SocketAddr> {
+        self.admin.as_ref()?.local_addr().ok()
+    }
+
+    /// Every live connection, by client ID
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.connections.list()
+    }
+
+    /// The connection of client `id`, `None` if it isn't connected
+    pub fn connection(&self, id: usize) -> Option<ConnectionInfo> {
+        self.connections.inspect(id)
+    }
+
+    /// Closes the connection of client `id` without waiting for its in-flight requests.
+    /// Returns `false` if the client isn't connected.
+    pub fn disconnect(&self, id: usize) -> bool {
+        self.connections.disconnect(id)
+    }
+
+    /// Returns the live pool, queue and compression counters of this server
+    pub fn metrics(&self) -> Arc<ServerMetrics> {
+        self.metrics.clone()
+    }
+
+    /// Runs the server, listening for incoming connections and handling them.
+    ///
+    /// Returns once [`stop`](Self::stop) has been called and every client has either
+    /// drained or been force-closed at the drain deadline.
+    pub fn run(&self) -> io::Result<ShutdownReport> {
         self.is_running.store(true, Ordering::SeqCst); // Set the server as running
-        info!("Server is running on {}", self.listener.local_addr()?);
+        info!("Server is running on {}", self.listener);
 
         let client_id = Arc::new(Mutex::new(0));
+        let admin = self.admin.clone().map(|admin| {
+            let connections = self.connections.clone();
+            thread::spawn(move || admin.run(&connections))
+        });
 
-        // Set the listener to non-blocking mode
-        self.listener.set_nonblocking(true)?;
+        // Pipelined requests of every connection share one bounded set of workers
+        let requests: Arc<WorkerPool<Task>> = Arc::new(WorkerPool::new(
+            self.config.request_workers,
+            self.config.request_workers,
+            None,
+            |task: Task| task(),
+        ));
+
+        // Connections are served by a fixed set of workers instead of a thread each
+        let request_pool = requests.clone();
+        let connections = self.connections.clone();
+        let config = self.config.clone();
+        let router = self.router.clone();
+        let memory = self.memory.clone();
+        let metrics = self.metrics.clone();
+        let rate_limiter = self.rate_limiter.clone();
+        let tls = self.tls.clone();
+        let pool = WorkerPool::new(
+            self.config.workers,
+            self.config.queue_depth,
+            Some(self.metrics.clone()),
+            move |(id, transport): (usize, Transport)| {
+                // Leaves the registry however the handler ends, a panic included
+                let _registration = Registration {
+                    connections: &connections,
+                    id,
+                };
+                // The TLS handshake happens on the handler's first reads, on this worker
+                let transport = match (transport, &tls) {
+                    (Transport::Tcp(stream), Some(tls)) => {
+                        match rustls::ServerConnection::new(tls.clone()) {
+                            Ok(session) => Transport::Tls(TlsStream::new(stream, session)),
+                            Err(e) => {
+                                warn!("Failed to start TLS for client {}: {}", id, e);
+                                return;
+                            }
+                        }
+                    }
+                    (transport, _) => transport,
+                };
+                let mut server_handler: ServerHandler = ServerHandler::new(
+                    transport,
+                    config.clone(),
+                    router.clone(),
+                    memory.clone(),
+                    metrics.clone(),
+                );
+                if let Some(activity) = connections.activity(id) {
+                    server_handler.set_activity(activity);
+                }
+                server_handler.set_rate_limiter(rate_limiter.clone());
+                server_handler.set_request_pool(request_pool.clone());
+                if let Err(e) = server_handler.handle(id) {
+                    eprintln!("Error handling client {}: {}", id, e);
+                }
+            },
+        );
 
-        for stream in self.listener.incoming() {
-            if !self.is_running.load(Ordering::SeqCst) {
+        // The listener blocks in accept; `stop` wakes it with a connection of its own
+        while self.wait_for_room() {
+            let stream = self.listener.accept();
+            if self.stop_requested.load(Ordering::SeqCst) {
                 break;
             }
             match stream {
                 Ok(stream) => {
+                    let peer_ip = stream.peer_addr().map(|addr| addr.ip());
+                    if let Some(ip) = peer_ip.filter(|&ip| !self.config.access.permits(ip)) {
+                        self.deny(ip, stream);
+                        continue;
+                    }
                     let id = {
                         let mut id_lock = client_id.lock().unwrap();
                         *id_lock += 1;
                         *id_lock
                     };
-                    let is_running = self.is_running.clone(); // Pass running state to thread
-
-                    // Spawn a thread for each client
-                    thread::spawn(move || {
-                        let mut server_handler: ServerHandler = ServerHandler::new(stream);
-                        if let Err(e) = server_handler.handle(id) {
-                            eprintln!("Error handling client {}: {}", id, e);
-                        }
+                    if let Some(reason) = self.over_connection_cap(peer_ip) {
+                        self.reject(id, stream, reason);
+                        continue;
+                    }
+                    if let Err(e) = self.connections.register(id, &stream) {
+                        warn!("Failed to track client {}: {}", id, e);
+                        continue;
+                    }
 
-                        // Check if the server is still running after handling the client
-                        if !is_running.load(Ordering::SeqCst) {
-                            println!("Server shutting down gracefully.");
-                        }
-                    });
+                    let submitted = match self.config.saturation_policy {
+                        SaturationPolicy::Queue => pool.try_submit((id, stream)),
+                        SaturationPolicy::Reject if pool.is_saturated() => Err((id, stream)),
+                        // An idle worker is about to pick this up, so the hand-off won't block for long
+                        SaturationPolicy::Reject => pool.submit((id, stream)),
+                        SaturationPolicy::Block => pool.submit((id, stream)),
+                    };
+                    if let Err((id, stream)) = submitted {
+                        self.connections.unregister(id);
+                        self.reject(id, stream, "worker pool saturated");
+                    }
                 }
 
-                Err(_e) => {
+                Err(e) => {
+                    warn!("Failed to accept connection: {}", e);
                     thread::sleep(Duration::from_millis(100));
                 }
             }
         }
-        Ok(())
+
+        let report = self.drain(pool);
+        // Every handler has waited for its requests, and with the connection workers gone
+        // nothing else holds the pool
+        if let Ok(requests) = Arc::try_unwrap(requests) {
+            requests.join();
+        }
+        if let (Some(admin), Some(handle)) = (&self.admin, admin) {
+            admin.close();
+            let _ = handle.join();
+        }
+        if let Listener::Unix(_, addr) = &self.listener {
+            addr.remove();
+        }
+        self.is_running.store(false, Ordering::SeqCst);
+        info!(
+            "Server stopped: {} client(s) drained, {} force-closed",
+            report.drained, report.force_closed
+        );
+        Ok(report)
     }
 
-    /// Stops the server by setting the `is_running` flag to `false`
+    /// Lets connected clients finish their in-flight request, force-closing whoever is
+    /// still around at the drain deadline, then joins every worker.
+    fn drain(&self, pool: WorkerPool<(usize, Transport)>) -> ShutdownReport {
+        self.connections.begin_drain();
+        self.connections.wait_until_empty(self.config.drain_timeout);
+        let (drained, force_closed) = self.connections.close_all();
+        pool.join();
+
+        ShutdownReport {
+            drained,
+            force_closed,
+        }
+    }
+
+    /// Under [`ConnectionCapPolicy::Backlog`], waits until the server is below
+    /// `max_connections`, leaving new connections in the listen backlog meanwhile.
+    /// Returns `false` if the server was stopped instead.
+    fn wait_for_room(&self) -> bool {
+        if let (Some(limit), ConnectionCapPolicy::Backlog) = (
+            self.config.max_connections,
+            self.config.connection_cap_policy,
+        ) {
+            while !self.connections.wait_for_room(limit, CAP_POLL_INTERVAL) {
+                if self.stop_requested.load(Ordering::SeqCst) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Returns which connection cap a new connection from `peer_ip` is over, if any
+    fn over_connection_cap(&self, peer_ip: Option<IpAddr>) -> Option<&'static str> {
+        if self
+            .config
+            .max_connections
+            .is_some_and(|limit| self.connections.count(None) >= limit)
+        {
+            return Some("connection limit reached");
+        }
+        match (peer_ip, self.config.max_connections_per_ip) {
+            (Some(ip), Some(limit)) if self.connections.count(Some(ip)) >= limit => {
+                Some("connection limit of its address reached")
+            }
+            _ => None,
+        }
+    }
+
+    /// Closes a connection from an address the access list doesn't permit, without a reply
+    fn deny(&self, ip: IpAddr, stream: Transport) {
+        self.metrics.connection_denied();
+        warn!("Refusing connection from {}: not in the access list", ip);
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+
+    /// Tells a client the server has no capacity left for it and closes the connection
+    fn reject(&self, id: usize, mut stream: Transport, reason: &str) {
+        self.metrics.connection_rejected();
+        warn!("Rejecting client {}: {}", id, reason);
+
+        // A TLS client can't read a plaintext reply, and isn't worth a handshake
+        if self.tls.is_none() {
+            let response =
+                ProtocolError::new(ErrorCode::ServerBusy, "Server is busy, try again later");
+            if let Err(e) = codec::write_frame(&mut stream, &ServerMessage::from(response)) {
+                warn!("Failed to notify rejected client {}: {}", id, e);
+            }
+        }
+        // Closing with the client's `Hello` unread would answer it with a reset, which may
+        // discard the reply before the client gets to read it
+        let _ = stream.shutdown(Shutdown::Write);
+        if self.lingering.fetch_add(1, Ordering::SeqCst) >= MAX_LINGERING {
+            self.lingering.fetch_sub(1, Ordering::SeqCst);
+            return;
+        }
+        let lingering = self.lingering.clone();
+        thread::spawn(move || {
+            discard_input(stream, REJECT_LINGER);
+            lingering.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+
+    /// Stops the server: wakes the accept loop and tells every connected client's handler
+    /// to finish its in-flight request and close. `run` then drains and returns.
+    /// A stop requested before `run` has started still takes effect.
     pub fn stop(&self) {
-        if self.is_running.load(Ordering::SeqCst) {
-            self.is_running.store(false, Ordering::SeqCst);
-            info!("Shutdown signal sent.");
-        } else {
-            warn!("Server was already stopped or not running.");
+        if self.stop_requested.swap(true, Ordering::SeqCst) {
+            warn!("Server was already stopped.");
+            return;
+        }
+        if !self.is_running.load(Ordering::SeqCst) {
+            warn!("Server is not running yet, it will stop as soon as it starts.");
+        }
+
+        self.connections.begin_drain();
+        self.wake_acceptor();
+        info!("Shutdown signal sent.");
+    }
+
+    /// Connects to our own listener so a blocked `accept` returns and sees the stop flag
+    fn wake_acceptor(&self) {
+        let woken = match &self.listener {
+            Listener::Tcp(listener) => listener.local_addr().and_then(|addr| {
+                TcpStream::connect_timeout(&loopback(addr), Duration::from_secs(1)).map(drop)
+            }),
+            Listener::Unix(_, addr) => addr.connect().map(drop),
+        };
+        if let Err(e) = woken {
+            warn!("Failed to wake accept loop: {}", e);
+        }
+    }
+}
+
+/// Reads and throws away whatever `stream` sends until it closes, fails or `linger` runs out
+fn discard_input(mut stream: Transport, linger: Duration) {
+    let deadline = Instant::now() + linger;
+    let mut discarded = [0u8; 1024];
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() || stream.set_read_timeout(Some(left)).is_err() {
+            return;
+        }
+        match stream.read(&mut discarded) {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+    }
+}
+
+/// Where to connect to reach a listener bound to `addr`, which may be a wildcard address
+pub(crate) fn loopback(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) if v4.ip().is_unspecified() => {
+            SocketAddr::from((Ipv4Addr::LOCALHOST, v4.port()))
+        }
+        SocketAddr::V6(v6) if v6.ip().is_unspecified() => {
+            SocketAddr::from((Ipv6Addr::LOCALHOST, v6.port()))
+        }
+        addr => addr,
+    }
+}
+
+/// A client's entry in the connection registry, removed when dropped.
+struct Registration<'a> {
+    connections: &'a ConnectionRegistry,
+    id: usize,
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.connections.unregister(self.id);
+    }
+}
+
+/// The socket a [`Server`] accepts connections on.
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, UnixAddr),
+}
+
+impl Listener {
+    fn accept(&self) -> io::Result<Transport> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, _)| stream.into()),
+            Listener::Unix(listener, _) => listener.accept().map(|(stream, _)| stream.into()),
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{}", addr),
+                Err(_) => f.write_str("an unknown TCP address"),
+            },
+            Listener::Unix(_, UnixAddr::Path(path)) => write!(f, "unix:{}", path.display()),
+            Listener::Unix(_, UnixAddr::Abstract(name)) => {
+                write!(f, "unix:@{}", String::from_utf8_lossy(name))
+            }
         }
     }
 }
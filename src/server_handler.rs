@@ -1,32 +1,143 @@
-use crate::message::{
-    client_message, server_message, AddRequest, AddResponse, ClientMessage, EchoMessage,
-    ServerMessage,
+use crate::{
+    arithmetic,
+    codec::{self, FrameFormat},
+    config::ServerConfig,
+    connection::{Action, Connection},
+    error::ProtocolError,
+    frame_decoder::FrameDecoder,
+    handshake::{self, Session},
+    heartbeat::{Heartbeat, Liveness, ReapReason},
+    memory::MemoryBudget,
+    message::{
+        client_message, server_message, AddInt64Request, AddInt64Response, AddRequest, AddResponse,
+        ArithmeticOperator, ArithmeticRequest, ArithmeticResponse, ClientMessage, EchoMessage,
+        ErrorCode, OverflowMode, Ping, Pong, ServerMessage,
+    },
+    metrics::ServerMetrics,
+    rate_limit::RateLimiter,
+    registry::ConnectionActivity,
+    router::{self, RequestContext, Router, Service},
+    stream::Payload,
+    transport::Transport,
+    unix::PeerCredentials,
+    worker_pool::{Task, WorkerPool},
 };
-use prost::Message;
+use log::warn;
 use std::{
-    io::{self, ErrorKind, Read, Write},
-    net::TcpStream,
-    thread,
+    io::{self, ErrorKind, Write},
+    net::Shutdown,
+    sync::{mpsc, Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
 };
 
 pub struct ServerHandler {
-    stream: TcpStream,
+    stream: Transport,
+    decoder: FrameDecoder,
+    config: Arc<ServerConfig>,
+    memory: Arc<MemoryBudget>,
+    router: Arc<Router>,
+    metrics: Arc<ServerMetrics>,
+    peer_credentials: Option<PeerCredentials>,
+    activity: Arc<ConnectionActivity>,
+    rate_limiter: Arc<RateLimiter>,
+    requests: Option<Arc<WorkerPool<Task>>>,
 }
 
 impl ServerHandler {
-    pub fn new(stream: TcpStream) -> Self {
-        ServerHandler { stream }
+    /// Creates a handler whose request buffers are charged to `memory` and whose traffic is
+    /// counted in `metrics`
+    pub fn new(
+        stream: Transport,
+        config: Arc<ServerConfig>,
+        router: Arc<Router>,
+        memory: Arc<MemoryBudget>,
+        metrics: Arc<ServerMetrics>,
+    ) -> Self {
+        let decoder = FrameDecoder::with_limits(
+            config.max_frame_length,
+            config.connection_memory_limit,
+            memory.clone(),
+        );
+        ServerHandler {
+            memory: memory.clone(),
+            rate_limiter: Arc::new(RateLimiter::new(config.rate_limits.clone())),
+            peer_credentials: stream.peer_credentials(),
+            stream,
+            decoder,
+            config,
+            router,
+            metrics,
+            activity: Arc::default(),
+            requests: None,
+        }
+    }
+
+    /// Counts the connection's traffic in `activity`, the counters the server's connection
+    /// registry reports
+    pub(crate) fn set_activity(&mut self, activity: Arc<ConnectionActivity>) {
+        self.activity = activity;
+    }
+
+    /// Charges the connection's requests to the per-IP and global budgets of `rate_limiter`
+    /// as well as its own, instead of budgets of its own
+    pub(crate) fn set_rate_limiter(&mut self, rate_limiter: Arc<RateLimiter>) {
+        self.rate_limiter = rate_limiter;
+    }
+
+    /// Runs requests carrying a request ID on `requests`, a pool shared with other
+    /// connections. Without one they are answered in order, like those without an ID.
+    pub(crate) fn set_request_pool(&mut self, requests: Arc<WorkerPool<Task>>) {
+        self.requests = Some(requests);
     }
+
     pub fn handle(&mut self, id: usize) -> io::Result<()> {
         println!("Client {} connected", id);
+        let Some(session) = self.handshake(id)? else {
+            return Ok(());
+        };
+        self.decoder.set_format(session.format.clone());
 
-        loop {
-            let message = match self.read_message() {
-                Ok(msg) => msg,
-                Err(e) if e.kind() == ErrorKind::WouldBlock => {
-                    thread::sleep(std::time::Duration::from_millis(10));
+        let (config, router, metrics) = (
+            self.config.clone(),
+            self.router.clone(),
+            self.metrics.clone(),
+        );
+        let in_flight = Arc::new(InFlightLimit::new(config.max_in_flight));
+        let (writer, writer_thread) = ReplyWriter::spawn(
+            id,
+            self.stream.try_clone()?,
+            session.format.clone(),
+            self.activity.clone(),
+            in_flight.clone(),
+        );
+        let writer = Arc::new(writer);
+        let requests = self.requests.clone();
+        let mut connection = Connection::new(
+            id,
+            config.clone(),
+            &router,
+            self.memory.clone(),
+            metrics.clone(),
+            &session,
+            self.rate_limiter
+                .connection(self.stream.peer_addr().map(|addr| addr.ip())),
+        );
+        connection.set_peer_credentials(self.peer_credentials);
+        let mut heartbeat = Heartbeat::new(&config);
+        self.stream.set_read_timeout(heartbeat.poll_interval())?;
+
+        let served = (|| loop {
+            let mut message = match self.read_message(&mut heartbeat) {
+                Ok(Incoming::Message(message)) => message,
+                Ok(Incoming::Ping(nonce)) => {
+                    writer.send(ping(nonce), true)?;
                     continue;
                 }
+                Ok(Incoming::Reap(reason)) => {
+                    reap(id, &metrics, reason);
+                    let _ = self.stream.shutdown(Shutdown::Both);
+                    return Ok(());
+                }
                 Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
                     println!("Client {} disconnected.", id);
                     return Ok(());
@@ -34,86 +145,443 @@ impl ServerHandler {
                 Err(e) => return Err(e),
             };
 
-            let response = self.process_message(message)?;
-            self.send_response(response)?;
+            let action = loop {
+                match connection.process(message) {
+                    Action::Delay(wait, delayed) => {
+                        thread::sleep(wait);
+                        message = Ok(delayed);
+                    }
+                    action => break action,
+                }
+            };
+            let dispatch = match action {
+                Action::Reply(response) => {
+                    writer.send(response, true)?;
+                    continue;
+                }
+                Action::ReplyAndClose(response) => {
+                    writer.send(response, false)?;
+                    return Ok(());
+                }
+                Action::Dispatch(dispatch) => dispatch,
+                Action::Delay(..) | Action::Skip => continue,
+            };
+
+            let Some(requests) = requests.as_ref().filter(|_| dispatch.pipelined) else {
+                let (response, keep_alive) = dispatch.respond(&config, &router, id);
+                writer.send(response, keep_alive)?;
+                if !keep_alive {
+                    return Ok(());
+                }
+                continue;
+            };
+
+            let slot = InFlightLimit::acquire(&in_flight);
+            let (config, router, writer) = (config.clone(), router.clone(), writer.clone());
+            let task: Task = Box::new(move || {
+                let (response, keep_alive) = dispatch.respond(&config, &router, id);
+                // The slot is given back once the reply is written, or straight away if the
+                // task panics or the connection's writer has stopped
+                if let Err(e) = writer.reply(response, keep_alive, slot) {
+                    warn!("Client {}: failed to send response: {}", id, e);
+                }
+            });
+            // Only a pool that is shutting down hands the task back
+            if let Err(task) = requests.submit(task) {
+                task();
+            }
+        })();
+
+        // A reaped or broken connection isn't waited on to take the replies still queued
+        if served.is_err() {
+            let _ = self.stream.shutdown(Shutdown::Both);
         }
+        // Requests still being processed by the pool are answered before returning
+        drop(writer);
+        let _ = writer_thread.join();
+        served
     }
-    fn read_message(&mut self) -> io::Result<ClientMessage> {
-        // Read message length
-        let mut length_buf = [0u8; 4];
-        self.stream.read_exact(&mut length_buf)?;
-        let message_length = u32::from_be_bytes(length_buf) as usize;
-
-        // Validate message length
-        if message_length == 0 || message_length > 1024 * 1024 {
-            return Err(io::Error::new(
-                ErrorKind::InvalidData,
-                "Invalid message length",
-            ));
+
+    /// Reads the connection's `Hello` and answers it. Returns what the connection agreed on,
+    /// or `None` once it has been refused or closed.
+    ///
+    /// A connection that fails its handshake is closed, whatever the error.
+    fn handshake(&mut self, id: usize) -> io::Result<Option<Session>> {
+        let mut heartbeat = Heartbeat::for_handshake(&self.config);
+        self.stream.set_read_timeout(heartbeat.poll_interval())?;
+        let accepted = loop {
+            match self.read_message(&mut heartbeat) {
+                Ok(Incoming::Message(Ok(message))) => {
+                    break handshake::accept(&self.config, &self.metrics, &message)
+                        .map_err(|error| (message.request_id, error))
+                }
+                Ok(Incoming::Message(Err(error))) => break Err((0, error)),
+                Ok(Incoming::Ping(_)) => {}
+                Ok(Incoming::Reap(reason)) => {
+                    reap(id, &self.metrics, reason);
+                    return Ok(None);
+                }
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    println!("Client {} disconnected.", id);
+                    return Ok(None);
+                }
+                Err(e) => return Err(e),
+            }
+        };
+
+        match accepted {
+            Ok((ack, session)) => {
+                write_reply(
+                    &mut self.stream,
+                    &FrameFormat::default(),
+                    &self.activity,
+                    &ack,
+                )?;
+                Ok(Some(session))
+            }
+            Err((request_id, error)) => {
+                let (response, _) = error_reply(id, request_id, error);
+                write_reply(
+                    &mut self.stream,
+                    &FrameFormat::default(),
+                    &self.activity,
+                    &response,
+                )?;
+                Ok(None)
+            }
         }
+    }
 
-        // Read message content
-        let mut message_buf = vec![0u8; message_length];
-        self.stream.read_exact(&mut message_buf)?;
+    /// Returns the next frame, reading until one is complete or `heartbeat` calls for
+    /// something else. The error is a transport failure.
+    ///
+    /// The stream's read timeout wakes this up on a silent connection, so the deadlines are
+    /// checked between reads. Bytes of a partial frame stay in the decoder meanwhile.
+    fn read_message(&mut self, heartbeat: &mut Heartbeat) -> io::Result<Incoming> {
+        loop {
+            if let Some(message) = self.decoder.next_frame() {
+                heartbeat.frame_received();
+                self.activity.message_received();
+                return Ok(Incoming::Message(message));
+            }
+            match heartbeat.check(self.decoder.buffered() > 0) {
+                Liveness::Alive => {}
+                Liveness::Ping(nonce) => return Ok(Incoming::Ping(nonce)),
+                Liveness::Reap(reason) => return Ok(Incoming::Reap(reason)),
+            }
+            match self.decoder.read_from(&mut self.stream) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        format!(
+                            "connection closed with {} bytes of an incomplete frame",
+                            self.decoder.buffered()
+                        ),
+                    ))
+                }
+                Ok(read) => self.activity.bytes_received(read),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut
+                    ) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
 
-        // Decode protobuf message
-        ClientMessage::decode(&message_buf[..]).map_err(|e| {
-            io::Error::new(
-                ErrorKind::InvalidData,
-                format!("Failed to decode message: {}", e),
-            )
-        })
+    // Handle AddRequest and respond with AddResponse, or an overflow error
+    pub fn handle_add_request(
+        &self,
+        add_request: AddRequest,
+    ) -> Result<AddResponse, ProtocolError> {
+        add(add_request, self.config.overflow_mode)
     }
+}
 
-    fn process_message(&self, message: ClientMessage) -> io::Result<ServerMessage> {
-        let mut server_message = ServerMessage::default();
+/// Who is on the other end of a connection, as far as the server knows.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Identity {
+    pub(crate) peer_credentials: Option<PeerCredentials>,
+    pub(crate) principal: Option<Arc<str>>,
+}
 
-        match message.message {
-            Some(client_message::Message::AddRequest(add_request)) => {
-                let mut response = AddResponse::default();
-                response.result = add_request.a + add_request.b;
+/// Routes `message` to its service and returns the reply tagged with its request ID, along
+/// with whether the connection may stay open. `payload` is that of the reassembled stream
+/// a `StreamEnd` closes.
+pub(crate) fn respond(
+    config: &ServerConfig,
+    router: &Router,
+    id: usize,
+    identity: &Identity,
+    message: ClientMessage,
+    payload: Option<&Payload>,
+) -> (ServerMessage, bool) {
+    let context = RequestContext {
+        client_id: id,
+        request_id: message.request_id,
+        config,
+        peer_credentials: identity.peer_credentials,
+        principal: identity.principal.as_deref(),
+        payload: payload.map(Payload::data),
+    };
+    match router.dispatch(&context, message) {
+        Ok(response) => (response, true),
+        Err(error) => error_reply(id, context.request_id, error),
+    }
+}
+
+/// Turns `error` into the reply for request `request_id`. Returns the reply and whether the
+/// connection may stay open.
+pub(crate) fn error_reply(
+    id: usize,
+    request_id: u64,
+    error: ProtocolError,
+) -> (ServerMessage, bool) {
+    warn!("Client {}: {}", id, error);
+    let keep_alive = !error.closes_connection();
+    let mut response = ServerMessage::from(error);
+    response.request_id = request_id;
+    (response, keep_alive)
+}
 
-                let mut server_message = ServerMessage::default();
-                server_message.message = Some(server_message::Message::AddResponse(response));
-                Ok(server_message)
+/// The services every server offers unless its [`Router`] says otherwise
+pub(crate) struct BuiltinService;
+
+impl Service for BuiltinService {
+    fn call(
+        &self,
+        context: &RequestContext<'_>,
+        message: client_message::Message,
+    ) -> Result<server_message::Message, ProtocolError> {
+        let overflow_mode = context.config.overflow_mode;
+        match message {
+            client_message::Message::AddRequest(add_request) => Ok(
+                server_message::Message::AddResponse(add(add_request, overflow_mode)?),
+            ),
+            client_message::Message::AddInt64Request(add_request) => Ok(
+                server_message::Message::AddInt64Response(add_int64(add_request, overflow_mode)?),
+            ),
+            client_message::Message::ArithmeticRequest(arithmetic_request) => {
+                Ok(server_message::Message::ArithmeticResponse(arithmetic(
+                    arithmetic_request,
+                    overflow_mode,
+                )?))
+            }
+            client_message::Message::EchoMessage(echo_request) => {
+                Ok(server_message::Message::EchoMessage(EchoMessage {
+                    content: echo_request.content,
+                }))
+            }
+            // Streams are echoed piece by piece, so a payload is never held in full
+            client_message::Message::StreamStart(start) => {
+                Ok(server_message::Message::StreamStart(start))
             }
-            Some(client_message::Message::EchoMessage(echo_request)) => {
-                let mut response = EchoMessage::default();
-                response.content = echo_request.content;
-                server_message.message = Some(server_message::Message::EchoMessage(response));
-                Ok(server_message)
+            client_message::Message::StreamChunk(chunk) => {
+                Ok(server_message::Message::StreamChunk(chunk))
             }
-            _ => Err(io::Error::new(
-                ErrorKind::InvalidData,
-                "Unsupported message type",
-            )),
+            client_message::Message::StreamEnd(end) => Ok(server_message::Message::StreamEnd(end)),
+            client_message::Message::Hello(_)
+            | client_message::Message::Ping(_)
+            | client_message::Message::Pong(_)
+            | client_message::Message::AuthRequest(_) => Err(router::not_routed()),
         }
     }
+}
 
-    fn send_response(&mut self, response: ServerMessage) -> io::Result<()> {
-        let mut response_buf = Vec::new();
-        response.encode(&mut response_buf).map_err(|e| {
-            io::Error::new(
-                ErrorKind::InvalidData,
-                format!("Failed to encode response: {}", e),
-            )
-        })?;
+/// What reading from a connection turned up.
+pub(crate) enum Incoming {
+    /// A frame, or the error to tell the client about it
+    Message(Result<ClientMessage, ProtocolError>),
+    /// The connection has been silent long enough to be sent a `Ping` with this nonce
+    Ping(u64),
+    /// The connection has been silent too long and has to be closed
+    Reap(ReapReason),
+}
 
-        // Write length prefix
-        let length = response_buf.len() as u32;
-        self.stream.write_all(&length.to_be_bytes())?;
+/// A server heartbeat
+pub(crate) fn ping(nonce: u64) -> ServerMessage {
+    ServerMessage {
+        request_id: 0,
+        message: Some(server_message::Message::Ping(Ping { nonce })),
+    }
+}
 
-        // Write message
-        self.stream.write_all(&response_buf)?;
-        self.stream.flush()?;
+/// The answer to a client heartbeat
+pub(crate) fn pong(request_id: u64, nonce: u64) -> ServerMessage {
+    ServerMessage {
+        request_id,
+        message: Some(server_message::Message::Pong(Pong { nonce })),
+    }
+}
 
-        Ok(())
+/// Logs and counts a connection closed for going silent
+pub(crate) fn reap(id: usize, metrics: &ServerMetrics, reason: ReapReason) {
+    warn!("Client {}: closing connection, {}", id, reason);
+    metrics.connection_reaped(reason);
+}
+
+/// The writing half of a connection, shared by its in-flight requests.
+///
+/// Replies are written by a thread of the connection's own, so the pool's workers hand them
+/// over and move on instead of waiting on a client that is slow to read. Each queued reply
+/// holds a slot of the connection's [`InFlightLimit`] until it is written, which caps how
+/// many pile up.
+struct ReplyWriter {
+    outbox: mpsc::Sender<Reply>,
+    in_flight: Arc<InFlightLimit>,
+}
+
+/// A reply waiting for the writer thread, and whether the connection may stay open after it.
+struct Reply {
+    response: ServerMessage,
+    keep_alive: bool,
+    _slot: InFlightSlot,
+}
+
+impl ReplyWriter {
+    /// Starts the thread writing the replies of client `id` to `stream`
+    fn spawn(
+        id: usize,
+        mut stream: Transport,
+        format: FrameFormat,
+        activity: Arc<ConnectionActivity>,
+        in_flight: Arc<InFlightLimit>,
+    ) -> (Self, JoinHandle<()>) {
+        let (outbox, replies) = mpsc::channel::<Reply>();
+        // Runs until every sender is gone, so in-flight requests are still answered after
+        // the reader stops, or until a reply closes the connection
+        let thread = thread::spawn(move || {
+            for reply in replies {
+                let written = write_reply(&mut stream, &format, &activity, &reply.response);
+                if let Err(e) = &written {
+                    warn!("Client {}: failed to send response: {}", id, e);
+                }
+                if written.is_err() || !reply.keep_alive {
+                    // Wakes the reader, which then stops taking requests
+                    let _ = stream.shutdown(Shutdown::Both);
+                    return;
+                }
+            }
+        });
+        (ReplyWriter { outbox, in_flight }, thread)
     }
-    // Handle AddRequest and respond with AddResponse
-    pub fn handle_add_request(&self, add_request: AddRequest) -> AddResponse {
-        // Calculate the sum of a and b from the AddRequest
-        let result = add_request.a + add_request.b;
-        // Create and return the AddResponse
-        AddResponse { result }
+
+    /// Queues `response`, waiting while the connection has `max_in_flight` requests and
+    /// replies outstanding already. A reply that isn't `keep_alive` closes the connection.
+    fn send(&self, response: ServerMessage, keep_alive: bool) -> io::Result<()> {
+        self.reply(
+            response,
+            keep_alive,
+            InFlightLimit::acquire(&self.in_flight),
+        )
     }
+
+    /// Queues `response` to the request holding `slot`, which is given back once it is written
+    fn reply(
+        &self,
+        response: ServerMessage,
+        keep_alive: bool,
+        slot: InFlightSlot,
+    ) -> io::Result<()> {
+        let reply = Reply {
+            response,
+            keep_alive,
+            _slot: slot,
+        };
+        self.outbox.send(reply).map_err(|_| {
+            io::Error::new(ErrorKind::BrokenPipe, "the connection's writer has stopped")
+        })
+    }
+}
+
+/// Writes `response` as one frame in `format`, counting it in `activity`
+fn write_reply(
+    stream: &mut Transport,
+    format: &FrameFormat,
+    activity: &ConnectionActivity,
+    response: &ServerMessage,
+) -> io::Result<()> {
+    let frame = codec::encode_frame_with(response, format);
+    stream.write_all(&frame)?;
+    stream.flush()?;
+    activity.message_sent(frame.len());
+    Ok(())
+}
+
+/// Counting semaphore capping the requests a connection processes at once, along with the
+/// replies it has waiting to be written
+struct InFlightLimit {
+    limit: usize,
+    count: Mutex<usize>,
+    released: Condvar,
+}
+
+impl InFlightLimit {
+    fn new(limit: usize) -> Self {
+        InFlightLimit {
+            limit: limit.max(1),
+            count: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Blocks until the connection has room for one more request, which holds the returned
+    /// slot until it drops it
+    fn acquire(limit: &Arc<Self>) -> InFlightSlot {
+        let mut count = limit.count.lock().unwrap();
+        while *count >= limit.limit {
+            count = limit.released.wait(count).unwrap();
+        }
+        *count += 1;
+        InFlightSlot(limit.clone())
+    }
+
+    fn release(&self) {
+        *self.count.lock().unwrap() -= 1;
+        self.released.notify_all();
+    }
+}
+
+/// A request's place under a connection's [`InFlightLimit`], given back when dropped.
+struct InFlightSlot(Arc<InFlightLimit>);
+
+impl Drop for InFlightSlot {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+/// Computes an `AddRequest`, falling back to `default_mode` if it doesn't pick an overflow mode
+fn add(add_request: AddRequest, default_mode: OverflowMode) -> Result<AddResponse, ProtocolError> {
+    let mode = arithmetic::resolve_overflow_mode(add_request.overflow_mode, default_mode)?;
+    let result = arithmetic::add(add_request.a, add_request.b, mode)?;
+    Ok(AddResponse { result })
+}
+
+/// 64-bit counterpart of [`add`]
+fn add_int64(
+    add_request: AddInt64Request,
+    default_mode: OverflowMode,
+) -> Result<AddInt64Response, ProtocolError> {
+    let mode = arithmetic::resolve_overflow_mode(add_request.overflow_mode, default_mode)?;
+    let result = arithmetic::add(add_request.a, add_request.b, mode)?;
+    Ok(AddInt64Response { result })
+}
+
+/// Computes an `ArithmeticRequest`, falling back to `default_mode` if it doesn't pick an
+/// overflow mode
+fn arithmetic(
+    request: ArithmeticRequest,
+    default_mode: OverflowMode,
+) -> Result<ArithmeticResponse, ProtocolError> {
+    let mode = arithmetic::resolve_overflow_mode(request.overflow_mode, default_mode)?;
+    let operator = ArithmeticOperator::try_from(request.operator).map_err(|_| {
+        ProtocolError::new(
+            ErrorCode::InvalidArgument,
+            format!("Unknown arithmetic operator {}", request.operator),
+        )
+    })?;
+    let result = arithmetic::evaluate(operator, request.a, request.b, mode)?;
+    Ok(ArithmeticResponse { result })
 }
@@ -0,0 +1,251 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    config::ServerConfig,
+    error::ProtocolError,
+    memory::MemoryBudget,
+    message::{
+        client_message, server_message, ClientMessage, ErrorCode, ServerMessage, StreamChunk,
+        StreamEnd, StreamStart,
+    },
+};
+
+/// Progress of a stream that has been started but not ended
+#[derive(Debug)]
+struct OpenStream {
+    received: u64,
+    total_length: Option<u64>,
+    /// What has arrived so far, when streams are reassembled
+    payload: Option<Payload>,
+}
+
+/// The payload of a stream being reassembled, charged to the server's memory budget until
+/// it is dropped.
+#[derive(Debug)]
+pub(crate) struct Payload {
+    data: Vec<u8>,
+    memory: Arc<MemoryBudget>,
+}
+
+impl Payload {
+    fn new(memory: Arc<MemoryBudget>) -> Self {
+        Payload {
+            data: Vec::new(),
+            memory,
+        }
+    }
+
+    pub(crate) fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Appends `data` if the budget has room for it, returning whether it did
+    fn extend(&mut self, data: &[u8]) -> bool {
+        if !self.memory.try_charge(data.len()) {
+            return false;
+        }
+        self.data.extend_from_slice(data);
+        true
+    }
+}
+
+impl Drop for Payload {
+    fn drop(&mut self) {
+        self.memory.refund(self.data.len());
+    }
+}
+
+/// The streams open on one connection, checked against the server's limits.
+///
+/// By default only progress is tracked, never payload, so a service can pass chunks on as
+/// they arrive. A reassembling tracker also buffers each stream until it ends, within
+/// `max_stream_length` and the server's memory budget. A stream that breaks the protocol is
+/// dropped; the client has to start it over.
+#[derive(Debug)]
+pub(crate) struct StreamTracker {
+    open: HashMap<u64, OpenStream>,
+    max_open_streams: usize,
+    max_stream_length: u64,
+    /// Budget reassembled payloads are charged to, `None` when streams aren't reassembled
+    memory: Option<Arc<MemoryBudget>>,
+}
+
+impl StreamTracker {
+    pub(crate) fn new(config: &ServerConfig) -> Self {
+        StreamTracker {
+            open: HashMap::new(),
+            max_open_streams: config.max_open_streams,
+            max_stream_length: config.max_stream_length,
+            memory: None,
+        }
+    }
+
+    /// A tracker buffering the payload of every stream, charging it to `memory`
+    pub(crate) fn reassembling(config: &ServerConfig, memory: Arc<MemoryBudget>) -> Self {
+        StreamTracker {
+            memory: Some(memory),
+            ..Self::new(config)
+        }
+    }
+
+    /// Returns whether `message` belongs to a stream and must be handled in order
+    pub(crate) fn is_stream_message(message: &client_message::Message) -> bool {
+        matches!(
+            message,
+            client_message::Message::StreamStart(_)
+                | client_message::Message::StreamChunk(_)
+                | client_message::Message::StreamEnd(_)
+        )
+    }
+
+    /// Checks a stream message against the streams open so far and records its progress.
+    /// Returns the whole payload of the stream a `StreamEnd` closes when reassembling. Other
+    /// messages pass untouched.
+    pub(crate) fn track(
+        &mut self,
+        message: &client_message::Message,
+    ) -> Result<Option<Payload>, ProtocolError> {
+        match message {
+            client_message::Message::StreamStart(start) => self.start(start).map(|_| None),
+            client_message::Message::StreamChunk(chunk) => self.chunk(chunk).map(|_| None),
+            client_message::Message::StreamEnd(end) => self.end(end),
+            _ => Ok(None),
+        }
+    }
+
+    /// The reply to a `StreamStart` or `StreamChunk` of a reassembled stream, which never
+    /// reaches a service. Chunks are acknowledged without their data.
+    pub(crate) fn acknowledge(&self, message: &ClientMessage) -> Option<ServerMessage> {
+        self.memory.as_ref()?;
+        let ack = match message.message.as_ref()? {
+            client_message::Message::StreamStart(start) => {
+                server_message::Message::StreamStart(*start)
+            }
+            client_message::Message::StreamChunk(chunk) => {
+                server_message::Message::StreamChunk(StreamChunk {
+                    stream_id: chunk.stream_id,
+                    offset: chunk.offset,
+                    data: Vec::new(),
+                })
+            }
+            _ => return None,
+        };
+        Some(ServerMessage {
+            request_id: message.request_id,
+            message: Some(ack),
+        })
+    }
+
+    fn start(&mut self, start: &StreamStart) -> Result<(), ProtocolError> {
+        if self.open.contains_key(&start.stream_id) {
+            return Err(ProtocolError::new(
+                ErrorCode::InvalidArgument,
+                format!("Stream {} is already open", start.stream_id),
+            ));
+        }
+        if self.open.len() >= self.max_open_streams {
+            return Err(ProtocolError::new(
+                ErrorCode::ResourceExhausted,
+                format!("No more than {} streams may be open", self.max_open_streams),
+            ));
+        }
+        if start.total_length > self.max_stream_length {
+            return Err(self.too_long(start.stream_id));
+        }
+
+        let total_length = (start.total_length > 0).then_some(start.total_length);
+        self.open.insert(
+            start.stream_id,
+            OpenStream {
+                received: 0,
+                total_length,
+                payload: self.memory.clone().map(Payload::new),
+            },
+        );
+        Ok(())
+    }
+
+    fn chunk(&mut self, chunk: &StreamChunk) -> Result<(), ProtocolError> {
+        let stream = self
+            .open
+            .get(&chunk.stream_id)
+            .ok_or_else(|| unknown(chunk.stream_id))?;
+        let (expected, total_length) = (stream.received, stream.total_length);
+        let received = expected + chunk.data.len() as u64;
+
+        let error = if chunk.offset != expected {
+            ProtocolError::new(
+                ErrorCode::InvalidArgument,
+                format!(
+                    "Stream {} chunk at offset {}, expected {}",
+                    chunk.stream_id, chunk.offset, expected
+                ),
+            )
+        } else if received > self.max_stream_length {
+            self.too_long(chunk.stream_id)
+        } else if total_length.is_some_and(|total_length| received > total_length) {
+            ProtocolError::new(
+                ErrorCode::InvalidArgument,
+                format!(
+                    "Stream {} runs past its total length of {} bytes",
+                    chunk.stream_id,
+                    total_length.unwrap_or_default()
+                ),
+            )
+        } else {
+            let stream = self
+                .open
+                .get_mut(&chunk.stream_id)
+                .ok_or_else(|| unknown(chunk.stream_id))?;
+            let buffered = stream
+                .payload
+                .as_mut()
+                .is_none_or(|payload| payload.extend(&chunk.data));
+            if buffered {
+                stream.received = received;
+                return Ok(());
+            }
+            ProtocolError::new(
+                ErrorCode::ResourceExhausted,
+                "Server is out of memory for stream payloads",
+            )
+        };
+
+        self.open.remove(&chunk.stream_id);
+        Err(error)
+    }
+
+    fn end(&mut self, end: &StreamEnd) -> Result<Option<Payload>, ProtocolError> {
+        let stream = self
+            .open
+            .remove(&end.stream_id)
+            .ok_or_else(|| unknown(end.stream_id))?;
+        match stream.total_length {
+            Some(total_length) if total_length != stream.received => Err(ProtocolError::new(
+                ErrorCode::InvalidArgument,
+                format!(
+                    "Stream {} ended after {} of its {} bytes",
+                    end.stream_id, stream.received, total_length
+                ),
+            )),
+            _ => Ok(stream.payload),
+        }
+    }
+
+    fn too_long(&self, stream_id: u64) -> ProtocolError {
+        ProtocolError::new(
+            ErrorCode::ResourceExhausted,
+            format!(
+                "Stream {} is longer than the {} byte limit",
+                stream_id, self.max_stream_length
+            ),
+        )
+    }
+}
+
+fn unknown(stream_id: u64) -> ProtocolError {
+    ProtocolError::new(
+        ErrorCode::InvalidArgument,
+        format!("Stream {} is not open", stream_id),
+    )
+}
@@ -0,0 +1,104 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore,
+};
+
+/// Certificate, key and optional client CA of a TLS listener, all PEM files.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// Server certificate chain, leaf first
+    pub cert_path: PathBuf,
+    /// Private key of the leaf certificate, PKCS#8, PKCS#1 or SEC1
+    pub key_path: PathBuf,
+    /// CA certificates client certificates must chain to. `None` accepts clients without a
+    /// certificate; otherwise every client has to present one (mTLS).
+    pub client_ca_path: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    /// Serves TLS with the certificate chain and key in the given PEM files
+    pub fn new(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        TlsConfig {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+            client_ca_path: None,
+        }
+    }
+
+    /// Requires every client to present a certificate issued by a CA in `ca_path`
+    pub fn with_client_auth(mut self, ca_path: impl Into<PathBuf>) -> Self {
+        self.client_ca_path = Some(ca_path.into());
+        self
+    }
+
+    /// Reads the PEM files and builds the rustls configuration every connection shares
+    pub fn load(&self) -> io::Result<Arc<rustls::ServerConfig>> {
+        let provider = Arc::new(ring::default_provider());
+        let certs = load_certs(&self.cert_path)?;
+        let key = load_key(&self.key_path)?;
+
+        let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_input)?;
+        let builder = match &self.client_ca_path {
+            Some(ca_path) => builder.with_client_cert_verifier(client_verifier(ca_path, provider)?),
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder
+            .with_single_cert(certs, key)
+            .map_err(invalid_input)?;
+        Ok(Arc::new(config))
+    }
+}
+
+/// Reads every certificate in the PEM file at `path`
+pub fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path).map_err(|e| in_file(path, e))?);
+    let certs = rustls_pemfile::certs(&mut reader)
+        .collect::<io::Result<Vec<_>>>()
+        .map_err(|e| in_file(path, e))?;
+    if certs.is_empty() {
+        return Err(in_file(path, invalid_input("no certificate found")));
+    }
+    Ok(certs)
+}
+
+/// Reads the first private key in the PEM file at `path`
+pub fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path).map_err(|e| in_file(path, e))?);
+    rustls_pemfile::private_key(&mut reader)
+        .map_err(|e| in_file(path, e))?
+        .ok_or_else(|| in_file(path, invalid_input("no private key found")))
+}
+
+fn client_verifier(
+    ca_path: &Path,
+    provider: Arc<CryptoProvider>,
+) -> io::Result<Arc<dyn rustls::server::danger::ClientCertVerifier>> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca_path)? {
+        roots
+            .add(cert)
+            .map_err(|e| in_file(ca_path, invalid_input(e)))?;
+    }
+    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+        .build()
+        .map_err(invalid_input)
+}
+
+fn invalid_input(error: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error.to_string())
+}
+
+fn in_file(path: &Path, error: io::Error) -> io::Error {
+    io::Error::new(error.kind(), format!("{}: {}", path.display(), error))
+}
@@ -0,0 +1,300 @@
+use std::{
+    io::{self, Read, Write},
+    mem,
+    net::{Shutdown, SocketAddr, TcpStream},
+    os::unix::net::UnixStream,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rustls::Connection;
+
+use crate::unix::PeerCredentials;
+
+/// Size of the buffer ciphertext is read into
+const READ_CHUNK: usize = 16 * 1024;
+
+/// The byte stream a connection is served over: plain TCP, TLS on top of it, or a Unix
+/// domain socket.
+///
+/// Each can be cloned, so one thread can read while others write replies, and the socket
+/// options a handler relies on apply to the underlying socket.
+#[derive(Debug)]
+pub enum Transport {
+    Tcp(TcpStream),
+    Tls(TlsStream),
+    Unix(UnixStream),
+}
+
+impl Transport {
+    /// Another handle on the same connection
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Transport::Tcp(stream) => stream.try_clone().map(Transport::Tcp),
+            Transport::Tls(stream) => stream.try_clone().map(Transport::Tls),
+            Transport::Unix(stream) => stream.try_clone().map(Transport::Unix),
+        }
+    }
+
+    /// Sets how long a read waits for data before failing with `WouldBlock`
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Transport::Tcp(stream) => stream.set_read_timeout(timeout),
+            Transport::Tls(stream) => stream.socket.set_read_timeout(timeout),
+            Transport::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    /// Address of the peer, `None` for a Unix domain socket
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Transport::Tcp(stream) => stream.peer_addr().ok(),
+            Transport::Tls(stream) => stream.socket.peer_addr().ok(),
+            Transport::Unix(_) => None,
+        }
+    }
+
+    /// Credentials of the process on the other end of a Unix domain socket. `None` over
+    /// TCP, or if the kernel wouldn't say.
+    pub fn peer_credentials(&self) -> Option<PeerCredentials> {
+        match self {
+            Transport::Unix(stream) => PeerCredentials::of(stream).ok(),
+            Transport::Tcp(_) | Transport::Tls(_) => None,
+        }
+    }
+
+    /// Shuts down the socket. Closing the write half of a TLS stream sends the peer a
+    /// `close_notify` first.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Transport::Tcp(stream) => stream.shutdown(how),
+            Transport::Tls(stream) => {
+                if matches!(how, Shutdown::Write | Shutdown::Both) {
+                    stream.close_notify();
+                }
+                stream.socket.shutdown(how)
+            }
+            Transport::Unix(stream) => stream.shutdown(how),
+        }
+    }
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Transport::Tcp(stream) => stream.read(buf),
+            Transport::Tls(stream) => stream.read(buf),
+            Transport::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Transport::Tcp(stream) => stream.write(buf),
+            Transport::Tls(stream) => stream.write(buf),
+            Transport::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Transport::Tcp(stream) => stream.flush(),
+            Transport::Tls(stream) => stream.flush(),
+            Transport::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl From<TcpStream> for Transport {
+    fn from(stream: TcpStream) -> Self {
+        Transport::Tcp(stream)
+    }
+}
+
+impl From<UnixStream> for Transport {
+    fn from(stream: UnixStream) -> Self {
+        Transport::Unix(stream)
+    }
+}
+
+impl From<TlsStream> for Transport {
+    fn from(stream: TlsStream) -> Self {
+        Transport::Tls(stream)
+    }
+}
+
+/// A rustls session over a blocking TCP stream, shared by every clone.
+///
+/// The session is only locked to move bytes in or out of it, never while waiting on the
+/// socket, so a reader blocked on a silent peer doesn't hold up writers, and a writer blocked
+/// on a peer that isn't reading doesn't keep the reader from decrypting. The handshake runs
+/// as part of the first reads and writes.
+#[derive(Debug)]
+pub struct TlsStream {
+    socket: TcpStream,
+    session: Arc<Mutex<Session>>,
+    /// Held while ciphertext is written to the socket, so records go out in the order the
+    /// session produced them
+    sending: Arc<Mutex<()>>,
+}
+
+#[derive(Debug)]
+struct Session {
+    connection: Connection,
+    /// Ciphertext read from the socket that rustls hasn't taken yet
+    incoming: Vec<u8>,
+    /// Ciphertext rustls has produced that hasn't been written to the socket yet
+    outgoing: Vec<u8>,
+}
+
+impl TlsStream {
+    /// Runs `connection`, a client or server session, over `socket`
+    pub fn new(socket: TcpStream, connection: impl Into<Connection>) -> Self {
+        TlsStream {
+            socket,
+            session: Arc::new(Mutex::new(Session {
+                connection: connection.into(),
+                incoming: Vec::new(),
+                outgoing: Vec::new(),
+            })),
+            sending: Arc::default(),
+        }
+    }
+
+    /// Another handle on the same session
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(TlsStream {
+            socket: self.socket.try_clone()?,
+            session: self.session.clone(),
+            sending: self.sending.clone(),
+        })
+    }
+
+    /// The leaf certificate the peer presented, DER-encoded. `None` before the handshake
+    /// or when the peer sent none.
+    pub fn peer_certificate(&self) -> Option<Vec<u8>> {
+        let session = self.session.lock().unwrap();
+        session
+            .connection
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .map(|cert| cert.to_vec())
+    }
+
+    /// Sends a `close_notify`, unless a writer stuck on the socket is in the way
+    fn close_notify(&self) {
+        self.session.lock().unwrap().connection.send_close_notify();
+        let _ = self.send_records(false);
+    }
+
+    /// Writes the records the session has queued to the socket, in order. When `wait` is
+    /// unset and someone else is writing already, leaves the records to them instead.
+    fn send_records(&self, wait: bool) -> io::Result<()> {
+        let sending = if wait {
+            self.sending.lock().unwrap()
+        } else {
+            match self.sending.try_lock() {
+                Ok(sending) => sending,
+                Err(_) => return Ok(()),
+            }
+        };
+        loop {
+            let records = {
+                let mut session = self.session.lock().unwrap();
+                session.queue_records()?;
+                if session.outgoing.is_empty() {
+                    // Let go with the session still locked, so records queued after this
+                    // check either find `sending` free or its holder still looping
+                    drop(sending);
+                    return Ok(());
+                }
+                mem::take(&mut session.outgoing)
+            };
+            (&self.socket).write_all(&records)?;
+        }
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut chunk = [0u8; READ_CHUNK];
+        loop {
+            let processed = {
+                let mut session = self.session.lock().unwrap();
+                match session.connection.reader().read(buf) {
+                    Ok(read) => return Ok(read),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(e),
+                }
+                if session.incoming.is_empty() {
+                    None
+                } else {
+                    Some(session.process_incoming())
+                }
+            };
+            // Whatever the session has to say, alerts included, say it before waiting on
+            // the peer
+            self.send_records(false)?;
+            if let Some(processed) = processed {
+                processed?;
+                continue;
+            }
+
+            let read = (&self.socket).read(&mut chunk)?;
+            let mut session = self.session.lock().unwrap();
+            if read == 0 {
+                // From here on `reader` tells a peer that sent `close_notify` from a stream
+                // cut short, which it reports as `UnexpectedEof`
+                session.connection.read_tls(&mut io::empty())?;
+                continue;
+            }
+            session.incoming.extend_from_slice(&chunk[..read]);
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self
+            .session
+            .lock()
+            .unwrap()
+            .connection
+            .writer()
+            .write(buf)?;
+        self.send_records(true)?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.session.lock().unwrap().connection.writer().flush()?;
+        self.send_records(true)?;
+        (&self.socket).flush()
+    }
+}
+
+impl Session {
+    /// Hands buffered ciphertext to rustls, as much as it takes, and queues whatever it
+    /// answers with. A protocol error is queued for the peer as an alert, then returned.
+    fn process_incoming(&mut self) -> io::Result<()> {
+        let mut incoming = &self.incoming[..];
+        let taken = self.connection.read_tls(&mut incoming)?;
+        self.incoming.drain(..taken);
+
+        let processed = self.connection.process_new_packets();
+        self.queue_records()?;
+        processed
+            .map(|_| ())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Moves every TLS record the session has produced to `outgoing`, encrypted
+    fn queue_records(&mut self) -> io::Result<()> {
+        while self.connection.wants_write() {
+            self.connection.write_tls(&mut self.outgoing)?;
+        }
+        Ok(())
+    }
+}
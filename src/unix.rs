@@ -0,0 +1,200 @@
+use std::{
+    fs, io,
+    os::unix::{
+        fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+        io::AsRawFd,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Where a Unix domain socket listener binds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnixAddr {
+    /// A socket file. A stale one left behind by an earlier run is replaced, nothing else
+    /// at the path is.
+    Path(PathBuf),
+    /// A name in Linux's abstract namespace, without the leading NUL byte. It has no file,
+    /// so file permissions don't apply, and it disappears with the listener.
+    Abstract(Vec<u8>),
+}
+
+impl UnixAddr {
+    /// Parses `@name` as an abstract name and anything else as a path, the convention of
+    /// `ss` and socat
+    pub fn parse(addr: &str) -> Self {
+        match addr.strip_prefix('@') {
+            Some(name) => UnixAddr::Abstract(name.as_bytes().to_vec()),
+            None => UnixAddr::Path(PathBuf::from(addr)),
+        }
+    }
+
+    /// Binds a listener, setting the socket file's permission bits to `mode` if given.
+    /// Fails with `AddrInUse` if the path holds anything but a stale socket, such as one
+    /// another listener still answers on, or a file that isn't a socket.
+    pub(crate) fn bind(&self, mode: Option<u32>) -> io::Result<UnixListener> {
+        match self {
+            UnixAddr::Path(path) => {
+                match fs::symlink_metadata(path) {
+                    Ok(metadata)
+                        if metadata.file_type().is_socket()
+                            && UnixStream::connect(path).is_err() =>
+                    {
+                        fs::remove_file(path)?
+                    }
+                    Ok(_) => return Err(in_use(path)),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+                match mode {
+                    Some(mode) => bind_with_mode(path, mode),
+                    None => UnixListener::bind(path),
+                }
+            }
+            UnixAddr::Abstract(name) => bind_abstract(name),
+        }
+    }
+
+    /// Opens a connection to a listener bound to this address
+    pub fn connect(&self) -> io::Result<UnixStream> {
+        match self {
+            UnixAddr::Path(path) => UnixStream::connect(path),
+            UnixAddr::Abstract(name) => connect_abstract(name),
+        }
+    }
+
+    /// Removes the socket file, if there is one
+    pub(crate) fn remove(&self) {
+        if let UnixAddr::Path(path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Binds the socket in a private directory next to `path`, where nobody else can reach it,
+/// then links it into place once it has its permission bits. The umask is process-wide, so
+/// narrowing it around the bind would race with other threads creating files. Linking
+/// never replaces what may have appeared at `path` since it was checked.
+fn bind_with_mode(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    static STAGING: AtomicUsize = AtomicUsize::new(0);
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let staging = parent.join(format!(
+        ".unix-{}-{}",
+        process::id(),
+        STAGING.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join("s");
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(mode))?;
+        fs::hard_link(&staged, path).map_err(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists => in_use(path),
+            _ => e,
+        })?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&staged);
+    let _ = fs::remove_dir(&staging);
+    bound
+}
+
+fn in_use(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::AddrInUse,
+        format!("{} is in use by a listener or another file", path.display()),
+    )
+}
+
+#[cfg(target_os = "linux")]
+fn bind_abstract(name: &[u8]) -> io::Result<UnixListener> {
+    use std::os::linux::net::SocketAddrExt;
+    UnixListener::bind_addr(&std::os::unix::net::SocketAddr::from_abstract_name(name)?)
+}
+
+#[cfg(target_os = "linux")]
+fn connect_abstract(name: &[u8]) -> io::Result<UnixStream> {
+    use std::os::linux::net::SocketAddrExt;
+    UnixStream::connect_addr(&std::os::unix::net::SocketAddr::from_abstract_name(name)?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_abstract(_: &[u8]) -> io::Result<UnixListener> {
+    Err(abstract_unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn connect_abstract(_: &[u8]) -> io::Result<UnixStream> {
+    Err(abstract_unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn abstract_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "abstract Unix socket names are Linux-only",
+    )
+}
+
+/// Who is on the other end of a Unix domain socket, as reported by the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    /// Process ID of the peer when it connected. Only Linux reports it.
+    pub pid: Option<i32>,
+}
+
+impl PeerCredentials {
+    /// Asks the kernel for the credentials of `stream`'s peer (`SO_PEERCRED` on Linux)
+    pub fn of(stream: &UnixStream) -> io::Result<Self> {
+        peer_credentials(stream.as_raw_fd())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn peer_credentials(fd: libc::c_int) -> io::Result<PeerCredentials> {
+    let mut credentials = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut length = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: `credentials` and `length` are valid for writes and `length` holds the size
+    // of `credentials`, as getsockopt requires
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut credentials as *mut libc::ucred).cast(),
+            &mut length,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(PeerCredentials {
+        uid: credentials.uid,
+        gid: credentials.gid,
+        pid: Some(credentials.pid),
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peer_credentials(fd: libc::c_int) -> io::Result<PeerCredentials> {
+    let (mut uid, mut gid) = (0, 0);
+    // SAFETY: `uid` and `gid` are valid for writes
+    if unsafe { libc::getpeereid(fd, &mut uid, &mut gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(PeerCredentials {
+        uid,
+        gid,
+        pid: None,
+    })
+}
@@ -0,0 +1,143 @@
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use log::warn;
+
+use crate::metrics::ServerMetrics;
+
+/// Work for a [`WorkerPool`] that runs whatever it is given.
+pub(crate) type Task = Box<dyn FnOnce() + Send>;
+
+/// Fixed-size pool of worker threads fed by a bounded queue.
+pub(crate) struct WorkerPool<T: Send + 'static> {
+    sender: SyncSender<T>,
+    workers: Vec<JoinHandle<()>>,
+    /// Counters of the pool serving connections. Other pools aren't counted.
+    metrics: Option<Arc<ServerMetrics>>,
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+    /// Spawns `size` workers, each running `job` for every item taken off the queue
+    pub(crate) fn new<F>(
+        size: usize,
+        queue_depth: usize,
+        metrics: Option<Arc<ServerMetrics>>,
+        job: F,
+    ) -> Self
+    where
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        let size = size.max(1);
+        let (sender, receiver) = mpsc::sync_channel(queue_depth);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let job = Arc::new(job);
+        if let Some(metrics) = &metrics {
+            metrics.set_pool_shape(size, queue_depth);
+        }
+
+        let workers = (0..size)
+            .map(|_| {
+                let receiver = receiver.clone();
+                let metrics = metrics.clone();
+                let job = job.clone();
+                thread::spawn(move || worker_loop(receiver, metrics, job))
+            })
+            .collect();
+
+        WorkerPool {
+            sender,
+            workers,
+            metrics,
+        }
+    }
+
+    /// Returns `true` when every worker is busy or already spoken for by a queued item.
+    /// Only a pool with metrics can tell.
+    pub(crate) fn is_saturated(&self) -> bool {
+        self.metrics.as_ref().is_some_and(|metrics| {
+            metrics.busy_workers() + metrics.queue_depth() >= self.workers.len()
+        })
+    }
+
+    /// Queues `item` without blocking, handing it back if the queue is full
+    pub(crate) fn try_submit(&self, item: T) -> Result<(), T> {
+        self.count(ServerMetrics::enqueued);
+        match self.sender.try_send(item) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(item)) | Err(TrySendError::Disconnected(item)) => {
+                self.count(ServerMetrics::dequeued);
+                Err(item)
+            }
+        }
+    }
+
+    /// Queues `item`, blocking until there is room in the queue
+    pub(crate) fn submit(&self, item: T) -> Result<(), T> {
+        self.count(ServerMetrics::enqueued);
+        self.sender.send(item).map_err(|e| {
+            self.count(ServerMetrics::dequeued);
+            e.0
+        })
+    }
+
+    fn count(&self, event: fn(&ServerMetrics)) {
+        if let Some(metrics) = &self.metrics {
+            event(metrics);
+        }
+    }
+
+    /// Closes the queue and waits for every worker to finish what it is running
+    pub(crate) fn join(self) {
+        let WorkerPool {
+            sender, workers, ..
+        } = self;
+        drop(sender);
+        for worker in workers {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn worker_loop<T, F>(
+    receiver: Arc<Mutex<Receiver<T>>>,
+    metrics: Option<Arc<ServerMetrics>>,
+    job: Arc<F>,
+) where
+    F: Fn(T),
+{
+    loop {
+        // Only hold the lock while waiting for the next item, not while running it
+        let item = match receiver.lock().unwrap().recv() {
+            Ok(item) => item,
+            Err(_) => break, // Pool dropped, no more work will arrive
+        };
+        let _busy = metrics.as_deref().map(Busy::new);
+        // A panicking job loses its own item, not the worker
+        if panic::catch_unwind(AssertUnwindSafe(|| job(item))).is_err() {
+            warn!("A worker's job panicked, the worker carries on");
+        }
+    }
+}
+
+/// Counts a worker as busy for as long as it is alive, whether its job returns or panics.
+struct Busy<'a>(&'a ServerMetrics);
+
+impl<'a> Busy<'a> {
+    fn new(metrics: &'a ServerMetrics) -> Self {
+        metrics.dequeued();
+        metrics.worker_started();
+        Busy(metrics)
+    }
+}
+
+impl Drop for Busy<'_> {
+    fn drop(&mut self) {
+        self.0.worker_finished();
+    }
+}
@@ -0,0 +1,877 @@
+use embedded_recruitment_task::{
+    access::AccessList,
+    async_server::AsyncServer,
+    auth::AuthConfig,
+    codec,
+    config::{ConnectionCapPolicy, ServerConfig},
+    heartbeat::ReapReason,
+    message::{
+        auth_request, client_message, server_message, AddRequest, ArithmeticOperator,
+        ArithmeticRequest, AuthRequest, ClientMessage, Compression, EchoMessage, ErrorCode, Ping,
+        ServerMessage,
+    },
+    policy::{Policy, Role},
+    rate_limit::{Budget, RateLimit, RateLimitConfig, RateLimitScope},
+    router::{MessageKind, Router},
+};
+use std::{
+    collections::HashMap,
+    io::Write,
+    net::TcpStream,
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use tokio::runtime::Runtime;
+
+mod test_client;
+
+fn create_runtime() -> Arc<Runtime> {
+    Arc::new(Runtime::new().expect("Failed to build tokio runtime"))
+}
+
+fn create_server(runtime: &Runtime) -> Arc<AsyncServer> {
+    Arc::new(
+        runtime
+            .block_on(AsyncServer::new("localhost:0", Router::default()))
+            .expect("Failed to start server"),
+    )
+}
+
+fn server_port(server: &AsyncServer) -> u16 {
+    server
+        .local_addr()
+        .expect("Failed to read server address")
+        .port()
+}
+
+fn setup_server_thread(runtime: Arc<Runtime>, server: Arc<AsyncServer>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        runtime
+            .block_on(server.run())
+            .expect("Server encountered an error");
+    })
+}
+
+#[test]
+fn test_async_client_echo_message() {
+    let runtime = create_runtime();
+    let server = create_server(&runtime);
+    let port = server_port(&server);
+    let handle = setup_server_thread(runtime.clone(), server.clone());
+
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    let echo_message = EchoMessage {
+        content: "Hello, World!".to_string(),
+    };
+    let message = client_message::Message::EchoMessage(echo_message.clone());
+    assert!(client.send(message).is_ok(), "Failed to send message");
+
+    let response = client.receive();
+    assert!(
+        response.is_ok(),
+        "Failed to receive response for EchoMessage"
+    );
+    match response.unwrap().message {
+        Some(server_message::Message::EchoMessage(echo)) => {
+            assert_eq!(
+                echo.content, echo_message.content,
+                "Echoed message content does not match"
+            );
+        }
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_async_client_add_request() {
+    let runtime = create_runtime();
+    let server = create_server(&runtime);
+    let port = server_port(&server);
+    let handle = setup_server_thread(runtime.clone(), server.clone());
+
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    let add_request = AddRequest {
+        a: 10,
+        b: 20,
+        ..Default::default()
+    };
+    let message = client_message::Message::AddRequest(add_request);
+    assert!(client.send(message).is_ok(), "Failed to send message");
+
+    let response = client.receive();
+    assert!(
+        response.is_ok(),
+        "Failed to receive response for AddRequest"
+    );
+    match response.unwrap().message {
+        Some(server_message::Message::AddResponse(add_response)) => {
+            assert_eq!(add_response.result, 30, "AddResponse result does not match");
+        }
+        _ => panic!("Expected AddResponse, but received a different message"),
+    }
+
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_async_many_idle_clients() {
+    let runtime = create_runtime();
+    let server = create_server(&runtime);
+    let port = server_port(&server);
+    let handle = setup_server_thread(runtime.clone(), server.clone());
+
+    // Far more connections than a worker pool would hold, all sitting idle. The generous
+    // timeout rides out a SYN dropped while the accept queue is momentarily full.
+    let mut clients: Vec<_> = (0..200)
+        .map(|_| test_client::TestClient::new("localhost", port, 5000))
+        .collect();
+    for client in clients.iter_mut() {
+        client.connect().expect("Failed to connect to the server");
+    }
+
+    // Each of them is still served
+    for (i, client) in clients.iter_mut().enumerate() {
+        let message = client_message::Message::EchoMessage(EchoMessage {
+            content: format!("client {}", i),
+        });
+        assert!(client.send(message).is_ok(), "Failed to send message");
+        match client
+            .receive()
+            .expect("Failed to receive response")
+            .message
+        {
+            Some(server_message::Message::EchoMessage(echo)) => {
+                assert_eq!(echo.content, format!("client {}", i));
+            }
+            _ => panic!("Expected EchoMessage, but received a different message"),
+        }
+    }
+
+    for client in clients.iter_mut() {
+        assert!(client.disconnect().is_ok());
+    }
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_async_stop_drains_connected_clients() {
+    let runtime = create_runtime();
+    let server = create_server(&runtime);
+    let port = server_port(&server);
+    let run_server = server.clone();
+    let run_runtime = runtime.clone();
+    let handle = thread::spawn(move || {
+        run_runtime
+            .block_on(run_server.run())
+            .expect("Server encountered an error")
+    });
+
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "before stop".to_string(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    assert!(client.receive().is_ok(), "Failed to receive response");
+
+    server.stop();
+    let report = handle.join().expect("Server thread panicked");
+    assert_eq!(report.drained, 1);
+    assert_eq!(report.force_closed, 0);
+
+    // The server closed the connection under the client
+    assert!(client.receive().is_err(), "Connection should be closed");
+}
+
+#[test]
+fn test_async_stop_before_run() {
+    let runtime = create_runtime();
+    let server = create_server(&runtime);
+
+    // Stopping ahead of `run` must not leave the server serving forever
+    server.stop();
+    let handle = setup_server_thread(runtime.clone(), server.clone());
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_async_malformed_message_gets_error_reply() {
+    let runtime = create_runtime();
+    let server = create_server(&runtime);
+    let port = server_port(&server);
+    let handle = setup_server_thread(runtime.clone(), server.clone());
+
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    let garbage = [0xff, 0xff];
+    assert!(client.send_raw(garbage.len() as u32, &garbage).is_ok());
+    match client
+        .receive()
+        .expect("Failed to receive error reply")
+        .message
+    {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.code, ErrorCode::MalformedMessage as i32);
+        }
+        _ => panic!("Expected ErrorResponse, but received a different message"),
+    }
+
+    // The connection stays usable
+    let message = client_message::Message::AddRequest(AddRequest {
+        a: 1,
+        b: 2,
+        ..Default::default()
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    match client
+        .receive()
+        .expect("Failed to receive response")
+        .message
+    {
+        Some(server_message::Message::AddResponse(add_response)) => {
+            assert_eq!(add_response.result, 3);
+        }
+        _ => panic!("Expected AddResponse, but received a different message"),
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_async_pipelined_requests_with_ids() {
+    let runtime = create_runtime();
+    let server = create_server(&runtime);
+    let port = server_port(&server);
+    let handle = setup_server_thread(runtime.clone(), server.clone());
+
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    let count = 50u64;
+    for request_id in 1..=count {
+        let message = client_message::Message::ArithmeticRequest(ArithmeticRequest {
+            operator: ArithmeticOperator::Subtract as i32,
+            a: 0,
+            b: request_id as i64,
+            ..Default::default()
+        });
+        assert!(client.send_with_id(request_id, message).is_ok());
+    }
+
+    let mut results = HashMap::new();
+    for _ in 0..count {
+        let response = client.receive().expect("Failed to receive response");
+        match response.message {
+            Some(server_message::Message::ArithmeticResponse(arithmetic)) => {
+                results.insert(response.request_id, arithmetic.result);
+            }
+            other => panic!("Expected ArithmeticResponse, but received {:?}", other),
+        }
+    }
+    for request_id in 1..=count {
+        assert_eq!(results.get(&request_id), Some(&-(request_id as i64)));
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_async_frame_split_across_writes() {
+    let runtime = create_runtime();
+    let server = create_server(&runtime);
+    let port = server_port(&server);
+    let handle = setup_server_thread(runtime.clone(), server.clone());
+
+    let frame = codec::encode_frame(&ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "split".to_string(),
+        })),
+        ..Default::default()
+    });
+
+    let mut stream = TcpStream::connect(("localhost", port)).expect("Failed to connect");
+    test_client::handshake(&mut stream).expect("Handshake failed");
+    stream.set_nodelay(true).unwrap();
+    for part in [&frame[..2], &frame[2..7], &frame[7..]] {
+        stream.write_all(part).unwrap();
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    let mut client = test_client::TestClient::from_stream(stream);
+    match client
+        .receive()
+        .expect("Failed to receive response")
+        .message
+    {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "split"),
+        other => panic!("Expected EchoMessage, but received {:?}", other),
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_async_compression_negotiated() {
+    let runtime = create_runtime();
+    let server = create_server(&runtime);
+    let port = server_port(&server);
+    let handle = setup_server_thread(runtime.clone(), server.clone());
+
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(
+        client.hello(&[Compression::Lz4]).expect("Handshake failed"),
+        Compression::Lz4
+    );
+
+    // Tagged requests are answered by blocking tasks, their replies get compressed too
+    let content = "abc".repeat(10_000);
+    for request_id in 1..=3 {
+        let message = client_message::Message::EchoMessage(EchoMessage {
+            content: content.clone(),
+        });
+        assert!(client.send_with_id(request_id, message).is_ok());
+    }
+    for _ in 1..=3 {
+        match client
+            .receive()
+            .expect("Failed to receive response")
+            .message
+        {
+            Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, content),
+            other => panic!("Expected EchoMessage, but received {:?}", other),
+        }
+    }
+    let stats = client.compression_stats().expect("Compression not enabled");
+    assert_eq!(stats.compressed_frames(), 6);
+    assert_eq!(server.metrics().compression().compressed_frames(), 6);
+    assert!(server.metrics().compression().ratio() < 0.1);
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_async_idle_connection_reaped() {
+    let runtime = create_runtime();
+    let server = Arc::new(
+        runtime
+            .block_on(AsyncServer::with_config(
+                "localhost:0",
+                ServerConfig {
+                    heartbeat_interval: Some(Duration::from_millis(100)),
+                    idle_timeout: Some(Duration::from_millis(300)),
+                    ..Default::default()
+                },
+                Router::default(),
+            ))
+            .expect("Failed to start server"),
+    );
+    let port = server_port(&server);
+    let handle = setup_server_thread(runtime.clone(), server.clone());
+    let mut stream = TcpStream::connect(("localhost", port)).expect("Failed to connect");
+    test_client::handshake(&mut stream).expect("Handshake failed");
+
+    // The Ping goes unanswered, so the connection is closed
+    let message: ServerMessage =
+        codec::read_frame(&mut stream, codec::MAX_FRAME_LENGTH).expect("Failed to receive Ping");
+    assert!(matches!(
+        message.message,
+        Some(server_message::Message::Ping(_))
+    ));
+    assert!(codec::read_frame::<_, ServerMessage>(&mut stream, codec::MAX_FRAME_LENGTH).is_err());
+    assert_eq!(
+        server
+            .metrics()
+            .reaped_connections(ReapReason::Unresponsive),
+        1
+    );
+
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_async_tls_transport() {
+    let pki = test_client::TestPki::generate();
+    let runtime = create_runtime();
+    let server = Arc::new(
+        runtime
+            .block_on(AsyncServer::with_config(
+                "localhost:0",
+                ServerConfig {
+                    tls: Some(pki.server_config(true)),
+                    ..Default::default()
+                },
+                Router::default(),
+            ))
+            .expect("Failed to start server"),
+    );
+    let port = server_port(&server);
+    let handle = setup_server_thread(runtime.clone(), server.clone());
+
+    let mut client =
+        test_client::TestClient::new("localhost", port, 1000).with_tls(pki.client_config(true));
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "encrypted".to_string(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    match client
+        .receive()
+        .expect("Failed to receive response")
+        .message
+    {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "encrypted"),
+        other => panic!("Expected EchoMessage, but received {:?}", other),
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_async_authentication_required() {
+    let runtime = create_runtime();
+    let server = Arc::new(
+        runtime
+            .block_on(AsyncServer::with_config(
+                "localhost:0",
+                ServerConfig {
+                    auth: Some(AuthConfig::new().with_token("erin", "letmein")),
+                    ..Default::default()
+                },
+                Router::default(),
+            ))
+            .expect("Failed to start server"),
+    );
+    let port = server_port(&server);
+    let handle = setup_server_thread(runtime.clone(), server.clone());
+
+    let echo = client_message::Message::EchoMessage(EchoMessage {
+        content: "hello".to_string(),
+    });
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert!(client.send(echo.clone()).is_ok(), "Failed to send message");
+    match client
+        .receive()
+        .expect("Failed to receive response")
+        .message
+    {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.code, ErrorCode::Unauthenticated as i32)
+        }
+        other => panic!("Expected ErrorResponse, but received {:?}", other),
+    }
+
+    // Not even a Ping before authenticating
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let ping = client_message::Message::Ping(Ping { nonce: 7 });
+    assert!(client.send(ping).is_ok(), "Failed to send message");
+    match client
+        .receive()
+        .expect("Failed to receive response")
+        .message
+    {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.code, ErrorCode::Unauthenticated as i32)
+        }
+        other => panic!("Expected ErrorResponse, but received {:?}", other),
+    }
+    assert!(client.receive().is_err(), "Connection should be closed");
+
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let auth = client_message::Message::AuthRequest(AuthRequest {
+        method: Some(auth_request::Method::BearerToken("letmein".to_string())),
+    });
+    assert!(client.send(auth).is_ok(), "Failed to send message");
+    match client
+        .receive()
+        .expect("Failed to receive response")
+        .message
+    {
+        Some(server_message::Message::AuthResponse(response)) => {
+            assert_eq!(response.principal, "erin")
+        }
+        other => panic!("Expected AuthResponse, but received {:?}", other),
+    }
+    assert!(client.send(echo).is_ok(), "Failed to send message");
+    match client
+        .receive()
+        .expect("Failed to receive response")
+        .message
+    {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "hello"),
+        other => panic!("Expected EchoMessage, but received {:?}", other),
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_async_policy_denies_operations() {
+    let runtime = create_runtime();
+    let policy = Policy::new()
+        .with_role("public", Role::new([MessageKind::Echo]))
+        .with_default_roles(["public"]);
+    let server = Arc::new(
+        runtime
+            .block_on(AsyncServer::with_config(
+                "localhost:0",
+                ServerConfig {
+                    policy: Some(policy),
+                    ..Default::default()
+                },
+                Router::default(),
+            ))
+            .expect("Failed to start server"),
+    );
+    let port = server_port(&server);
+    let handle = setup_server_thread(runtime.clone(), server.clone());
+
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let add = client_message::Message::AddRequest(AddRequest {
+        a: 1,
+        b: 2,
+        ..Default::default()
+    });
+    assert!(client.send(add).is_ok(), "Failed to send message");
+    match client
+        .receive()
+        .expect("Failed to receive response")
+        .message
+    {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.code, ErrorCode::PermissionDenied as i32)
+        }
+        other => panic!("Expected ErrorResponse, but received {:?}", other),
+    }
+
+    // The connection stays open for what the policy allows
+    let echo = client_message::Message::EchoMessage(EchoMessage {
+        content: "hello".to_string(),
+    });
+    assert!(client.send(echo).is_ok(), "Failed to send message");
+    match client
+        .receive()
+        .expect("Failed to receive response")
+        .message
+    {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "hello"),
+        other => panic!("Expected EchoMessage, but received {:?}", other),
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+/// Sends an echo of `content` and returns the reply
+fn echo(client: &mut test_client::TestClient, content: &str) -> server_message::Message {
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: content.to_string(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    client
+        .receive()
+        .expect("Failed to receive response")
+        .message
+        .expect("Reply has no message")
+}
+
+#[test]
+fn test_async_rate_limits() {
+    let runtime = create_runtime();
+    let policy = Policy::new()
+        .with_role("public", Role::new([MessageKind::Echo]))
+        .with_role(
+            "bulk",
+            Role::new([MessageKind::Echo]).with_rate(Budget {
+                messages: Some(RateLimit::new(100, 10)),
+                bytes: None,
+            }),
+        )
+        .with_default_roles(["public"])
+        .with_grant("erin", ["bulk"]);
+    let server = Arc::new(
+        runtime
+            .block_on(AsyncServer::with_config(
+                "localhost:0",
+                ServerConfig {
+                    auth: Some(
+                        AuthConfig::new()
+                            .with_token("dana", "opensesame")
+                            .with_token("erin", "letmein"),
+                    ),
+                    policy: Some(policy),
+                    rate_limits: RateLimitConfig {
+                        per_connection: Budget {
+                            messages: Some(RateLimit::new(5, 3)),
+                            bytes: None,
+                        },
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+                Router::default(),
+            ))
+            .expect("Failed to start server"),
+    );
+    let port = server_port(&server);
+    let handle = setup_server_thread(runtime.clone(), server.clone());
+    let echoes = |client: &mut test_client::TestClient, count| {
+        (0..count)
+            .filter(|_| {
+                matches!(
+                    echo(client, "hello"),
+                    server_message::Message::EchoMessage(_)
+                )
+            })
+            .count()
+    };
+
+    let sign_in = |token: &str| {
+        let mut client = test_client::TestClient::new("localhost", port, 1000);
+        assert!(client.connect().is_ok(), "Failed to connect to the server");
+        let auth = client_message::Message::AuthRequest(AuthRequest {
+            method: Some(auth_request::Method::BearerToken(token.to_string())),
+        });
+        assert!(client.send(auth).is_ok(), "Failed to send message");
+        assert!(client.receive().is_ok(), "Failed to receive response");
+        client
+    };
+
+    // Principals without a rate override get the default burst, then have to wait
+    let mut plain = sign_in("opensesame");
+    assert_eq!(echoes(&mut plain, 3), 3);
+    match echo(&mut plain, "too many") {
+        server_message::Message::ErrorResponse(error) => {
+            assert_eq!(error.code, ErrorCode::RateLimited as i32);
+            assert!(error.retry_after_ms > 0);
+        }
+        other => panic!("Expected ErrorResponse, but received {:?}", other),
+    }
+    assert_eq!(
+        server
+            .metrics()
+            .throttled_requests(RateLimitScope::Connection),
+        1
+    );
+
+    // Those granted a role with a larger burst get that one
+    let mut client = sign_in("letmein");
+    assert_eq!(echoes(&mut client, 10), 10);
+
+    assert!(plain.disconnect().is_ok());
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+fn create_server_with_config(runtime: &Runtime, config: ServerConfig) -> Arc<AsyncServer> {
+    Arc::new(
+        runtime
+            .block_on(AsyncServer::with_config(
+                "localhost:0",
+                config,
+                Router::default(),
+            ))
+            .expect("Failed to start server"),
+    )
+}
+
+#[test]
+fn test_async_access_list_denies_connections() {
+    let runtime = create_runtime();
+    let loopback = vec!["127.0.0.0/8".parse().unwrap(), "::1/128".parse().unwrap()];
+    let server = create_server_with_config(
+        &runtime,
+        ServerConfig {
+            access: AccessList {
+                allow: loopback.clone(),
+                deny: loopback,
+            },
+            ..Default::default()
+        },
+    );
+    let port = server_port(&server);
+    let handle = setup_server_thread(runtime.clone(), server.clone());
+
+    // Closed without a reply
+    let mut stream = TcpStream::connect(("localhost", port)).expect("Failed to connect");
+    assert!(test_client::handshake(&mut stream).is_err());
+    assert_eq!(server.metrics().denied_connections(), 1);
+
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_async_connection_caps() {
+    let runtime = create_runtime();
+    let server = create_server_with_config(
+        &runtime,
+        ServerConfig {
+            max_connections: Some(2),
+            max_connections_per_ip: Some(1),
+            ..Default::default()
+        },
+    );
+    let port = server_port(&server);
+    let handle = setup_server_thread(runtime.clone(), server.clone());
+
+    let mut client1 = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client1.connect().is_ok(), "Failed to connect to the server");
+    assert!(matches!(
+        echo(&mut client1, "first"),
+        server_message::Message::EchoMessage(_)
+    ));
+
+    // Every test client connects from the same address
+    let mut client2 = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client2.connect().is_ok(), "Failed to connect to the server");
+    match echo(&mut client2, "second") {
+        server_message::Message::ErrorResponse(error) => {
+            assert_eq!(error.code, ErrorCode::ServerBusy as i32)
+        }
+        other => panic!("Expected ErrorResponse, but received {:?}", other),
+    }
+    assert_eq!(server.metrics().rejected_connections(), 1);
+
+    // Closing the first connection makes room again
+    assert!(client1.disconnect().is_ok());
+    thread::sleep(Duration::from_millis(100));
+    let mut client3 = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client3.connect().is_ok(), "Failed to connect to the server");
+    assert!(matches!(
+        echo(&mut client3, "third"),
+        server_message::Message::EchoMessage(_)
+    ));
+
+    assert!(client3.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_async_connection_cap_leaves_connections_in_backlog() {
+    let runtime = create_runtime();
+    let server = create_server_with_config(
+        &runtime,
+        ServerConfig {
+            max_connections: Some(1),
+            connection_cap_policy: ConnectionCapPolicy::Backlog,
+            ..Default::default()
+        },
+    );
+    let port = server_port(&server);
+    let handle = setup_server_thread(runtime.clone(), server.clone());
+
+    let mut client1 = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client1.connect().is_ok(), "Failed to connect to the server");
+    assert!(matches!(
+        echo(&mut client1, "first"),
+        server_message::Message::EchoMessage(_)
+    ));
+
+    // The second client waits in the backlog until the first one leaves
+    let mut client2 = test_client::TestClient::new("localhost", port, 3000);
+    assert!(client2.connect().is_ok(), "Failed to connect to the server");
+    let started = Instant::now();
+    let leaver = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(300));
+        assert!(client1.disconnect().is_ok());
+    });
+    assert!(matches!(
+        echo(&mut client2, "second"),
+        server_message::Message::EchoMessage(_)
+    ));
+    assert!(started.elapsed() >= Duration::from_millis(300));
+    assert_eq!(server.metrics().rejected_connections(), 0);
+    leaver.join().unwrap();
+
+    // A stop while at the cap still gets through
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
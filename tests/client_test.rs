@@ -1,11 +1,43 @@
+// The baseline tests build their messages field by field
+#![allow(
+    clippy::clone_on_copy,
+    clippy::field_reassign_with_default,
+    clippy::useless_vec
+)]
+
 use embedded_recruitment_task::{
-    message::{client_message, server_message, AddRequest, EchoMessage},
-    server::Server,
+    access::AccessList,
+    auth::{self, AuthConfig},
+    codec::{self, FrameFormat},
+    config::{ConnectionCapPolicy, SaturationPolicy, ServerConfig},
+    error::ProtocolError,
+    handshake::PROTOCOL_VERSION,
+    heartbeat::ReapReason,
+    message::{
+        admin_request, admin_response, auth_request, client_message, server_message,
+        AddInt64Request, AddRequest, AddResponse, AdminRequest, AdminResponse, ArithmeticOperator,
+        ArithmeticRequest, AuthRequest, Capability, ClientMessage, Compression, DisconnectClient,
+        EchoMessage, ErrorCode, Hello, InspectConnection, ListConnections, OverflowMode, Ping,
+        Pong, ServerMessage, StreamChunk, StreamEnd, StreamStart,
+    },
+    middleware::{CatchPanic, Next, Timing},
+    policy::{Policy, Role},
+    rate_limit::{Budget, RateLimit, RateLimitConfig, RateLimitScope, Throttle},
+    router::{MessageKind, RequestContext, Router},
+    server::{Server, ShutdownReport},
+    tls::TlsConfig,
+    transport::{TlsStream, Transport},
+    unix::UnixAddr,
 };
-use serial_test::serial;
 use std::{
-    sync::Arc,
+    collections::{HashMap, HashSet},
+    fs,
+    io::{ErrorKind, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    sync::{mpsc, Arc, Mutex},
     thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 mod test_client;
@@ -16,19 +48,105 @@ fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {
     })
 }
 
+fn add(client: &mut test_client::TestClient, add_request: AddRequest) -> server_message::Message {
+    let message = client_message::Message::AddRequest(add_request);
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    client
+        .receive()
+        .expect("Failed to receive response")
+        .message
+        .expect("Response has no message set")
+}
+
+fn arithmetic(
+    client: &mut test_client::TestClient,
+    operator: ArithmeticOperator,
+    a: i64,
+    b: i64,
+    overflow_mode: OverflowMode,
+) -> server_message::Message {
+    let message = client_message::Message::ArithmeticRequest(ArithmeticRequest {
+        operator: operator as i32,
+        a,
+        b,
+        overflow_mode: overflow_mode as i32,
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    client
+        .receive()
+        .expect("Failed to receive response")
+        .message
+        .expect("Response has no message set")
+}
+
+fn expect_error(client: &mut test_client::TestClient, code: ErrorCode) {
+    match client
+        .receive()
+        .expect("Failed to receive error reply")
+        .message
+    {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(
+                error.code, code as i32,
+                "Unexpected error: {}",
+                error.message
+            );
+        }
+        other => panic!("Expected ErrorResponse, but received {:?}", other),
+    }
+}
+
+fn setup_server_thread_with_report(server: Arc<Server>) -> JoinHandle<ShutdownReport> {
+    thread::spawn(move || server.run().expect("Server encountered an error"))
+}
+
+fn server_port(server: &Server) -> u16 {
+    server
+        .local_addr()
+        .expect("Failed to read server address")
+        .port()
+}
+
 fn create_server() -> Arc<Server> {
-    Arc::new(Server::new("localhost:5000").expect("Failed to start server"))
+    Arc::new(Server::new("localhost:0", Router::default()).expect("Failed to start server"))
+}
+
+fn create_server_with_router(router: Router) -> Arc<Server> {
+    Arc::new(Server::new("localhost:0", router).expect("Failed to start server"))
+}
+
+fn create_server_with_config(config: ServerConfig) -> Arc<Server> {
+    Arc::new(
+        Server::with_config("localhost:0", config, Router::default())
+            .expect("Failed to start server"),
+    )
+}
+
+fn echo(client: &mut test_client::TestClient, content: &str) -> String {
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: content.to_string(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+
+    match client
+        .receive()
+        .expect("Failed to receive response")
+        .message
+    {
+        Some(server_message::Message::EchoMessage(echo)) => echo.content,
+        other => panic!("Expected EchoMessage, but received {:?}", other),
+    }
 }
 
 #[test]
-#[serial]
 fn test_client_connection() {
     // Set up the server in a separate thread
     let server = create_server();
+    let port = server_port(&server);
     let handle = setup_server_thread(server.clone());
 
     // Create and connect the client
-    let mut client = test_client::TestClient::new("localhost", 5000, 1000);
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     // Disconnect the client
@@ -46,14 +164,14 @@ fn test_client_connection() {
 }
 
 #[test]
-#[serial]
 fn test_client_echo_message() {
     // Set up the server in a separate thread
     let server = create_server();
+    let port = server_port(&server);
     let handle = setup_server_thread(server.clone());
 
     // Create and connect the client
-    let mut client = test_client::TestClient::new("localhost", 5000, 1000);
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     // Prepare the message
@@ -98,14 +216,14 @@ fn test_client_echo_message() {
 }
 
 #[test]
-#[serial]
 fn test_multiple_echo_messages() {
     // Set up the server in a separate thread
     let server = create_server();
+    let port = server_port(&server);
     let handle = setup_server_thread(server.clone());
 
     // Create and connect the client
-    let mut client = test_client::TestClient::new("localhost", 5000, 1000);
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     // Prepare multiple messages
@@ -157,17 +275,17 @@ fn test_multiple_echo_messages() {
 }
 
 #[test]
-#[serial]
 fn test_multiple_clients() {
     // Set up the server in a separate thread
     let server = create_server();
+    let port = server_port(&server);
     let handle = setup_server_thread(server.clone());
 
     // Create and connect multiple clients
     let mut clients = vec![
-        test_client::TestClient::new("localhost", 5000, 1000),
-        test_client::TestClient::new("localhost", 5000, 1000),
-        test_client::TestClient::new("localhost", 5000, 1000),
+        test_client::TestClient::new("localhost", port, 1000),
+        test_client::TestClient::new("localhost", port, 1000),
+        test_client::TestClient::new("localhost", port, 1000),
     ];
 
     for client in clients.iter_mut() {
@@ -230,14 +348,14 @@ fn test_multiple_clients() {
 }
 
 #[test]
-#[serial]
 fn test_client_add_request() {
     // Set up the server in a separate thread
     let server = create_server();
+    let port = server_port(&server);
     let handle = setup_server_thread(server.clone());
 
     // Create and connect the client
-    let mut client = test_client::TestClient::new("localhost", 5000, 1000);
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     // Prepare the message
@@ -284,11 +402,11 @@ fn test_client_add_request() {
 // new Test cases
 
 #[test]
-#[serial]
 fn test_add_request_zero_values() {
     let server = create_server();
+    let port = server_port(&server);
     let handle = setup_server_thread(server.clone());
-    let mut client = test_client::TestClient::new("localhost", 5000, 1000);
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     // Prepare the AddRequest message with zero values
@@ -322,13 +440,13 @@ fn test_add_request_zero_values() {
 }
 
 #[test]
-#[serial]
 fn test_simultaneous_requests() {
     let server = create_server(); // Assuming this initializes the server
+    let port = server_port(&server);
     let handle = setup_server_thread(server.clone()); // Assuming this sets up the server thread
 
-    let mut client1 = test_client::TestClient::new("localhost", 5000, 1000);
-    let mut client2 = test_client::TestClient::new("localhost", 5000, 1001); // Different client
+    let mut client1 = test_client::TestClient::new("localhost", port, 1000);
+    let mut client2 = test_client::TestClient::new("localhost", port, 1001); // Different client
 
     assert!(client1.connect().is_ok(), "Failed to connect to the server");
     assert!(client2.connect().is_ok(), "Failed to connect to the server");
@@ -393,11 +511,11 @@ fn test_simultaneous_requests() {
 }
 
 #[test]
-#[serial]
 fn test_large_echo_message() {
     let server = create_server();
+    let port = server_port(&server);
     let handle = setup_server_thread(server.clone());
-    let mut client = test_client::TestClient::new("localhost", 5000, 1000);
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     let mut echo_message = EchoMessage::default();
@@ -420,3 +538,3331 @@ fn test_large_echo_message() {
         "Server thread panicked or failed to join"
     );
 }
+
+#[test]
+fn test_saturated_pool_rejects_with_server_busy() {
+    let server = create_server_with_config(ServerConfig {
+        workers: 1,
+        queue_depth: 0,
+        saturation_policy: SaturationPolicy::Reject,
+        ..Default::default()
+    });
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+
+    // The first client occupies the only worker
+    let mut client1 = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client1.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(echo(&mut client1, "busy"), "busy");
+
+    // The second client is turned away with a protocol-level error
+    let mut client2 = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client2.connect().is_ok(), "Failed to connect to the server");
+    match client2
+        .receive()
+        .expect("Failed to receive rejection")
+        .message
+    {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.code, ErrorCode::ServerBusy as i32);
+        }
+        other => panic!("Expected ErrorResponse, but received {:?}", other),
+    }
+
+    let metrics = server.metrics();
+    assert_eq!(metrics.pool_size(), 1);
+    assert_eq!(metrics.busy_workers(), 1);
+    assert_eq!(metrics.rejected_connections(), 1);
+
+    assert!(client1.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_saturated_pool_queues_connection() {
+    let server = create_server_with_config(ServerConfig {
+        workers: 1,
+        queue_depth: 1,
+        saturation_policy: SaturationPolicy::Queue,
+        ..Default::default()
+    });
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+
+    let mut client1 = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client1.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(echo(&mut client1, "first"), "first");
+
+    // The second client waits in the accept queue while the worker is busy
+    let mut client2 = test_client::TestClient::new("localhost", port, 5000);
+    assert!(client2.connect().is_ok(), "Failed to connect to the server");
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "second".to_string(),
+    });
+    assert!(client2.send(message).is_ok(), "Failed to send message");
+
+    let metrics = server.metrics();
+    for _ in 0..50 {
+        if metrics.queue_depth() == 1 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(metrics.queue_capacity(), 1);
+    assert_eq!(metrics.queue_depth(), 1);
+
+    // Freeing the worker lets the queued connection through
+    assert!(client1.disconnect().is_ok());
+    match client2
+        .receive()
+        .expect("Failed to receive response")
+        .message
+    {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "second"),
+        other => panic!("Expected EchoMessage, but received {:?}", other),
+    }
+    assert_eq!(metrics.queue_depth(), 0);
+
+    assert!(client2.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_saturated_pool_blocks_accept_loop() {
+    let server = create_server_with_config(ServerConfig {
+        workers: 1,
+        queue_depth: 0,
+        saturation_policy: SaturationPolicy::Block,
+        ..Default::default()
+    });
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+
+    let mut client1 = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client1.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(echo(&mut client1, "first"), "first");
+
+    // With no queue, the acceptor holds the second client until the worker is free, and
+    // the third waits in the listen backlog meanwhile
+    let mut client2 = test_client::TestClient::new("localhost", port, 3000);
+    assert!(client2.connect().is_ok(), "Failed to connect to the server");
+    let mut client3 = test_client::TestClient::new("localhost", port, 5000);
+    assert!(client3.connect().is_ok(), "Failed to connect to the server");
+    let started = Instant::now();
+    let leaver = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(300));
+        assert!(client1.disconnect().is_ok());
+    });
+    assert_eq!(echo(&mut client2, "second"), "second");
+    assert!(started.elapsed() >= Duration::from_millis(300));
+    leaver.join().unwrap();
+
+    assert!(client2.disconnect().is_ok());
+    assert_eq!(echo(&mut client3, "third"), "third");
+    assert_eq!(server.metrics().rejected_connections(), 0);
+
+    assert!(client3.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_stop_drains_connected_clients() {
+    let server = create_server();
+    let port = server_port(&server);
+    let handle = setup_server_thread_with_report(server.clone());
+
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(echo(&mut client, "before stop"), "before stop");
+
+    // Stopping wakes the accept loop right away and closes idle clients
+    let started = Instant::now();
+    server.stop();
+    let report = handle.join().expect("Server thread panicked");
+    assert!(started.elapsed() < Duration::from_secs(1));
+    assert_eq!(
+        report,
+        ShutdownReport {
+            drained: 1,
+            force_closed: 0
+        }
+    );
+
+    // The server closed the connection under the client
+    assert!(client.receive().is_err(), "Connection should be closed");
+}
+
+#[test]
+fn test_stop_force_closes_clients_past_drain_deadline() {
+    let server = create_server_with_config(ServerConfig {
+        drain_timeout: Duration::from_millis(200),
+        ..Default::default()
+    });
+    let port = server_port(&server);
+    let handle = setup_server_thread_with_report(server.clone());
+
+    // A client that keeps sending large echoes but never reads the replies leaves
+    // its handler stuck writing once the socket buffers fill up
+    let mut stream = TcpStream::connect(("localhost", port)).expect("Failed to connect");
+    test_client::handshake(&mut stream).expect("Handshake failed");
+    let frame = codec::encode_frame(&ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "a".repeat(900_000),
+        })),
+        ..Default::default()
+    });
+    thread::spawn(move || while stream.write_all(&frame).is_ok() {});
+    thread::sleep(Duration::from_millis(500));
+
+    server.stop();
+    let report = handle.join().expect("Server thread panicked");
+    assert_eq!(
+        report,
+        ShutdownReport {
+            drained: 0,
+            force_closed: 1
+        }
+    );
+}
+
+#[test]
+fn test_stop_before_run() {
+    let server = create_server();
+
+    // Stopping ahead of `run` must not leave the server serving forever
+    server.stop();
+    let handle = setup_server_thread_with_report(server.clone());
+    assert_eq!(
+        handle.join().expect("Server thread panicked"),
+        ShutdownReport::default()
+    );
+}
+
+#[test]
+fn test_bind_ephemeral_port() {
+    // Port 0 lets the OS pick a free port, which `local_addr` reports back
+    let server = create_server();
+    let port = server_port(&server);
+    assert_ne!(port, 0, "Server should report the port it actually bound");
+
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(echo(&mut client, "ephemeral"), "ephemeral");
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_unsupported_message_gets_error_reply() {
+    let server = create_server();
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // A ClientMessage carrying only an unknown field decodes with no message set
+    let unknown_field = [15 << 3, 1];
+    assert!(client
+        .send_raw(unknown_field.len() as u32, &unknown_field)
+        .is_ok());
+    expect_error(&mut client, ErrorCode::UnsupportedMessage);
+
+    // The connection stays usable
+    assert_eq!(echo(&mut client, "still here"), "still here");
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_malformed_message_gets_error_reply() {
+    let server = create_server();
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Truncated varint, not a valid protobuf message
+    let garbage = [0xff, 0xff];
+    assert!(client.send_raw(garbage.len() as u32, &garbage).is_ok());
+    expect_error(&mut client, ErrorCode::MalformedMessage);
+
+    // So is an empty frame
+    assert!(client.send_raw(0, &[]).is_ok());
+    expect_error(&mut client, ErrorCode::MalformedMessage);
+
+    // Both leave the frame boundary intact, so the connection stays usable
+    assert_eq!(echo(&mut client, "still here"), "still here");
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_oversized_frame_gets_error_reply_and_closes() {
+    let server = create_server();
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    assert!(client.send_raw(2 * 1024 * 1024, &[]).is_ok());
+    expect_error(&mut client, ErrorCode::FrameTooLarge);
+
+    // The body was never read, so the server can't find the next frame and hangs up
+    assert!(client.receive().is_err(), "Connection should be closed");
+
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_add_request_overflow_is_reported() {
+    let server = create_server();
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Checked is the server default: overflow comes back as an error, not a panic
+    let add_request = AddRequest {
+        a: i32::MAX,
+        b: 1,
+        ..Default::default()
+    };
+    let message = client_message::Message::AddRequest(add_request);
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    expect_error(&mut client, ErrorCode::ArithmeticOverflow);
+
+    // The handler survived and keeps serving the connection
+    let add_request = AddRequest {
+        a: 1,
+        b: 2,
+        ..Default::default()
+    };
+    match add(&mut client, add_request) {
+        server_message::Message::AddResponse(add_response) => assert_eq!(add_response.result, 3),
+        other => panic!("Expected AddResponse, but received {:?}", other),
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_add_request_overflow_modes() {
+    let server = create_server();
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    let cases = [
+        (OverflowMode::Wrapping, i32::MIN),
+        (OverflowMode::Saturating, i32::MAX),
+    ];
+    for (mode, expected) in cases {
+        let add_request = AddRequest {
+            a: i32::MAX,
+            b: 1,
+            overflow_mode: mode as i32,
+        };
+        match add(&mut client, add_request) {
+            server_message::Message::AddResponse(add_response) => {
+                assert_eq!(add_response.result, expected, "Wrong result for {:?}", mode);
+            }
+            other => panic!("Expected AddResponse, but received {:?}", other),
+        }
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_add_request_uses_server_overflow_mode() {
+    let server = create_server_with_config(ServerConfig {
+        overflow_mode: OverflowMode::Saturating,
+        ..Default::default()
+    });
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    let add_request = AddRequest {
+        a: i32::MIN,
+        b: -1,
+        ..Default::default()
+    };
+    match add(&mut client, add_request) {
+        server_message::Message::AddResponse(add_response) => {
+            assert_eq!(add_response.result, i32::MIN);
+        }
+        other => panic!("Expected AddResponse, but received {:?}", other),
+    }
+
+    // A request can still override the server default
+    let add_request = AddRequest {
+        a: i32::MIN,
+        b: -1,
+        overflow_mode: OverflowMode::Checked as i32,
+    };
+    let message = client_message::Message::AddRequest(add_request);
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    expect_error(&mut client, ErrorCode::ArithmeticOverflow);
+
+    // A mode the server doesn't know is a bad argument, and the connection stays open
+    let add_request = AddRequest {
+        a: 1,
+        b: 2,
+        overflow_mode: 42,
+    };
+    let message = client_message::Message::AddRequest(add_request);
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    expect_error(&mut client, ErrorCode::InvalidArgument);
+    assert_eq!(echo(&mut client, "still open"), "still open");
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_add_int64_request() {
+    let server = create_server();
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Well past the range of AddRequest's int32
+    let add_request = AddInt64Request {
+        a: i32::MAX as i64,
+        b: i32::MAX as i64,
+        ..Default::default()
+    };
+    let message = client_message::Message::AddInt64Request(add_request);
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    match client
+        .receive()
+        .expect("Failed to receive response")
+        .message
+    {
+        Some(server_message::Message::AddInt64Response(add_response)) => {
+            assert_eq!(add_response.result, 2 * i32::MAX as i64);
+        }
+        other => panic!("Expected AddInt64Response, but received {:?}", other),
+    }
+
+    let add_request = AddInt64Request {
+        a: i64::MAX,
+        b: 1,
+        ..Default::default()
+    };
+    let message = client_message::Message::AddInt64Request(add_request);
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    expect_error(&mut client, ErrorCode::ArithmeticOverflow);
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_client_arithmetic_request() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+
+    // Create and connect the client
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    let cases = [
+        (ArithmeticOperator::Add, 10, 20, 30),
+        (ArithmeticOperator::Subtract, 10, 20, -10),
+        (ArithmeticOperator::Multiply, -6, 7, -42),
+        (ArithmeticOperator::Divide, -7, 2, -3),
+        (ArithmeticOperator::Modulo, -7, 2, -1),
+        (ArithmeticOperator::Power, 3, 4, 81),
+        (ArithmeticOperator::Power, 5, 0, 1),
+    ];
+    for (operator, a, b, expected) in cases {
+        match arithmetic(&mut client, operator, a, b, OverflowMode::Unspecified) {
+            server_message::Message::ArithmeticResponse(response) => {
+                assert_eq!(
+                    response.result, expected,
+                    "ArithmeticResponse result for {} {:?} {} does not match",
+                    a, operator, b
+                );
+            }
+            other => panic!("Expected ArithmeticResponse, but received {:?}", other),
+        }
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_arithmetic_division_by_zero() {
+    let server = create_server();
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // No overflow mode makes a zero divisor meaningful
+    for (operator, expression) in [
+        (ArithmeticOperator::Divide, "1 / 0"),
+        (ArithmeticOperator::Modulo, "1 % 0"),
+    ] {
+        for mode in [OverflowMode::Checked, OverflowMode::Wrapping] {
+            match arithmetic(&mut client, operator, 1, 0, mode) {
+                server_message::Message::ErrorResponse(error) => {
+                    assert_eq!(error.code, ErrorCode::DivisionByZero as i32);
+                    assert!(error.message.starts_with(expression), "{}", error.message);
+                }
+                other => panic!("Expected ErrorResponse, but received {:?}", other),
+            }
+        }
+    }
+
+    // The connection stays usable
+    assert_eq!(echo(&mut client, "still here"), "still here");
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_arithmetic_overflow() {
+    let server = create_server();
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    let overflowing = [
+        (ArithmeticOperator::Subtract, i64::MIN, 1),
+        (ArithmeticOperator::Multiply, i64::MAX, 2),
+        (ArithmeticOperator::Divide, i64::MIN, -1),
+        (ArithmeticOperator::Power, 2, 63),
+    ];
+    for (operator, a, b) in overflowing {
+        match arithmetic(&mut client, operator, a, b, OverflowMode::Checked) {
+            server_message::Message::ErrorResponse(error) => {
+                assert_eq!(error.code, ErrorCode::ArithmeticOverflow as i32);
+            }
+            other => panic!("Expected ErrorResponse, but received {:?}", other),
+        }
+    }
+
+    let defined = [
+        (
+            ArithmeticOperator::Multiply,
+            i64::MAX,
+            2,
+            OverflowMode::Wrapping,
+            -2,
+        ),
+        (
+            ArithmeticOperator::Divide,
+            i64::MIN,
+            -1,
+            OverflowMode::Wrapping,
+            i64::MIN,
+        ),
+        (
+            ArithmeticOperator::Divide,
+            i64::MIN,
+            -1,
+            OverflowMode::Saturating,
+            i64::MAX,
+        ),
+        (
+            ArithmeticOperator::Power,
+            -2,
+            63,
+            OverflowMode::Saturating,
+            i64::MIN,
+        ),
+        // The remainder itself always fits, whatever the mode
+        (
+            ArithmeticOperator::Modulo,
+            i64::MIN,
+            -1,
+            OverflowMode::Checked,
+            0,
+        ),
+    ];
+    for (operator, a, b, mode, expected) in defined {
+        match arithmetic(&mut client, operator, a, b, mode) {
+            server_message::Message::ArithmeticResponse(response) => {
+                assert_eq!(
+                    response.result, expected,
+                    "{} {:?} {} in {:?}",
+                    a, operator, b, mode
+                );
+            }
+            other => panic!("Expected ArithmeticResponse, but received {:?}", other),
+        }
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_arithmetic_invalid_argument() {
+    let server = create_server();
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    let invalid = [
+        (ArithmeticOperator::Unspecified, 1, 2),
+        (ArithmeticOperator::Power, 2, -1),
+    ];
+    for (operator, a, b) in invalid {
+        match arithmetic(&mut client, operator, a, b, OverflowMode::Checked) {
+            server_message::Message::ErrorResponse(error) => {
+                assert_eq!(error.code, ErrorCode::InvalidArgument as i32);
+            }
+            other => panic!("Expected ErrorResponse, but received {:?}", other),
+        }
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_pipelined_requests_with_ids() {
+    let server = create_server();
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Send every request before reading any reply
+    let count = 50u64;
+    for request_id in 1..=count {
+        let message = client_message::Message::ArithmeticRequest(ArithmeticRequest {
+            operator: ArithmeticOperator::Multiply as i32,
+            a: request_id as i64,
+            b: 3,
+            ..Default::default()
+        });
+        assert!(
+            client.send_with_id(request_id, message).is_ok(),
+            "Failed to send message"
+        );
+    }
+
+    // Replies may come back in any order, the request ID tells them apart
+    let mut results = HashMap::new();
+    for _ in 0..count {
+        let response = client.receive().expect("Failed to receive response");
+        match response.message {
+            Some(server_message::Message::ArithmeticResponse(arithmetic)) => {
+                assert!(
+                    results
+                        .insert(response.request_id, arithmetic.result)
+                        .is_none(),
+                    "Request {} answered twice",
+                    response.request_id
+                );
+            }
+            other => panic!("Expected ArithmeticResponse, but received {:?}", other),
+        }
+    }
+    for request_id in 1..=count {
+        assert_eq!(results.get(&request_id), Some(&(request_id as i64 * 3)));
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_pipelined_requests_share_bounded_pool() {
+    // Records the threads echoes run on, and how many run at once
+    let threads = Arc::new(Mutex::new(HashSet::new()));
+    let running = Arc::new(Mutex::new((0usize, 0usize)));
+    let (seen, counts) = (threads.clone(), running.clone());
+    let router = Router::default().route(
+        MessageKind::Echo,
+        move |_: &RequestContext<'_>, message: client_message::Message| {
+            seen.lock().unwrap().insert(thread::current().id());
+            {
+                let mut counts = counts.lock().unwrap();
+                counts.0 += 1;
+                counts.1 = counts.1.max(counts.0);
+            }
+            thread::sleep(Duration::from_millis(20));
+            counts.lock().unwrap().0 -= 1;
+            match message {
+                client_message::Message::EchoMessage(echo) => {
+                    Ok(server_message::Message::EchoMessage(echo))
+                }
+                _ => Err(ProtocolError::new(
+                    ErrorCode::InvalidArgument,
+                    "not an echo",
+                )),
+            }
+        },
+    );
+    let config = ServerConfig {
+        request_workers: 2,
+        max_in_flight: 32,
+        ..Default::default()
+    };
+    let server = Arc::new(
+        Server::with_config("localhost:0", config, router).expect("Failed to start server"),
+    );
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+
+    // Every client pipelines more requests than the pool has workers
+    let clients: Vec<_> = (0..3)
+        .map(|_| {
+            thread::spawn(move || {
+                let mut client = test_client::TestClient::new("localhost", port, 5000);
+                assert!(client.connect().is_ok(), "Failed to connect to the server");
+                for request_id in 1..=10 {
+                    let message = client_message::Message::EchoMessage(EchoMessage {
+                        content: request_id.to_string(),
+                    });
+                    assert!(
+                        client.send_with_id(request_id, message).is_ok(),
+                        "Failed to send message"
+                    );
+                }
+                for _ in 0..10 {
+                    let response = client.receive().expect("Failed to receive response");
+                    match response.message {
+                        Some(server_message::Message::EchoMessage(echo)) => {
+                            assert_eq!(echo.content, response.request_id.to_string())
+                        }
+                        other => panic!("Expected EchoMessage, but received {:?}", other),
+                    }
+                }
+                assert!(client.disconnect().is_ok());
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().expect("Client thread panicked");
+    }
+
+    assert!(threads.lock().unwrap().len() <= 2);
+    assert!(running.lock().unwrap().1 <= 2);
+
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_slow_reader_does_not_stall_other_connections() {
+    let config = ServerConfig {
+        request_workers: 1,
+        ..Default::default()
+    };
+    let server = create_server_with_config(config);
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+
+    // Pipelines more echoes than the socket buffers hold and never reads a reply
+    let mut slow = test_client::TestClient::new("localhost", port, 5000);
+    assert!(slow.connect().is_ok(), "Failed to connect to the server");
+    let content = "x".repeat(900 * 1024);
+    for request_id in 1..=32 {
+        let message = client_message::Message::EchoMessage(EchoMessage {
+            content: content.clone(),
+        });
+        assert!(
+            slow.send_with_id(request_id, message).is_ok(),
+            "Failed to send message"
+        );
+    }
+
+    // The only request worker isn't stuck writing to the slow reader
+    let mut client = test_client::TestClient::new("localhost", port, 2000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "not stuck".to_string(),
+    });
+    assert!(
+        client.send_with_id(1, message).is_ok(),
+        "Failed to send message"
+    );
+    let response = client.receive().expect("Failed to receive response");
+    match response.message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "not stuck"),
+        other => panic!("Expected EchoMessage, but received {:?}", other),
+    }
+
+    assert!(client.disconnect().is_ok());
+    assert!(slow.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_request_id_echoed_on_error() {
+    let server = create_server();
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    let message = client_message::Message::ArithmeticRequest(ArithmeticRequest {
+        operator: ArithmeticOperator::Divide as i32,
+        a: 1,
+        b: 0,
+        ..Default::default()
+    });
+    assert!(client.send_with_id(42, message).is_ok());
+    let response = client.receive().expect("Failed to receive response");
+    assert_eq!(response.request_id, 42);
+    assert!(matches!(
+        response.message,
+        Some(server_message::Message::ErrorResponse(_))
+    ));
+
+    // A frame that can't be decoded has no request ID to echo
+    assert!(client.send_raw(3, &[0xff, 0xff, 0xff]).is_ok());
+    let response = client.receive().expect("Failed to receive response");
+    assert_eq!(response.request_id, 0);
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_untagged_requests_answered_in_order() {
+    let server = create_server();
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    for i in 0..20 {
+        let message = client_message::Message::EchoMessage(EchoMessage {
+            content: format!("message {}", i),
+        });
+        assert!(client.send(message).is_ok(), "Failed to send message");
+    }
+    for i in 0..20 {
+        let response = client.receive().expect("Failed to receive response");
+        assert_eq!(response.request_id, 0);
+        match response.message {
+            Some(server_message::Message::EchoMessage(echo)) => {
+                assert_eq!(echo.content, format!("message {}", i));
+            }
+            other => panic!("Expected EchoMessage, but received {:?}", other),
+        }
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_custom_router() {
+    // Echo is served by our own service, nothing else is registered
+    let router = Router::empty().route(
+        MessageKind::Echo,
+        |context: &RequestContext<'_>, message: client_message::Message| match message {
+            client_message::Message::EchoMessage(echo) => {
+                Ok(server_message::Message::EchoMessage(EchoMessage {
+                    content: format!("{}: {}", context.client_id, echo.content.to_uppercase()),
+                }))
+            }
+            _ => unreachable!("the router only sends echo requests here"),
+        },
+    );
+    let server = create_server_with_router(router);
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    assert_eq!(echo(&mut client, "hello"), "1: HELLO");
+
+    // Unregistered kinds are answered as unimplemented and the connection stays open
+    let message = client_message::Message::AddRequest(AddRequest {
+        a: 1,
+        b: 2,
+        ..Default::default()
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    expect_error(&mut client, ErrorCode::Unimplemented);
+    assert_eq!(echo(&mut client, "still here"), "1: STILL HERE");
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_router_overrides_builtin_service() {
+    let router = Router::default().route(
+        MessageKind::Add,
+        |_: &RequestContext<'_>, _: client_message::Message| {
+            Ok(server_message::Message::AddResponse(AddResponse {
+                result: 42,
+            }))
+        },
+    );
+    assert!(MessageKind::ALL.iter().all(|kind| router.handles(*kind)));
+    let server = create_server_with_router(router);
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    match add(
+        &mut client,
+        AddRequest {
+            a: 1,
+            b: 2,
+            ..Default::default()
+        },
+    ) {
+        server_message::Message::AddResponse(response) => assert_eq!(response.result, 42),
+        other => panic!("Expected AddResponse, but received {:?}", other),
+    }
+    // The other built-in services are untouched
+    assert_eq!(echo(&mut client, "hello"), "hello");
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_middleware_layers() {
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let outer_calls = calls.clone();
+    let inner_calls = calls.clone();
+    let router = Router::default()
+        .layer(Timing::default())
+        // Outermost: refuses some requests before anything else sees them
+        .layer(
+            move |context: &RequestContext<'_>, message: ClientMessage, next: Next<'_>| {
+                outer_calls.lock().unwrap().push("outer");
+                if let Some(client_message::Message::EchoMessage(echo)) = &message.message {
+                    if echo.content == "forbidden" {
+                        return Err(ProtocolError::new(
+                            ErrorCode::InvalidArgument,
+                            "Not on this server",
+                        ));
+                    }
+                }
+                next.run(context, message)
+            },
+        )
+        // Innermost: rewrites both the request and the reply
+        .layer(
+            move |context: &RequestContext<'_>, mut message: ClientMessage, next: Next<'_>| {
+                inner_calls.lock().unwrap().push("inner");
+                if let Some(client_message::Message::EchoMessage(echo)) = &mut message.message {
+                    echo.content = echo.content.trim().to_string();
+                }
+                let mut response = next.run(context, message)?;
+                if let Some(server_message::Message::EchoMessage(echo)) = &mut response.message {
+                    echo.content.push('!');
+                }
+                Ok(response)
+            },
+        );
+    let server = create_server_with_router(router);
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    assert_eq!(echo(&mut client, "  hello "), "hello!");
+    assert_eq!(*calls.lock().unwrap(), ["outer", "inner"]);
+
+    // Short-circuited by the outer layer, the inner one never runs
+    calls.lock().unwrap().clear();
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "forbidden".to_string(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    expect_error(&mut client, ErrorCode::InvalidArgument);
+    assert_eq!(*calls.lock().unwrap(), ["outer"]);
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_short_circuited_reply_keeps_request_id() {
+    // Answers adds itself, with a reply it doesn't tag
+    let router = Router::default().layer(
+        |context: &RequestContext<'_>, message: ClientMessage, next: Next<'_>| match message.message
+        {
+            Some(client_message::Message::AddRequest(_)) => Ok(ServerMessage {
+                request_id: 0,
+                message: Some(server_message::Message::AddResponse(AddResponse {
+                    result: 42,
+                })),
+            }),
+            _ => next.run(context, message),
+        },
+    );
+    let server = create_server_with_router(router);
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    let message = client_message::Message::AddRequest(AddRequest {
+        a: 1,
+        b: 2,
+        ..Default::default()
+    });
+    assert!(
+        client.send_with_id(7, message).is_ok(),
+        "Failed to send message"
+    );
+    let response = client.receive().expect("Failed to receive response");
+    assert_eq!(response.request_id, 7);
+    match response.message {
+        Some(server_message::Message::AddResponse(add)) => assert_eq!(add.result, 42),
+        other => panic!("Expected AddResponse, but received {:?}", other),
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_catch_panic_layer() {
+    let router = Router::default().layer(CatchPanic).route(
+        MessageKind::Add,
+        |_: &RequestContext<'_>, _: client_message::Message| -> Result<_, ProtocolError> {
+            panic!("add service is broken")
+        },
+    );
+    let server = create_server_with_router(router);
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    let message = client_message::Message::AddRequest(AddRequest {
+        a: 1,
+        b: 2,
+        ..Default::default()
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    expect_error(&mut client, ErrorCode::InternalError);
+
+    // The connection and its worker survive the panic
+    assert_eq!(echo(&mut client, "still here"), "still here");
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_panicking_pipelined_request() {
+    // No CatchPanic layer, so the panic reaches the request pool
+    let router = Router::default().route(
+        MessageKind::Add,
+        |_: &RequestContext<'_>, _: client_message::Message| -> Result<_, ProtocolError> {
+            panic!("add service is broken")
+        },
+    );
+    let config = ServerConfig {
+        request_workers: 1,
+        ..Default::default()
+    };
+    let server = Arc::new(
+        Server::with_config("localhost:0", config, router).expect("Failed to start server"),
+    );
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    let message = client_message::Message::AddRequest(AddRequest {
+        a: 1,
+        b: 2,
+        ..Default::default()
+    });
+    assert!(
+        client.send_with_id(1, message).is_ok(),
+        "Failed to send message"
+    );
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "still here".to_string(),
+    });
+    assert!(
+        client.send_with_id(2, message).is_ok(),
+        "Failed to send message"
+    );
+
+    // The only request worker survives the panic and serves the next request
+    let response = client.receive().expect("Failed to receive response");
+    assert_eq!(response.request_id, 2);
+    match response.message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "still here"),
+        other => panic!("Expected EchoMessage, but received {:?}", other),
+    }
+
+    // The panicked request gave its slot back, so the connection drains and stop returns
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+    assert_eq!(server.metrics().busy_workers(), 0);
+}
+
+#[test]
+fn test_panicking_request_frees_its_connection() {
+    let router = Router::default().route(
+        MessageKind::Add,
+        |_: &RequestContext<'_>, _: client_message::Message| -> Result<_, ProtocolError> {
+            panic!("add service is broken")
+        },
+    );
+    let config = ServerConfig {
+        workers: 1,
+        max_connections: Some(1),
+        ..Default::default()
+    };
+    let server = Arc::new(
+        Server::with_config("localhost:0", config, router).expect("Failed to start server"),
+    );
+    let port = server_port(&server);
+    let handle = setup_server_thread_with_report(server.clone());
+
+    // The panic takes the connection down with it
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let message = client_message::Message::AddRequest(AddRequest {
+        a: 1,
+        b: 2,
+        ..Default::default()
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    assert!(
+        client.receive().is_err(),
+        "Expected the connection to close"
+    );
+
+    // Neither the worker nor the connection slot is lost
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !server.connections().is_empty() {
+        assert!(Instant::now() < deadline, "Connection still registered");
+        thread::sleep(Duration::from_millis(10));
+    }
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(echo(&mut client, "still here"), "still here");
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    let report = handle.join().expect("Server thread panicked");
+    assert_eq!(report.force_closed, 0);
+}
+
+#[test]
+fn test_frame_split_across_writes() {
+    let server = create_server();
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+
+    let frame = codec::encode_frame(&ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "split".to_string(),
+        })),
+        ..Default::default()
+    });
+
+    // Half a length prefix, then the rest of the prefix with half the body, then the rest
+    let mut stream = TcpStream::connect(("localhost", port)).expect("Failed to connect");
+    test_client::handshake(&mut stream).expect("Handshake failed");
+    stream.set_nodelay(true).unwrap();
+    for part in [&frame[..2], &frame[2..7], &frame[7..]] {
+        stream.write_all(part).unwrap();
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    let mut client = test_client::TestClient::from_stream(stream);
+    match client
+        .receive()
+        .expect("Failed to receive response")
+        .message
+    {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "split"),
+        other => panic!("Expected EchoMessage, but received {:?}", other),
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_configured_max_frame_length() {
+    let server = create_server_with_config(ServerConfig {
+        max_frame_length: 64,
+        ..Default::default()
+    });
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    assert_eq!(echo(&mut client, "short enough"), "short enough");
+
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "x".repeat(100),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    expect_error(&mut client, ErrorCode::FrameTooLarge);
+    assert!(client.receive().is_err(), "Connection should be closed");
+
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_memory_limit_shared_by_connections() {
+    let server = create_server_with_config(ServerConfig {
+        memory_limit: 256,
+        ..Default::default()
+    });
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+
+    // The first client holds 200 bytes of an unfinished frame
+    let frame = codec::encode_frame(&ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "a".repeat(220),
+        })),
+        ..Default::default()
+    });
+    let mut stream = TcpStream::connect(("localhost", port)).expect("Failed to connect");
+    test_client::handshake(&mut stream).expect("Handshake failed");
+    stream.write_all(&frame[..200]).unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    // Which leaves too little for the second one
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "b".repeat(100),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    expect_error(&mut client, ErrorCode::ResourceExhausted);
+    assert!(client.receive().is_err(), "Connection should be closed");
+
+    // The first client finishes its frame, which frees the memory again
+    stream.write_all(&frame[200..]).unwrap();
+    let mut first = test_client::TestClient::from_stream(stream);
+    match first.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content.len(), 220),
+        other => panic!("Expected EchoMessage, but received {:?}", other),
+    }
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(echo(&mut client, &"c".repeat(100)), "c".repeat(100));
+
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_stream_echo_large_payload() {
+    let server = create_server();
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 5000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Three times the frame limit, sent a chunk at a time and echoed back the same way
+    let payload: Vec<u8> = (0..3 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let start = client_message::Message::StreamStart(StreamStart {
+        stream_id: 1,
+        total_length: payload.len() as u64,
+    });
+    assert!(client.send_with_id(7, start).is_ok());
+    let response = client.receive().expect("Failed to receive response");
+    assert_eq!(response.request_id, 7);
+    assert!(matches!(
+        response.message,
+        Some(server_message::Message::StreamStart(StreamStart {
+            stream_id: 1,
+            ..
+        }))
+    ));
+
+    let mut echoed = Vec::with_capacity(payload.len());
+    for (index, data) in payload.chunks(64 * 1024).enumerate() {
+        let chunk = client_message::Message::StreamChunk(StreamChunk {
+            stream_id: 1,
+            offset: (index * 64 * 1024) as u64,
+            data: data.to_vec(),
+        });
+        assert!(
+            client.send_with_id(7, chunk).is_ok(),
+            "Failed to send chunk"
+        );
+        match client
+            .receive()
+            .expect("Failed to receive response")
+            .message
+        {
+            Some(server_message::Message::StreamChunk(chunk)) => {
+                assert_eq!(chunk.offset, echoed.len() as u64);
+                echoed.extend_from_slice(&chunk.data);
+            }
+            other => panic!("Expected StreamChunk, but received {:?}", other),
+        }
+    }
+    assert!(echoed == payload, "Echoed payload differs");
+
+    let end = client_message::Message::StreamEnd(StreamEnd { stream_id: 1 });
+    assert!(client.send_with_id(7, end).is_ok());
+    assert!(matches!(
+        client
+            .receive()
+            .expect("Failed to receive response")
+            .message,
+        Some(server_message::Message::StreamEnd(StreamEnd {
+            stream_id: 1
+        }))
+    ));
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_stream_protocol_errors() {
+    let server = create_server_with_config(ServerConfig {
+        max_open_streams: 2,
+        max_stream_length: 1000,
+        ..Default::default()
+    });
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    let mut send = |message: client_message::Message| {
+        assert!(client.send(message).is_ok(), "Failed to send message");
+        client
+            .receive()
+            .expect("Failed to receive response")
+            .message
+            .expect("Response has no message set")
+    };
+    let start = |stream_id, total_length| {
+        client_message::Message::StreamStart(StreamStart {
+            stream_id,
+            total_length,
+        })
+    };
+    let chunk = |stream_id, offset, length| {
+        client_message::Message::StreamChunk(StreamChunk {
+            stream_id,
+            offset,
+            data: vec![0; length],
+        })
+    };
+    let end = |stream_id| client_message::Message::StreamEnd(StreamEnd { stream_id });
+    let error_code = |response: server_message::Message| match response {
+        server_message::Message::ErrorResponse(error) => error.code,
+        other => panic!("Expected ErrorResponse, but received {:?}", other),
+    };
+
+    // Chunks must belong to an open stream and follow on from each other
+    assert_eq!(
+        error_code(send(chunk(1, 0, 10))),
+        ErrorCode::InvalidArgument as i32
+    );
+    send(start(1, 0));
+    send(chunk(1, 0, 10));
+    assert_eq!(
+        error_code(send(chunk(1, 20, 10))),
+        ErrorCode::InvalidArgument as i32
+    );
+    // The broken stream was dropped
+    assert_eq!(error_code(send(end(1))), ErrorCode::InvalidArgument as i32);
+
+    // A declared length has to be met exactly
+    send(start(2, 100));
+    send(chunk(2, 0, 50));
+    assert_eq!(error_code(send(end(2))), ErrorCode::InvalidArgument as i32);
+
+    // Limits are resource errors, which close the connection
+    send(start(3, 0));
+    send(start(4, 0));
+    assert_eq!(
+        error_code(send(start(5, 0))),
+        ErrorCode::ResourceExhausted as i32
+    );
+    assert!(client.receive().is_err(), "Connection should be closed");
+
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert!(client.send(start(1, 0)).is_ok());
+    assert!(client.receive().is_ok());
+    assert!(client.send(chunk(1, 0, 1001)).is_ok());
+    expect_error(&mut client, ErrorCode::ResourceExhausted);
+    assert!(client.receive().is_err(), "Connection should be closed");
+
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+/// Sends `payload` on stream `stream_id` in chunks of `chunk_size` bytes, expecting each
+/// chunk to be acknowledged without its data. Returns the first error code instead, if any.
+fn send_stream_chunks(
+    client: &mut test_client::TestClient,
+    stream_id: u64,
+    payload: &[u8],
+    chunk_size: usize,
+) -> Result<(), i32> {
+    for (index, data) in payload.chunks(chunk_size).enumerate() {
+        let offset = (index * chunk_size) as u64;
+        let chunk = client_message::Message::StreamChunk(StreamChunk {
+            stream_id,
+            offset,
+            data: data.to_vec(),
+        });
+        assert!(client.send(chunk).is_ok(), "Failed to send chunk");
+        match client
+            .receive()
+            .expect("Failed to receive response")
+            .message
+        {
+            Some(server_message::Message::StreamChunk(ack)) => {
+                assert_eq!((ack.stream_id, ack.offset), (stream_id, offset));
+                assert!(ack.data.is_empty());
+            }
+            Some(server_message::Message::ErrorResponse(error)) => return Err(error.code),
+            other => panic!("Expected StreamChunk, but received {:?}", other),
+        }
+    }
+    Ok(())
+}
+
+/// Answers each reassembled stream with its length
+fn reassembling_router() -> Router {
+    Router::default().reassemble(|_: &RequestContext<'_>, stream_id: u64, payload: &[u8]| {
+        Ok(server_message::Message::EchoMessage(EchoMessage {
+            content: format!("stream {}: {} bytes", stream_id, payload.len()),
+        }))
+    })
+}
+
+#[test]
+fn test_stream_reassembled_for_service() {
+    let payload: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+    let expected = payload.clone();
+    let router = Router::default().reassemble(
+        move |context: &RequestContext<'_>, stream_id: u64, payload: &[u8]| {
+            assert_eq!(context.payload, Some(payload));
+            Ok(server_message::Message::EchoMessage(EchoMessage {
+                content: format!("stream {}: {}", stream_id, payload == expected.as_slice()),
+            }))
+        },
+    );
+    let config = ServerConfig {
+        max_frame_length: 1024,
+        ..Default::default()
+    };
+    let server = Arc::new(
+        Server::with_config("localhost:0", config, router).expect("Failed to start server"),
+    );
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Ten times the frame limit, handed to the service in one piece
+    let start = client_message::Message::StreamStart(StreamStart {
+        stream_id: 3,
+        total_length: payload.len() as u64,
+    });
+    assert!(client.send(start).is_ok(), "Failed to send message");
+    assert!(matches!(
+        client
+            .receive()
+            .expect("Failed to receive response")
+            .message,
+        Some(server_message::Message::StreamStart(StreamStart {
+            stream_id: 3,
+            ..
+        }))
+    ));
+    assert_eq!(send_stream_chunks(&mut client, 3, &payload, 900), Ok(()));
+    let end = client_message::Message::StreamEnd(StreamEnd { stream_id: 3 });
+    assert!(
+        client.send_with_id(9, end).is_ok(),
+        "Failed to send message"
+    );
+    let response = client.receive().expect("Failed to receive response");
+    assert_eq!(response.request_id, 9);
+    match response.message {
+        Some(server_message::Message::EchoMessage(echo)) => {
+            assert_eq!(echo.content, "stream 3: true")
+        }
+        other => panic!("Expected EchoMessage, but received {:?}", other),
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_stream_reassembly_limits() {
+    let config = ServerConfig {
+        max_frame_length: 1024,
+        max_stream_length: 4000,
+        memory_limit: 6000,
+        ..Default::default()
+    };
+    let server = Arc::new(
+        Server::with_config("localhost:0", config, reassembling_router())
+            .expect("Failed to start server"),
+    );
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let start = |client: &mut test_client::TestClient, stream_id| {
+        let start = client_message::Message::StreamStart(StreamStart {
+            stream_id,
+            total_length: 0,
+        });
+        assert!(client.send(start).is_ok(), "Failed to send message");
+        assert!(client.receive().is_ok(), "Failed to receive response");
+    };
+    let end = |client: &mut test_client::TestClient, stream_id| {
+        let end = client_message::Message::StreamEnd(StreamEnd { stream_id });
+        assert!(client.send(end).is_ok(), "Failed to send message");
+        match client
+            .receive()
+            .expect("Failed to receive response")
+            .message
+        {
+            Some(server_message::Message::EchoMessage(echo)) => echo.content,
+            other => panic!("Expected EchoMessage, but received {:?}", other),
+        }
+    };
+
+    // A stream can't be buffered past the stream length limit
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    start(&mut client, 1);
+    assert_eq!(
+        send_stream_chunks(&mut client, 1, &[0; 4500], 900),
+        Err(ErrorCode::ResourceExhausted as i32)
+    );
+    assert!(client.receive().is_err(), "Connection should be closed");
+
+    // Ended streams give their memory back, so streams that fit one at a time go through
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    for stream_id in [1, 2] {
+        start(&mut client, stream_id);
+        assert_eq!(
+            send_stream_chunks(&mut client, stream_id, &[0; 3600], 900),
+            Ok(())
+        );
+        assert_eq!(
+            end(&mut client, stream_id),
+            format!("stream {}: 3600 bytes", stream_id)
+        );
+    }
+
+    // Together, open streams can't buffer more than the server's memory budget
+    start(&mut client, 3);
+    assert_eq!(send_stream_chunks(&mut client, 3, &[0; 3600], 900), Ok(()));
+    start(&mut client, 4);
+    assert_eq!(
+        send_stream_chunks(&mut client, 4, &[0; 3600], 900),
+        Err(ErrorCode::ResourceExhausted as i32)
+    );
+    assert!(client.receive().is_err(), "Connection should be closed");
+
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_compression_negotiated() {
+    let server = create_server();
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let content = "All work and no play makes Jack a dull boy. ".repeat(2000);
+
+    for algorithm in [Compression::Zstd, Compression::Lz4] {
+        let mut client = test_client::TestClient::new("localhost", port, 1000);
+        assert!(client.connect().is_ok(), "Failed to connect to the server");
+        assert_eq!(
+            client
+                .hello(&[algorithm, Compression::Zstd])
+                .expect("Handshake failed"),
+            algorithm
+        );
+
+        // Below the threshold, bodies go over the wire as they are
+        assert_eq!(echo(&mut client, "short"), "short");
+        let stats = client.compression_stats().expect("Compression not enabled");
+        assert_eq!((stats.frames(), stats.compressed_frames()), (2, 0));
+
+        assert_eq!(echo(&mut client, &content), content);
+        let stats = client.compression_stats().expect("Compression not enabled");
+        assert_eq!((stats.frames(), stats.compressed_frames()), (4, 2));
+        assert!(
+            stats.wire_bytes() * 10 < stats.plain_bytes(),
+            "Compressed {} bytes to {}",
+            stats.plain_bytes(),
+            stats.wire_bytes()
+        );
+
+        assert!(client.disconnect().is_ok());
+    }
+
+    let stats = server.metrics();
+    assert_eq!(stats.compression().compressed_frames(), 4);
+    assert!(stats.compression().ratio() < 0.1);
+
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_compression_falls_back_to_plain_frames() {
+    let server = create_server_with_config(ServerConfig {
+        compression: vec![Compression::Lz4],
+        ..Default::default()
+    });
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+
+    // Nothing offered is enabled on the server
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(
+        client
+            .hello(&[Compression::Zstd])
+            .expect("Handshake failed"),
+        Compression::None
+    );
+    assert!(client.compression_stats().is_none());
+    let content = "z".repeat(10_000);
+    assert_eq!(echo(&mut client, &content), content);
+
+    // A second Hello is refused, the connection carries on uncompressed
+    let hello = client_message::Message::Hello(Hello {
+        compression: vec![Compression::Lz4 as i32],
+        ..test_client::default_hello()
+    });
+    assert!(client.send(hello).is_ok(), "Failed to send message");
+    expect_error(&mut client, ErrorCode::InvalidArgument);
+    assert_eq!(echo(&mut client, &content), content);
+    assert_eq!(server.metrics().compression().frames(), 0);
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_checksum_mismatch_is_reported() {
+    let server = create_server();
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let response = client
+        .hello_with(Hello {
+            capabilities: vec![Capability::RequestIds as i32, Capability::Checksums as i32],
+            ..test_client::default_hello()
+        })
+        .expect("Handshake failed");
+    assert!(response.capabilities().any(|c| c == Capability::Checksums));
+    assert_eq!(response.compression(), Compression::None);
+
+    // Flipping a bit in `b` would still decode, as a valid request for the wrong sum
+    let format = FrameFormat {
+        checksum: true,
+        ..Default::default()
+    };
+    let mut frame = codec::encode_frame_with(
+        &ClientMessage {
+            message: Some(client_message::Message::AddRequest(AddRequest {
+                a: 1,
+                b: 2,
+                ..Default::default()
+            })),
+            ..Default::default()
+        },
+        &format,
+    );
+    let last_body_byte = frame.len() - 5;
+    frame[last_body_byte] ^= 0x04;
+    assert!(client
+        .send_raw((frame.len() - 4) as u32, &frame[4..])
+        .is_ok());
+    expect_error(&mut client, ErrorCode::ChecksumMismatch);
+    assert_eq!(server.metrics().checksum_failures(), 1);
+
+    // The connection carries on, checksums and all
+    match add(
+        &mut client,
+        AddRequest {
+            a: 1,
+            b: 2,
+            ..Default::default()
+        },
+    ) {
+        server_message::Message::AddResponse(response) => assert_eq!(response.result, 3),
+        other => panic!("Expected AddResponse, but received {:?}", other),
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_checksums_can_be_disabled() {
+    let server = create_server_with_config(ServerConfig {
+        checksums: false,
+        ..Default::default()
+    });
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    let response = client
+        .hello_with(Hello {
+            capabilities: vec![Capability::RequestIds as i32, Capability::Checksums as i32],
+            ..test_client::default_hello()
+        })
+        .expect("Handshake failed");
+    assert_eq!(response.capabilities, vec![Capability::RequestIds as i32]);
+    assert_eq!(echo(&mut client, "unchecked"), "unchecked");
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_handshake_refuses_other_versions() {
+    let server = create_server();
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+
+    let stream = TcpStream::connect(("localhost", port)).expect("Failed to connect");
+    let mut client = test_client::TestClient::from_stream(stream);
+    let hello = client_message::Message::Hello(Hello {
+        protocol_version: PROTOCOL_VERSION + 1,
+        ..test_client::default_hello()
+    });
+    assert!(client.send(hello).is_ok(), "Failed to send message");
+    expect_error(&mut client, ErrorCode::UnsupportedVersion);
+    assert!(client.receive().is_err(), "Connection should be closed");
+
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_handshake_required() {
+    let server = create_server();
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+
+    let stream = TcpStream::connect(("localhost", port)).expect("Failed to connect");
+    let mut client = test_client::TestClient::from_stream(stream);
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "too early".to_string(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    expect_error(&mut client, ErrorCode::HandshakeRequired);
+    assert!(client.receive().is_err(), "Connection should be closed");
+
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_tagged_requests_in_order_without_request_ids() {
+    // The first request is slow enough to be overtaken, if it could be
+    let router = Router::empty().route(
+        MessageKind::Echo,
+        |_: &RequestContext<'_>, message: client_message::Message| match message {
+            client_message::Message::EchoMessage(echo) => {
+                if echo.content == "slow" {
+                    thread::sleep(Duration::from_millis(200));
+                }
+                Ok(server_message::Message::EchoMessage(echo))
+            }
+            _ => unreachable!("the router only sends echo requests here"),
+        },
+    );
+    let server = create_server_with_router(router);
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let ack = client
+        .hello_with(Hello {
+            capabilities: Vec::new(),
+            ..test_client::default_hello()
+        })
+        .expect("Handshake failed");
+    assert!(ack.capabilities.is_empty());
+
+    for (request_id, content) in [(1, "slow"), (2, "fast")] {
+        let message = client_message::Message::EchoMessage(EchoMessage {
+            content: content.to_string(),
+        });
+        assert!(
+            client.send_with_id(request_id, message).is_ok(),
+            "Failed to send message"
+        );
+    }
+    for request_id in [1, 2] {
+        let response = client.receive().expect("Failed to receive response");
+        assert_eq!(response.request_id, request_id);
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_ping_answered_with_pong() {
+    let server = create_server();
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    let ping = client_message::Message::Ping(Ping { nonce: 7 });
+    assert!(
+        client.send_with_id(3, ping).is_ok(),
+        "Failed to send message"
+    );
+    let response = client.receive().expect("Failed to receive response");
+    assert_eq!(response.request_id, 3);
+    match response.message {
+        Some(server_message::Message::Pong(pong)) => assert_eq!(pong.nonce, 7),
+        other => panic!("Expected Pong, but received {:?}", other),
+    }
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_idle_connections_reaped() {
+    let server = create_server_with_config(ServerConfig {
+        heartbeat_interval: None,
+        idle_timeout: Some(Duration::from_millis(200)),
+        ..Default::default()
+    });
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+
+    // One client never sends its Hello, the other goes quiet after a request
+    let mut silent = TcpStream::connect(("localhost", port)).expect("Failed to connect");
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(echo(&mut client, "still here"), "still here");
+
+    thread::sleep(Duration::from_millis(500));
+    assert!(client.receive().is_err(), "Connection should be closed");
+    assert!(codec::read_frame::<_, ServerMessage>(&mut silent, codec::MAX_FRAME_LENGTH).is_err());
+    assert_eq!(server.metrics().reaped_connections(ReapReason::Idle), 2);
+
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_heartbeat_detects_unresponsive_peer() {
+    let server = create_server_with_config(ServerConfig {
+        heartbeat_interval: Some(Duration::from_millis(100)),
+        idle_timeout: Some(Duration::from_millis(400)),
+        ..Default::default()
+    });
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut stream = TcpStream::connect(("localhost", port)).expect("Failed to connect");
+    test_client::handshake(&mut stream).expect("Handshake failed");
+
+    // Answering every Ping keeps the connection open well past the idle timeout
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_millis(1000) {
+        let message: ServerMessage = codec::read_frame(&mut stream, codec::MAX_FRAME_LENGTH)
+            .expect("Failed to receive Ping");
+        let nonce = match message.message {
+            Some(server_message::Message::Ping(ping)) => ping.nonce,
+            other => panic!("Expected Ping, but received {:?}", other),
+        };
+        let pong = ClientMessage {
+            message: Some(client_message::Message::Pong(Pong { nonce })),
+            ..Default::default()
+        };
+        codec::write_frame(&mut stream, &pong).expect("Failed to send Pong");
+    }
+    assert_eq!(
+        server
+            .metrics()
+            .reaped_connections(ReapReason::Unresponsive),
+        0
+    );
+
+    // A peer that stops answering is dropped once the idle timeout runs out
+    let message: ServerMessage =
+        codec::read_frame(&mut stream, codec::MAX_FRAME_LENGTH).expect("Failed to receive Ping");
+    assert!(matches!(
+        message.message,
+        Some(server_message::Message::Ping(_))
+    ));
+    assert!(codec::read_frame::<_, ServerMessage>(&mut stream, codec::MAX_FRAME_LENGTH).is_err());
+    assert_eq!(
+        server
+            .metrics()
+            .reaped_connections(ReapReason::Unresponsive),
+        1
+    );
+
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_incomplete_frame_reaped_after_read_timeout() {
+    let server = create_server_with_config(ServerConfig {
+        read_timeout: Some(Duration::from_millis(200)),
+        ..Default::default()
+    });
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut stream = TcpStream::connect(("localhost", port)).expect("Failed to connect");
+    test_client::handshake(&mut stream).expect("Handshake failed");
+
+    let frame = codec::encode_frame(&ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "never finished".to_string(),
+        })),
+        ..Default::default()
+    });
+    stream.write_all(&frame[..6]).unwrap();
+    assert!(codec::read_frame::<_, ServerMessage>(&mut stream, codec::MAX_FRAME_LENGTH).is_err());
+    assert_eq!(
+        server.metrics().reaped_connections(ReapReason::ReadTimeout),
+        1
+    );
+
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_tls_transport() {
+    let pki = test_client::TestPki::generate();
+    let server = create_server_with_config(ServerConfig {
+        tls: Some(pki.server_config(false)),
+        ..Default::default()
+    });
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+
+    let mut client =
+        test_client::TestClient::new("localhost", port, 1000).with_tls(pki.client_config(false));
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(echo(&mut client, "encrypted"), "encrypted");
+
+    // Concurrent replies share the one TLS session
+    for request_id in 1..=10u64 {
+        let message = client_message::Message::EchoMessage(EchoMessage {
+            content: request_id.to_string(),
+        });
+        assert!(client.send_with_id(request_id, message).is_ok());
+    }
+    let mut replies = HashMap::new();
+    for _ in 1..=10 {
+        let response = client.receive().expect("Failed to receive response");
+        if let Some(server_message::Message::EchoMessage(echo)) = response.message {
+            replies.insert(response.request_id, echo.content);
+        }
+    }
+    assert_eq!(replies.len(), 10);
+    assert_eq!(replies[&7], "7");
+    assert!(client.disconnect().is_ok());
+
+    // A plaintext client gets nowhere
+    let mut stream = TcpStream::connect(("localhost", port)).expect("Failed to connect");
+    assert!(test_client::handshake(&mut stream).is_err());
+
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_tls_client_certificate_required() {
+    let pki = test_client::TestPki::generate();
+    let server = create_server_with_config(ServerConfig {
+        tls: Some(pki.server_config(true)),
+        ..Default::default()
+    });
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+
+    let mut anonymous =
+        test_client::TestClient::new("localhost", port, 1000).with_tls(pki.client_config(false));
+    assert!(
+        anonymous.connect().is_ok(),
+        "Failed to connect to the server"
+    );
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "who am I".to_string(),
+    });
+    let _ = anonymous.send(message);
+    assert!(
+        anonymous.receive().is_err(),
+        "A client without a certificate should be refused"
+    );
+
+    let mut client =
+        test_client::TestClient::new("localhost", port, 1000).with_tls(pki.client_config(true));
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(echo(&mut client, "mutual"), "mutual");
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_tls_missing_key_fails_to_start() {
+    let pki = test_client::TestPki::generate();
+    let config = ServerConfig {
+        tls: Some(TlsConfig::new(
+            pki.dir.join("server.pem"),
+            pki.dir.join("missing.key"),
+        )),
+        ..Default::default()
+    };
+    let error = Server::with_config("localhost:0", config, Router::default())
+        .err()
+        .expect("Server should not start without its key");
+    assert!(error.to_string().contains("missing.key"), "{}", error);
+}
+
+/// Both ends of a TLS session over loopback TCP, handshake done
+fn tls_pair(pki: &test_client::TestPki) -> (TlsStream, TlsStream) {
+    let listener = TcpListener::bind("localhost:0").expect("Failed to bind");
+    let socket = TcpStream::connect(listener.local_addr().unwrap()).expect("Failed to connect");
+    let (accepted, _) = listener.accept().expect("Failed to accept");
+    let config = pki
+        .server_config(false)
+        .load()
+        .expect("Failed to load TLS config");
+    let name = rustls::pki_types::ServerName::try_from("localhost").unwrap();
+    let session = rustls::ClientConnection::new(pki.client_config(false), name).unwrap();
+    let mut client = TlsStream::new(socket, session);
+
+    // The handshake runs as part of the first reads on either end
+    let server = thread::spawn(move || {
+        let mut server = TlsStream::new(accepted, rustls::ServerConnection::new(config).unwrap());
+        let mut byte = [0u8; 1];
+        server
+            .read_exact(&mut byte)
+            .expect("Server handshake failed");
+        server.write_all(b"s").and_then(|_| server.flush()).unwrap();
+        server
+    });
+    client.write_all(b"c").and_then(|_| client.flush()).unwrap();
+    let mut byte = [0u8; 1];
+    client
+        .read_exact(&mut byte)
+        .expect("Client handshake failed");
+    let server = server.join().expect("Server handshake panicked");
+    (client, server)
+}
+
+#[test]
+fn test_tls_truncated_stream_is_detected() {
+    let pki = test_client::TestPki::generate();
+
+    // Closing without a close_notify cuts the stream short
+    let (mut client, mut server) = tls_pair(&pki);
+    server
+        .write_all(b"hello")
+        .and_then(|_| server.flush())
+        .unwrap();
+    drop(server);
+    let mut received = [0u8; 5];
+    client.read_exact(&mut received).expect("Failed to read");
+    assert_eq!(&received, b"hello");
+    let error = client
+        .read(&mut received)
+        .expect_err("Truncation went unnoticed");
+    assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+
+    // A close_notify ends it cleanly
+    let (mut client, server) = tls_pair(&pki);
+    Transport::Tls(server)
+        .shutdown(Shutdown::Write)
+        .expect("Failed to shut down");
+    assert_eq!(client.read(&mut received).expect("Failed to read"), 0);
+}
+
+#[test]
+fn test_tls_writer_does_not_block_reader() {
+    const LENGTH: usize = 8 * 1024 * 1024;
+    let pki = test_client::TestPki::generate();
+    let (client, server) = tls_pair(&pki);
+
+    // Both ends write more than the socket buffers hold before either reads a byte, so each
+    // writer blocks until the other end's reader gets to decrypt
+    let (done, finished) = mpsc::channel();
+    for mut end in [client, server] {
+        let done = done.clone();
+        thread::spawn(move || {
+            let mut reader = end.try_clone().expect("Failed to clone");
+            let writer = thread::spawn(move || {
+                end.write_all(&vec![7u8; LENGTH])
+                    .and_then(|_| end.flush())
+                    .expect("Failed to write");
+            });
+            let mut received = vec![0u8; LENGTH];
+            reader.read_exact(&mut received).expect("Failed to read");
+            writer.join().expect("Writer panicked");
+            let _ = done.send(received.iter().all(|&byte| byte == 7));
+        });
+    }
+    for _ in 0..2 {
+        let received = finished
+            .recv_timeout(Duration::from_secs(20))
+            .expect("TLS ends deadlocked");
+        assert!(received);
+    }
+}
+
+/// Echoes who the router says the client is
+fn whoami_router() -> Router {
+    Router::default().route(
+        MessageKind::Echo,
+        |context: &RequestContext<'_>, _: client_message::Message| {
+            let content = match context.peer_credentials {
+                Some(peer) => format!("uid {} pid {:?}", peer.uid, peer.pid),
+                None => "unknown".to_string(),
+            };
+            Ok(server_message::Message::EchoMessage(EchoMessage {
+                content,
+            }))
+        },
+    )
+}
+
+#[test]
+fn test_unix_socket_listener() {
+    let dir = std::env::temp_dir().join(format!("embedded-unix-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("server.sock");
+    let addr = UnixAddr::Path(path.clone());
+    let config = ServerConfig {
+        unix_socket_mode: Some(0o600),
+        ..Default::default()
+    };
+    let server = Arc::new(
+        Server::unix(addr.clone(), config, whoami_router()).expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let metadata = fs::metadata(&path).expect("Socket file missing");
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+    assert_eq!(
+        fs::read_dir(&dir).unwrap().count(),
+        1,
+        "Nothing but the socket should be left in its directory"
+    );
+
+    // A second server doesn't take over the socket of a running one
+    let second = Server::unix(addr.clone(), ServerConfig::default(), whoami_router());
+    assert_eq!(
+        second.err().map(|err| err.kind()),
+        Some(ErrorKind::AddrInUse)
+    );
+
+    // The kernel vouches for the client's identity
+    let mut client = test_client::TestClient::from_stream(addr.connect().unwrap());
+    assert!(client.hello(&[]).is_ok(), "Handshake failed");
+    assert_eq!(
+        echo(&mut client, "who am I"),
+        format!("uid {} pid Some({})", metadata.uid(), std::process::id())
+    );
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+    assert!(!path.exists(), "Socket file should be removed");
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_unix_socket_listener_replaces_only_stale_sockets() {
+    let dir = std::env::temp_dir().join(format!("embedded-unix-file-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("data");
+    fs::write(&path, "not a socket").unwrap();
+
+    // Whether or not the socket gets a mode of its own, the file isn't replaced
+    for unix_socket_mode in [None, Some(0o600)] {
+        let config = ServerConfig {
+            unix_socket_mode,
+            ..Default::default()
+        };
+        let server = Server::unix(UnixAddr::Path(path.clone()), config, whoami_router());
+        assert_eq!(
+            server.err().map(|err| err.kind()),
+            Some(ErrorKind::AddrInUse)
+        );
+        assert_eq!(fs::read_to_string(&path).unwrap(), "not a socket");
+    }
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+    // A socket nobody listens on any more is left over from an earlier run, and replaced
+    let stale = dir.join("stale.sock");
+    drop(std::os::unix::net::UnixListener::bind(&stale).unwrap());
+    let config = ServerConfig {
+        unix_socket_mode: Some(0o600),
+        ..Default::default()
+    };
+    let server = Server::unix(UnixAddr::Path(stale.clone()), config, whoami_router())
+        .expect("Failed to replace the stale socket");
+    assert!(UnixAddr::Path(stale).connect().is_ok());
+    drop(server);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_unix_abstract_socket_listener() {
+    let addr = UnixAddr::parse(&format!("@embedded-test-{}", std::process::id()));
+    assert!(matches!(addr, UnixAddr::Abstract(_)));
+    let server = Arc::new(
+        Server::unix(addr.clone(), ServerConfig::default(), Router::default())
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = test_client::TestClient::from_stream(addr.connect().unwrap());
+    assert!(client.hello(&[]).is_ok(), "Handshake failed");
+    assert_eq!(echo(&mut client, "abstract"), "abstract");
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_tcp_clients_have_no_peer_credentials() {
+    let server = create_server_with_router(whoami_router());
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    assert_eq!(echo(&mut client, "who am I"), "unknown");
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+/// Sends `request` to an admin listener and returns its answer
+fn admin(stream: &mut TcpStream, request: admin_request::Request) -> admin_response::Response {
+    let request = AdminRequest {
+        request: Some(request),
+    };
+    codec::write_frame(stream, &request).expect("Failed to send admin request");
+    codec::read_frame::<_, AdminResponse>(stream, codec::MAX_FRAME_LENGTH)
+        .expect("Failed to receive admin response")
+        .response
+        .expect("Admin response has no response set")
+}
+
+#[test]
+fn test_connection_registry() {
+    let server = create_server_with_config(ServerConfig {
+        admin_addr: Some("localhost:0".to_string()),
+        ..Default::default()
+    });
+    let port = server_port(&server);
+    let admin_addr = server.admin_addr().expect("Admin listener not running");
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(echo(&mut client, "one"), "one");
+    assert_eq!(echo(&mut client, "two"), "two");
+
+    // The reply can arrive before the server has counted it
+    let started = Instant::now();
+    let info = loop {
+        let connections = server.connections();
+        assert_eq!(connections.len(), 1);
+        if connections[0].messages_sent == 3 || started.elapsed() > Duration::from_secs(1) {
+            break connections[0].clone();
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    // The Hello, then the two echoes
+    assert_eq!(info.messages_received, 3);
+    assert_eq!(info.messages_sent, 3);
+    assert!(info.bytes_received > 0 && info.bytes_sent > 0);
+    assert!(info.peer_addr.is_some_and(|addr| addr.ip().is_loopback()));
+    assert!(info.last_activity >= info.connected_at);
+    assert_eq!(server.connection(info.id), Some(info.clone()));
+
+    let mut stream = TcpStream::connect(admin_addr).expect("Failed to connect to admin listener");
+    let id = info.id as u64;
+    match admin(
+        &mut stream,
+        admin_request::Request::ListConnections(ListConnections {}),
+    ) {
+        admin_response::Response::ConnectionList(list) => {
+            assert_eq!(list.connections.len(), 1);
+            assert_eq!(list.connections[0].client_id, id);
+            assert_eq!(list.connections[0].peer, info.peer());
+        }
+        other => panic!("Expected ConnectionList, but received {:?}", other),
+    }
+    match admin(
+        &mut stream,
+        admin_request::Request::InspectConnection(InspectConnection { client_id: id }),
+    ) {
+        admin_response::Response::Connection(connection) => {
+            assert_eq!(connection.client_id, id);
+            assert_eq!(connection.messages_received, 3);
+        }
+        other => panic!("Expected Connection, but received {:?}", other),
+    }
+    match admin(
+        &mut stream,
+        admin_request::Request::DisconnectClient(DisconnectClient { client_id: id }),
+    ) {
+        admin_response::Response::Disconnected(disconnected) => {
+            assert_eq!(disconnected.client_id, id)
+        }
+        other => panic!("Expected Disconnected, but received {:?}", other),
+    }
+
+    // The client sees its connection close, and the registry forgets it
+    assert!(client.receive().is_err());
+    let started = Instant::now();
+    while server.connection(info.id).is_some() && started.elapsed() < Duration::from_secs(1) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(server.connections().is_empty());
+    match admin(
+        &mut stream,
+        admin_request::Request::InspectConnection(InspectConnection { client_id: id }),
+    ) {
+        admin_response::Response::ErrorResponse(error) => {
+            assert_eq!(error.code, ErrorCode::NotFound as i32)
+        }
+        other => panic!("Expected ErrorResponse, but received {:?}", other),
+    }
+    assert!(!server.disconnect(info.id));
+
+    // An open admin connection doesn't hold up shutdown
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_admin_listener_requires_authentication_off_loopback() {
+    // Anyone could reach it, so it needs admin credentials. The clients' don't count, and
+    // bearer tokens alone would cross the network in cleartext.
+    for (auth, admin_auth) in [
+        (None, None),
+        (Some(AuthConfig::new().with_hmac_key("ops", b"key")), None),
+        (None, Some(AuthConfig::new().with_token("ops", "s3cret"))),
+    ] {
+        let open = ServerConfig {
+            admin_addr: Some("0.0.0.0:0".to_string()),
+            auth,
+            admin_auth,
+            ..Default::default()
+        };
+        let error = Server::with_config("localhost:0", open, Router::default())
+            .err()
+            .expect("Server started with an open admin listener");
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    // A bare port binds loopback
+    let server = create_server_with_config(ServerConfig {
+        admin_addr: Some("0".to_string()),
+        ..Default::default()
+    });
+    let admin_addr = server.admin_addr().expect("Admin listener not running");
+    assert!(admin_addr.ip().is_loopback());
+
+    let secret = b"admin secret";
+    let server = create_server_with_config(ServerConfig {
+        admin_addr: Some("0.0.0.0:0".to_string()),
+        auth: Some(AuthConfig::new().with_token("alice", "client-token")),
+        admin_auth: Some(
+            AuthConfig::new()
+                .with_token("ops", "s3cret")
+                .with_hmac_key("ops", secret),
+        ),
+        ..Default::default()
+    });
+    let admin_port = server
+        .admin_addr()
+        .expect("Admin listener not running")
+        .port();
+    let handle = setup_server_thread(server.clone());
+    let list = || admin_request::Request::ListConnections(ListConnections {});
+    let connect = || {
+        TcpStream::connect(("127.0.0.1", admin_port)).expect("Failed to connect to admin listener")
+    };
+    let expect_unauthenticated = |stream: &mut TcpStream, request| {
+        match admin(stream, request) {
+            admin_response::Response::ErrorResponse(error) => {
+                assert_eq!(error.code, ErrorCode::Unauthenticated as i32)
+            }
+            other => panic!("Expected ErrorResponse, but received {:?}", other),
+        }
+        assert!(codec::read_frame::<_, AdminResponse>(stream, codec::MAX_FRAME_LENGTH).is_err());
+    };
+    let bearer = |token: &str| {
+        admin_request::Request::AuthRequest(AuthRequest {
+            method: Some(auth_request::Method::BearerToken(token.to_string())),
+        })
+    };
+
+    // Nothing but authentication before authenticating
+    expect_unauthenticated(&mut connect(), list());
+    // Client credentials don't open the admin listener, and bearer tokens aren't taken here
+    expect_unauthenticated(&mut connect(), bearer("client-token"));
+    expect_unauthenticated(&mut connect(), bearer("s3cret"));
+
+    // With the admin HMAC key
+    let mut stream = connect();
+    let challenge = match admin(
+        &mut stream,
+        admin_request::Request::AuthRequest(AuthRequest {
+            method: Some(auth_request::Method::HmacPrincipal("ops".to_string())),
+        }),
+    ) {
+        admin_response::Response::AuthResponse(response) => response.challenge,
+        other => panic!("Expected AuthResponse, but received {:?}", other),
+    };
+    let proof = admin_request::Request::AuthRequest(AuthRequest {
+        method: Some(auth_request::Method::HmacProof(auth::sign_challenge(
+            secret, &challenge,
+        ))),
+    });
+    match admin(&mut stream, proof) {
+        admin_response::Response::AuthResponse(response) => assert_eq!(response.principal, "ops"),
+        other => panic!("Expected AuthResponse, but received {:?}", other),
+    }
+    assert!(matches!(
+        admin(&mut stream, list()),
+        admin_response::Response::ConnectionList(_)
+    ));
+
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+/// Sends an echo and returns the error it got, which has to be `RATE_LIMITED`
+fn expect_rate_limited(client: &mut test_client::TestClient) -> Duration {
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "too many".to_string(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    match client
+        .receive()
+        .expect("Failed to receive error reply")
+        .message
+    {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.code, ErrorCode::RateLimited as i32);
+            Duration::from_millis(error.retry_after_ms)
+        }
+        other => panic!("Expected ErrorResponse, but received {:?}", other),
+    }
+}
+
+#[test]
+fn test_rate_limit_rejects_with_retry_after() {
+    let server = create_server_with_config(ServerConfig {
+        rate_limits: RateLimitConfig {
+            per_connection: Budget {
+                messages: Some(RateLimit::new(5, 3)),
+                bytes: None,
+            },
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // The burst goes through, the next request has to wait for a token
+    for _ in 0..3 {
+        assert_eq!(echo(&mut client, "burst"), "burst");
+    }
+    let retry_after = expect_rate_limited(&mut client);
+    assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_millis(200));
+    assert_eq!(
+        server
+            .metrics()
+            .throttled_requests(RateLimitScope::Connection),
+        1
+    );
+
+    // The connection stays open and the hint is good
+    thread::sleep(retry_after);
+    assert_eq!(echo(&mut client, "again"), "again");
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_rate_limit_shared_by_peer_ip() {
+    let server = create_server_with_config(ServerConfig {
+        rate_limits: RateLimitConfig {
+            per_peer_ip: Budget {
+                messages: None,
+                bytes: Some(RateLimit::new(1, 64)),
+            },
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut first = test_client::TestClient::new("localhost", port, 1000);
+    let mut second = test_client::TestClient::new("localhost", port, 1000);
+    assert!(first.connect().is_ok(), "Failed to connect to the server");
+    assert!(second.connect().is_ok(), "Failed to connect to the server");
+
+    // The first client spends the address's byte budget, the second one is turned away
+    assert_eq!(echo(&mut first, &"x".repeat(60)), "x".repeat(60));
+    expect_rate_limited(&mut second);
+    let metrics = server.metrics();
+    assert_eq!(metrics.throttled_requests(RateLimitScope::PeerIp), 1);
+    assert_eq!(metrics.throttled_requests(RateLimitScope::Connection), 0);
+
+    // Reconnecting doesn't get the address a fresh budget
+    assert!(first.disconnect().is_ok());
+    assert!(second.disconnect().is_ok());
+    let started = Instant::now();
+    while !server.connections().is_empty() && started.elapsed() < Duration::from_secs(1) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let mut third = test_client::TestClient::new("localhost", port, 1000);
+    assert!(third.connect().is_ok(), "Failed to connect to the server");
+    expect_rate_limited(&mut third);
+    assert_eq!(metrics.throttled_requests(RateLimitScope::PeerIp), 2);
+
+    assert!(third.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_rate_limit_delays_requests() {
+    let server = create_server_with_config(ServerConfig {
+        rate_limits: RateLimitConfig {
+            global: Budget {
+                messages: Some(RateLimit::new(20, 1)),
+                bytes: None,
+            },
+            throttle: Throttle::Delay,
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Every request is answered, at the configured pace
+    let started = Instant::now();
+    for i in 0..5 {
+        assert_eq!(echo(&mut client, &i.to_string()), i.to_string());
+    }
+    assert!(started.elapsed() >= Duration::from_millis(180));
+    assert!(server.metrics().throttled_requests(RateLimitScope::Global) >= 3);
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_connection_caps_reject_with_server_busy() {
+    let server = create_server_with_config(ServerConfig {
+        max_connections: Some(2),
+        max_connections_per_ip: Some(1),
+        ..Default::default()
+    });
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+
+    let mut client1 = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client1.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(echo(&mut client1, "first"), "first");
+
+    // Every test client connects from the same address
+    let mut client2 = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client2.connect().is_ok(), "Failed to connect to the server");
+    expect_error(&mut client2, ErrorCode::ServerBusy);
+    assert_eq!(server.metrics().rejected_connections(), 1);
+
+    // Closing the first connection makes room again
+    assert!(client1.disconnect().is_ok());
+    let started = Instant::now();
+    while !server.connections().is_empty() && started.elapsed() < Duration::from_secs(1) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let mut client3 = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client3.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(echo(&mut client3, "third"), "third");
+
+    assert!(client3.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_connection_cap_leaves_connections_in_backlog() {
+    let server = create_server_with_config(ServerConfig {
+        max_connections: Some(1),
+        connection_cap_policy: ConnectionCapPolicy::Backlog,
+        ..Default::default()
+    });
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+
+    let mut client1 = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client1.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(echo(&mut client1, "first"), "first");
+
+    // The second client waits in the backlog until the first one leaves
+    let mut client2 = test_client::TestClient::new("localhost", port, 3000);
+    assert!(client2.connect().is_ok(), "Failed to connect to the server");
+    let started = Instant::now();
+    let leaver = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(300));
+        assert!(client1.disconnect().is_ok());
+    });
+    assert_eq!(echo(&mut client2, "second"), "second");
+    assert!(started.elapsed() >= Duration::from_millis(300));
+    assert_eq!(server.metrics().rejected_connections(), 0);
+    leaver.join().unwrap();
+
+    // A stop while at the cap still gets through
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_access_list_denies_connections() {
+    let loopback = vec!["127.0.0.0/8".parse().unwrap(), "::1/128".parse().unwrap()];
+    let server = create_server_with_config(ServerConfig {
+        access: AccessList {
+            allow: loopback.clone(),
+            deny: loopback,
+        },
+        ..Default::default()
+    });
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+
+    // Closed without a reply
+    let mut stream = TcpStream::connect(("localhost", port)).expect("Failed to connect");
+    assert!(test_client::handshake(&mut stream).is_err());
+    assert_eq!(server.metrics().denied_connections(), 1);
+    assert!(server.connections().is_empty());
+
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+
+    let only_elsewhere = AccessList {
+        allow: vec!["10.0.0.0/8".parse().unwrap()],
+        deny: Vec::new(),
+    };
+    assert!(!only_elsewhere.permits("127.0.0.1".parse().unwrap()));
+    assert!(only_elsewhere.permits("10.1.2.3".parse().unwrap()));
+    // IPv4 clients of a dual-stack listener show up as mapped IPv6 addresses
+    assert!(only_elsewhere.permits("::ffff:10.1.2.3".parse().unwrap()));
+}
+
+/// Echoes who the client authenticated as
+fn principal_router() -> Router {
+    Router::default().route(
+        MessageKind::Echo,
+        |context: &RequestContext<'_>, _: client_message::Message| {
+            let content = context.principal.unwrap_or("nobody").to_string();
+            Ok(server_message::Message::EchoMessage(EchoMessage {
+                content,
+            }))
+        },
+    )
+}
+
+fn create_auth_server(auth: AuthConfig) -> Arc<Server> {
+    let config = ServerConfig {
+        auth: Some(auth),
+        ..Default::default()
+    };
+    Arc::new(
+        Server::with_config("localhost:0", config, principal_router())
+            .expect("Failed to start server"),
+    )
+}
+
+/// Sends an `AuthRequest` and returns the reply
+fn authenticate(
+    client: &mut test_client::TestClient,
+    method: auth_request::Method,
+) -> server_message::Message {
+    let message = client_message::Message::AuthRequest(AuthRequest {
+        method: Some(method),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    client
+        .receive()
+        .expect("Failed to receive response")
+        .message
+        .expect("Response has no message set")
+}
+
+#[test]
+fn test_bearer_token_authentication() {
+    let dir = std::env::temp_dir().join(format!("embedded-auth-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("tokens");
+    fs::write(&path, "# principal token\nalice s3cret\n\nbob hunter2\n").unwrap();
+    let auth = AuthConfig::new()
+        .load_tokens(&path)
+        .expect("Failed to load tokens");
+    let _ = fs::remove_dir_all(&dir);
+    let server = create_auth_server(auth);
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+
+    // Nothing but the handshake before authenticating
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "anonymous".to_string(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    expect_error(&mut client, ErrorCode::Unauthenticated);
+    assert!(client.receive().is_err(), "Connection should be closed");
+
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let token = auth_request::Method::BearerToken("s3cret".to_string());
+    match authenticate(&mut client, token) {
+        server_message::Message::AuthResponse(response) => {
+            assert_eq!(response.principal, "alice")
+        }
+        other => panic!("Expected AuthResponse, but received {:?}", other),
+    }
+    assert_eq!(echo(&mut client, "who am I"), "alice");
+    assert!(client.disconnect().is_ok());
+
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let token = auth_request::Method::BearerToken("s3cre7".to_string());
+    match authenticate(&mut client, token) {
+        server_message::Message::ErrorResponse(error) => {
+            assert_eq!(error.code, ErrorCode::Unauthenticated as i32)
+        }
+        other => panic!("Expected ErrorResponse, but received {:?}", other),
+    }
+
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_hmac_challenge_authentication() {
+    let secret = b"shared secret";
+    let server = create_auth_server(AuthConfig::new().with_hmac_key("carol", secret));
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let challenge = match authenticate(
+        &mut client,
+        auth_request::Method::HmacPrincipal("carol".to_string()),
+    ) {
+        server_message::Message::AuthResponse(response) => {
+            assert!(response.principal.is_empty());
+            response.challenge
+        }
+        other => panic!("Expected AuthResponse, but received {:?}", other),
+    };
+    assert_eq!(challenge.len(), auth::CHALLENGE_LENGTH);
+    let proof = auth::sign_challenge(secret, &challenge);
+    match authenticate(&mut client, auth_request::Method::HmacProof(proof)) {
+        server_message::Message::AuthResponse(response) => {
+            assert_eq!(response.principal, "carol")
+        }
+        other => panic!("Expected AuthResponse, but received {:?}", other),
+    }
+    assert_eq!(echo(&mut client, "who am I"), "carol");
+    assert!(client.disconnect().is_ok());
+
+    // The wrong secret, or a proof without a challenge, gets nowhere
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let challenge = match authenticate(
+        &mut client,
+        auth_request::Method::HmacPrincipal("carol".to_string()),
+    ) {
+        server_message::Message::AuthResponse(response) => response.challenge,
+        other => panic!("Expected AuthResponse, but received {:?}", other),
+    };
+    let proof = auth::sign_challenge(b"guessed secret", &challenge);
+    match authenticate(&mut client, auth_request::Method::HmacProof(proof)) {
+        server_message::Message::ErrorResponse(error) => {
+            assert_eq!(error.code, ErrorCode::Unauthenticated as i32)
+        }
+        other => panic!("Expected ErrorResponse, but received {:?}", other),
+    }
+
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let proof = auth::sign_challenge(secret, b"no challenge");
+    match authenticate(&mut client, auth_request::Method::HmacProof(proof)) {
+        server_message::Message::ErrorResponse(error) => {
+            assert_eq!(error.code, ErrorCode::Unauthenticated as i32)
+        }
+        other => panic!("Expected ErrorResponse, but received {:?}", other),
+    }
+
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_heartbeat_answered_before_authenticating() {
+    let secret = b"shared secret";
+    let config = ServerConfig {
+        auth: Some(AuthConfig::new().with_hmac_key("carol", secret)),
+        heartbeat_interval: Some(Duration::from_millis(100)),
+        ..Default::default()
+    };
+    let server = Arc::new(
+        Server::with_config("localhost:0", config, principal_router())
+            .expect("Failed to start server"),
+    );
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+
+    // Answers the server's Ping, as every client has to
+    let answer_ping = |client: &mut test_client::TestClient| {
+        let nonce = match client.receive().expect("Failed to receive Ping").message {
+            Some(server_message::Message::Ping(ping)) => ping.nonce,
+            other => panic!("Expected Ping, but received {:?}", other),
+        };
+        let pong = client_message::Message::Pong(Pong { nonce });
+        assert!(client.send(pong).is_ok(), "Failed to send Pong");
+    };
+
+    // Pinged before signing in, and again between the challenge and its proof
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert!(client.hello(&[]).is_ok(), "Handshake failed");
+    answer_ping(&mut client);
+    let challenge = match authenticate(
+        &mut client,
+        auth_request::Method::HmacPrincipal("carol".to_string()),
+    ) {
+        server_message::Message::AuthResponse(response) => response.challenge,
+        other => panic!("Expected AuthResponse, but received {:?}", other),
+    };
+    answer_ping(&mut client);
+    let proof = auth::sign_challenge(secret, &challenge);
+    match authenticate(&mut client, auth_request::Method::HmacProof(proof)) {
+        server_message::Message::AuthResponse(response) => {
+            assert_eq!(response.principal, "carol")
+        }
+        other => panic!("Expected AuthResponse, but received {:?}", other),
+    }
+    assert_eq!(echo(&mut client, "who am I"), "carol");
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_hello_ack_says_authentication_required() {
+    let server = create_auth_server(AuthConfig::new().with_token("dave", "token"));
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+
+    let mut stream = TcpStream::connect(("localhost", port)).expect("Failed to connect");
+    let ack = test_client::handshake(&mut stream).expect("Handshake failed");
+    assert!(ack.authentication_required);
+    drop(stream);
+
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+
+    // Without credentials configured, nobody authenticates and everybody is served
+    let server = create_server_with_router(principal_router());
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+    let mut stream = TcpStream::connect(("localhost", port)).expect("Failed to connect");
+    let ack = test_client::handshake(&mut stream).expect("Handshake failed");
+    assert!(!ack.authentication_required);
+    let mut client = test_client::TestClient::from_stream(stream);
+    assert_eq!(echo(&mut client, "who am I"), "nobody");
+
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_unauthenticated_clients_are_screened_and_charged() {
+    let server = Arc::new(
+        Server::with_config(
+            "localhost:0",
+            ServerConfig {
+                auth: Some(AuthConfig::new().with_token("alice", "s3cret")),
+                rate_limits: RateLimitConfig {
+                    per_connection: Budget {
+                        messages: Some(RateLimit::new(1, 2)),
+                        bytes: None,
+                    },
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            principal_router(),
+        )
+        .expect("Failed to start server"),
+    );
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+
+    // Not even a Ping before authenticating
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let ping = client_message::Message::Ping(Ping { nonce: 7 });
+    assert!(client.send(ping.clone()).is_ok(), "Failed to send message");
+    expect_error(&mut client, ErrorCode::Unauthenticated);
+    assert!(client.receive().is_err(), "Connection should be closed");
+
+    // The AuthRequest and Pings spend the budget like any request
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let token = auth_request::Method::BearerToken("s3cret".to_string());
+    assert!(matches!(
+        authenticate(&mut client, token),
+        server_message::Message::AuthResponse(_)
+    ));
+    assert!(client.send(ping.clone()).is_ok(), "Failed to send message");
+    assert!(matches!(
+        client
+            .receive()
+            .expect("Failed to receive response")
+            .message,
+        Some(server_message::Message::Pong(Pong { nonce: 7 }))
+    ));
+    assert!(client.send(ping).is_ok(), "Failed to send message");
+    expect_error(&mut client, ErrorCode::RateLimited);
+
+    assert!(client.disconnect().is_ok());
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_policy_restricts_operations() {
+    let dir = std::env::temp_dir().join(format!("embedded-policy-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("policy");
+    let audit_path = dir.join("audit.log");
+    fs::write(
+        &path,
+        format!(
+            "# Everyone may echo short messages\n\
+             role public echo max_request_size=64\n\
+             role calculator echo add arithmetic\n\
+             \n\
+             grant alice calculator\n\
+             default public\n\
+             audit {}\n",
+            audit_path.display()
+        ),
+    )
+    .unwrap();
+    let policy = Policy::load(&path).expect("Failed to load policy");
+    let config = ServerConfig {
+        auth: Some(
+            AuthConfig::new()
+                .with_token("alice", "s3cret")
+                .with_token("bob", "hunter2"),
+        ),
+        policy: Some(policy),
+        ..Default::default()
+    };
+    let server = create_server_with_config(config);
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+
+    // Bob only has the default role
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    authenticate(
+        &mut client,
+        auth_request::Method::BearerToken("hunter2".to_string()),
+    );
+    assert_eq!(echo(&mut client, "short"), "short");
+    let request = AddRequest {
+        a: 1,
+        b: 2,
+        ..Default::default()
+    };
+    match add(&mut client, request) {
+        server_message::Message::ErrorResponse(error) => {
+            assert_eq!(error.code, ErrorCode::PermissionDenied as i32)
+        }
+        other => panic!("Expected ErrorResponse, but received {:?}", other),
+    }
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "x".repeat(100),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    expect_error(&mut client, ErrorCode::PermissionDenied);
+    // Denials leave the connection open
+    assert_eq!(echo(&mut client, "still here"), "still here");
+    assert!(client.disconnect().is_ok());
+
+    // Alice's role lets her add, and echo at any size
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    authenticate(
+        &mut client,
+        auth_request::Method::BearerToken("s3cret".to_string()),
+    );
+    match add(&mut client, request) {
+        server_message::Message::AddResponse(response) => assert_eq!(response.result, 3),
+        other => panic!("Expected AddResponse, but received {:?}", other),
+    }
+    let long = "x".repeat(100);
+    assert_eq!(echo(&mut client, &long), long);
+    assert!(client.disconnect().is_ok());
+
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+
+    let audit = fs::read_to_string(&audit_path).expect("Failed to read audit log");
+    let entries: Vec<_> = audit.lines().collect();
+    assert_eq!(entries.len(), 2, "Unexpected audit log: {}", audit);
+    assert!(entries[0].contains("client=") && entries[0].contains("principal=bob"));
+    assert!(entries[0].contains("operation=add denied"));
+    assert!(entries[1].contains("operation=echo denied"));
+
+    fs::write(&path, "role public echo\ngrant alice calculator\n").unwrap();
+    let error = Policy::load(&path).expect_err("Undefined roles can't be granted");
+    assert!(error.to_string().contains("role calculator isn't defined"));
+    fs::write(&path, "role public echo subtract\n").unwrap();
+    let error = Policy::load(&path).expect_err("Unknown operations can't be allowed");
+    assert!(error
+        .to_string()
+        .contains("line 1: unknown operation subtract"));
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_policy_role_overrides_rate_limit() {
+    let anonymous = Role::new([MessageKind::Echo]).with_rate(Budget {
+        messages: Some(RateLimit::new(1, 1)),
+        bytes: None,
+    });
+    let server = create_server_with_config(ServerConfig {
+        policy: Some(
+            Policy::new()
+                .with_role("anonymous", anonymous)
+                .with_default_roles(["anonymous"]),
+        ),
+        ..Default::default()
+    });
+    let port = server_port(&server);
+    let handle = setup_server_thread(server.clone());
+
+    // Without authentication every client is anonymous
+    let mut client = test_client::TestClient::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(echo(&mut client, "first"), "first");
+    assert!(!expect_rate_limited(&mut client).is_zero());
+    assert_eq!(
+        server
+            .metrics()
+            .throttled_requests(RateLimitScope::Connection),
+        1
+    );
+
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
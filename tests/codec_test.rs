@@ -0,0 +1,298 @@
+use bytes::BytesMut;
+use embedded_recruitment_task::{
+    codec::{
+        self, FrameCodec, FrameError, FrameFormat, CHECKSUM_LENGTH, COMPRESSION_FLAG,
+        LENGTH_PREFIX, MAX_FRAME_LENGTH,
+    },
+    compression::{CompressionStats, FrameCompression},
+    error::ProtocolError,
+    message::{
+        client_message, server_message, AddResponse, ClientMessage, Compression, EchoMessage,
+        ErrorCode, ServerMessage,
+    },
+};
+use prost::Message;
+use std::{
+    io::{self, Cursor},
+    sync::Arc,
+};
+use tokio_util::codec::{Decoder, Encoder};
+
+fn echo_request(content: &str) -> ClientMessage {
+    ClientMessage {
+        request_id: 7,
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: content.to_string(),
+        })),
+    }
+}
+
+#[test]
+fn test_encode_then_decode_frame() {
+    let request = echo_request("Hello, World!");
+    let frame = codec::encode_frame(&request);
+    let length = u32::from_be_bytes(frame[..LENGTH_PREFIX].try_into().unwrap()) as usize;
+    assert_eq!(length, frame.len() - LENGTH_PREFIX);
+
+    // Nothing comes out until the last byte is in
+    let mut buffer = BytesMut::from(&frame[..frame.len() - 1]);
+    assert!(
+        codec::decode_frame::<ClientMessage>(&mut buffer, MAX_FRAME_LENGTH)
+            .unwrap()
+            .is_none()
+    );
+    buffer.extend_from_slice(&frame[frame.len() - 1..]);
+    let decoded = codec::decode_frame::<ClientMessage>(&mut buffer, MAX_FRAME_LENGTH).unwrap();
+    assert_eq!(decoded, Some(request));
+    assert!(buffer.is_empty());
+}
+
+#[test]
+fn test_tokio_codec_round_trip() {
+    let mut frame_codec = FrameCodec::<ServerMessage>::new();
+    let responses = [
+        ServerMessage {
+            request_id: 1,
+            message: Some(server_message::Message::AddResponse(AddResponse {
+                result: 3,
+            })),
+        },
+        ServerMessage::from(ProtocolError::new(ErrorCode::ServerBusy, "busy")),
+    ];
+
+    let mut buffer = BytesMut::new();
+    for response in responses.clone() {
+        frame_codec.encode(response, &mut buffer).unwrap();
+    }
+    for response in responses {
+        assert_eq!(frame_codec.decode(&mut buffer).unwrap(), Some(response));
+    }
+    assert_eq!(frame_codec.decode(&mut buffer).unwrap(), None);
+}
+
+#[test]
+fn test_frame_errors() {
+    let mut frame_codec = FrameCodec::<ClientMessage>::with_max_length(16);
+
+    let mut buffer = BytesMut::from(&0u32.to_be_bytes()[..]);
+    let error = frame_codec.decode(&mut buffer).unwrap_err();
+    assert!(matches!(error, FrameError::Empty));
+    assert!(error.is_recoverable());
+    assert_eq!(ProtocolError::from(error).code, ErrorCode::MalformedMessage);
+
+    let mut buffer = BytesMut::from(&codec::encode_frame(&echo_request(&"x".repeat(32)))[..]);
+    let error = frame_codec.decode(&mut buffer).unwrap_err();
+    assert!(matches!(error, FrameError::TooLarge { max_length: 16, .. }));
+    assert!(!error.is_recoverable());
+    assert_eq!(ProtocolError::from(error).code, ErrorCode::FrameTooLarge);
+
+    let mut buffer = BytesMut::from(&[0, 0, 0, 3, 0xff, 0xff, 0xff][..]);
+    let error = frame_codec.decode(&mut buffer).unwrap_err();
+    assert!(matches!(error, FrameError::Decode(_)));
+    assert!(error.is_recoverable());
+    assert!(buffer.is_empty(), "The undecodable body wasn't consumed");
+}
+
+#[test]
+fn test_read_and_write_frames() {
+    let mut stream = Vec::new();
+    codec::write_frame(&mut stream, &echo_request("first")).unwrap();
+    codec::write_frame(&mut stream, &echo_request("second")).unwrap();
+
+    let mut reader = Cursor::new(stream);
+    for content in ["first", "second"] {
+        let request: ClientMessage = codec::read_frame(&mut reader, MAX_FRAME_LENGTH).unwrap();
+        assert_eq!(request, echo_request(content));
+    }
+
+    // A body cut short by the end of the stream is a transport error
+    let frame = codec::encode_frame(&echo_request("truncated"));
+    let mut reader = Cursor::new(&frame[..frame.len() - 2]);
+    match codec::read_frame::<_, ClientMessage>(&mut reader, MAX_FRAME_LENGTH) {
+        Err(FrameError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+        other => panic!("Expected an I/O error, but got {:?}", other),
+    }
+}
+
+fn compressed(algorithm: Compression, stats: &Arc<CompressionStats>) -> FrameFormat {
+    FrameFormat {
+        compression: Some(FrameCompression::new(algorithm, 64, stats.clone())),
+        checksum: false,
+    }
+}
+
+#[test]
+fn test_compressed_frames() {
+    let stats = Arc::new(CompressionStats::default());
+    let zstd = compressed(Compression::Zstd, &stats);
+
+    // Short bodies are flagged as uncompressed, long ones as zstd
+    let short = codec::encode_frame_with(&echo_request("short"), &zstd);
+    assert_eq!(short[LENGTH_PREFIX], Compression::None as u8);
+    assert_eq!(
+        short.len(),
+        LENGTH_PREFIX + COMPRESSION_FLAG + echo_request("short").encoded_len()
+    );
+    let long = codec::encode_frame_with(&echo_request(&"x".repeat(4096)), &zstd);
+    assert_eq!(long[LENGTH_PREFIX], Compression::Zstd as u8);
+    assert!(long.len() < 256);
+
+    let mut frame_codec = FrameCodec::<ClientMessage>::new();
+    frame_codec.set_format(zstd.clone());
+    let mut buffer = BytesMut::from(&short[..]);
+    buffer.extend_from_slice(&long);
+    assert_eq!(
+        frame_codec.decode(&mut buffer).unwrap(),
+        Some(echo_request("short"))
+    );
+    assert_eq!(
+        frame_codec.decode(&mut buffer).unwrap(),
+        Some(echo_request(&"x".repeat(4096)))
+    );
+    assert_eq!((stats.frames(), stats.compressed_frames()), (4, 2));
+
+    // Only the agreed algorithm is accepted
+    let lz4 = compressed(Compression::Lz4, &stats);
+    let mut buffer = BytesMut::from(&long[..]);
+    let error =
+        codec::decode_frame_with::<ClientMessage>(&mut buffer, MAX_FRAME_LENGTH, &lz4).unwrap_err();
+    assert!(matches!(error, FrameError::Compression(_)));
+    assert!(error.is_recoverable());
+
+    // A body that would inflate past the limit is refused
+    let mut reader = Cursor::new(codec::encode_frame_with(
+        &echo_request(&"y".repeat(4096)),
+        &lz4,
+    ));
+    let error = codec::read_frame_with::<_, ClientMessage>(&mut reader, 1024, &lz4).unwrap_err();
+    assert!(matches!(error, FrameError::TooLarge { length, .. } if length > 4096));
+}
+
+/// A frame whose body is `wire`, flagged as compressed with `algorithm`
+fn raw_compressed_frame(algorithm: Compression, wire: &[u8]) -> BytesMut {
+    let mut frame = BytesMut::new();
+    frame.extend_from_slice(&((COMPRESSION_FLAG + wire.len()) as u32).to_be_bytes());
+    frame.extend_from_slice(&[algorithm as u8]);
+    frame.extend_from_slice(wire);
+    frame
+}
+
+#[test]
+fn test_compressed_bodies_of_every_shape() {
+    let stats = Arc::new(CompressionStats::default());
+    let content: String = (0..20_000u32)
+        .map(|i| match i % 7 {
+            0 => char::from(b'a' + (i * 31 % 26) as u8),
+            _ => char::from(b'a' + (i / 500 % 26) as u8),
+        })
+        .collect();
+    for algorithm in [Compression::Zstd, Compression::Lz4] {
+        let format = compressed(algorithm, &stats);
+        for length in [64, 65, 300, 4096, 20_000] {
+            let request = echo_request(&content[..length]);
+            let mut buffer = BytesMut::from(&codec::encode_frame_with(&request, &format)[..]);
+            assert_eq!(
+                codec::decode_frame_with(&mut buffer, MAX_FRAME_LENGTH, &format).unwrap(),
+                Some(request)
+            );
+        }
+    }
+}
+
+#[test]
+fn test_compressed_bodies_claiming_more_than_they_hold() {
+    let stats = Arc::new(CompressionStats::default());
+
+    // A few bytes claiming a 1 MiB body are refused once they run out
+    let lz4 = compressed(Compression::Lz4, &stats);
+    let mut wire = (1u32 << 20).to_le_bytes().to_vec();
+    wire.extend_from_slice(&[0x10, b'a']);
+    let mut buffer = raw_compressed_frame(Compression::Lz4, &wire);
+    let error =
+        codec::decode_frame_with::<ClientMessage>(&mut buffer, MAX_FRAME_LENGTH, &lz4).unwrap_err();
+    assert!(matches!(error, FrameError::Compression(_)), "{:?}", error);
+    // So are matches reaching back before the start of the body
+    let mut wire = 8u32.to_le_bytes().to_vec();
+    wire.extend_from_slice(&[0x14, b'a', 0x09, 0x00, 0x00]);
+    let mut buffer = raw_compressed_frame(Compression::Lz4, &wire);
+    let error =
+        codec::decode_frame_with::<ClientMessage>(&mut buffer, MAX_FRAME_LENGTH, &lz4).unwrap_err();
+    assert!(matches!(error, FrameError::Compression(_)), "{:?}", error);
+
+    let zstd = compressed(Compression::Zstd, &stats);
+    let whole = zstd::bulk::compress(&vec![0u8; 1 << 20], 0).unwrap();
+    let mut buffer = raw_compressed_frame(Compression::Zstd, &whole[..whole.len() / 2]);
+    let error = codec::decode_frame_with::<ClientMessage>(&mut buffer, MAX_FRAME_LENGTH, &zstd)
+        .unwrap_err();
+    assert!(matches!(error, FrameError::Compression(_)), "{:?}", error);
+
+    // A header asking for a window longer than the limit is refused before inflating
+    let mut buffer = raw_compressed_frame(Compression::Zstd, &whole);
+    let error = codec::decode_frame_with::<ClientMessage>(&mut buffer, 1024, &zstd).unwrap_err();
+    assert!(matches!(error, FrameError::Compression(_)), "{:?}", error);
+
+    // One that fits the window but inflates past the limit is cut off there
+    let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), 0).unwrap();
+    encoder.window_log(10).unwrap();
+    encoder.include_contentsize(false).unwrap();
+    io::Write::write_all(&mut encoder, &[0u8; 4096]).unwrap();
+    let wire = encoder.finish().unwrap();
+    let mut buffer = raw_compressed_frame(Compression::Zstd, &wire);
+    let error = codec::decode_frame_with::<ClientMessage>(&mut buffer, 1024, &zstd).unwrap_err();
+    assert!(
+        matches!(
+            error,
+            FrameError::TooLarge {
+                max_length: 1024,
+                ..
+            }
+        ),
+        "{:?}",
+        error
+    );
+}
+
+#[test]
+fn test_checksummed_frames() {
+    let checksummed = FrameFormat {
+        checksum: true,
+        ..Default::default()
+    };
+    let frame = codec::encode_frame_with(&echo_request("checked"), &checksummed);
+    assert_eq!(
+        frame.len(),
+        LENGTH_PREFIX + echo_request("checked").encoded_len() + CHECKSUM_LENGTH
+    );
+    let mut buffer = BytesMut::from(&frame[..]);
+    assert_eq!(
+        codec::decode_frame_with(&mut buffer, MAX_FRAME_LENGTH, &checksummed).unwrap(),
+        Some(echo_request("checked"))
+    );
+
+    // Any flipped bit is caught, including one that would still decode
+    for index in LENGTH_PREFIX..frame.len() {
+        let mut corrupted = frame.clone();
+        corrupted[index] ^= 0x10;
+        let mut buffer = BytesMut::from(&corrupted[..]);
+        let error =
+            codec::decode_frame_with::<ClientMessage>(&mut buffer, MAX_FRAME_LENGTH, &checksummed)
+                .unwrap_err();
+        assert!(matches!(error, FrameError::Checksum { .. }));
+        assert!(error.is_recoverable());
+        assert!(buffer.is_empty(), "The corrupted frame wasn't consumed");
+        assert_eq!(ProtocolError::from(error).code, ErrorCode::ChecksumMismatch);
+    }
+
+    // Checksums and compression combine, the checksum covering the compressed bytes
+    let stats = Arc::new(CompressionStats::default());
+    let both = FrameFormat {
+        checksum: true,
+        ..compressed(Compression::Lz4, &stats)
+    };
+    let request = echo_request(&"z".repeat(4096));
+    let mut reader = Cursor::new(codec::encode_frame_with(&request, &both));
+    let decoded: ClientMessage =
+        codec::read_frame_with(&mut reader, MAX_FRAME_LENGTH, &both).unwrap();
+    assert_eq!(decoded, request);
+    assert_eq!(stats.compressed_frames(), 2);
+}
@@ -0,0 +1,232 @@
+use embedded_recruitment_task::{
+    codec::{self, MAX_FRAME_LENGTH},
+    frame_decoder::FrameDecoder,
+    memory::MemoryBudget,
+    message::{client_message, AddRequest, ClientMessage, EchoMessage, ErrorCode},
+};
+use std::{
+    io::{self, Read},
+    sync::Arc,
+};
+
+fn sample_messages() -> Vec<ClientMessage> {
+    vec![
+        ClientMessage {
+            request_id: 1,
+            message: Some(client_message::Message::EchoMessage(EchoMessage {
+                content: "Hello, World!".to_string(),
+            })),
+        },
+        ClientMessage {
+            request_id: 0,
+            message: Some(client_message::Message::AddRequest(AddRequest {
+                a: -7,
+                b: 300,
+                ..Default::default()
+            })),
+        },
+        ClientMessage {
+            request_id: 3,
+            message: Some(client_message::Message::EchoMessage(EchoMessage {
+                content: "x".repeat(1000),
+            })),
+        },
+    ]
+}
+
+fn drain(decoder: &mut FrameDecoder, decoded: &mut Vec<ClientMessage>) {
+    while let Some(message) = decoder.next_frame() {
+        decoded.push(message.expect("Failed to decode frame"));
+    }
+}
+
+#[test]
+fn test_split_at_every_offset() {
+    let messages = sample_messages();
+    let stream: Vec<u8> = messages.iter().flat_map(codec::encode_frame).collect();
+
+    for split in 0..=stream.len() {
+        let mut decoder = FrameDecoder::new();
+        let mut decoded = Vec::new();
+
+        decoder.extend(&stream[..split]);
+        drain(&mut decoder, &mut decoded);
+        decoder.extend(&stream[split..]);
+        drain(&mut decoder, &mut decoded);
+
+        assert_eq!(
+            decoded, messages,
+            "Frames split at byte {} were lost",
+            split
+        );
+        assert_eq!(decoder.buffered(), 0);
+    }
+}
+
+#[test]
+fn test_one_byte_at_a_time() {
+    let messages = sample_messages();
+    let stream: Vec<u8> = messages.iter().flat_map(codec::encode_frame).collect();
+
+    let mut decoder = FrameDecoder::new();
+    let mut decoded = Vec::new();
+    for byte in &stream {
+        decoder.extend(std::slice::from_ref(byte));
+        drain(&mut decoder, &mut decoded);
+    }
+    assert_eq!(decoded, messages);
+}
+
+/// Hands out its data in fixed-size chunks, failing with `WouldBlock` before each one
+struct TrickleReader {
+    data: Vec<u8>,
+    position: usize,
+    chunk: usize,
+    ready: bool,
+}
+
+impl Read for TrickleReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.ready {
+            self.ready = true;
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.ready = false;
+        let end = (self.position + self.chunk)
+            .min(self.data.len())
+            .min(self.position + buf.len());
+        let read = end - self.position;
+        buf[..read].copy_from_slice(&self.data[self.position..end]);
+        self.position = end;
+        Ok(read)
+    }
+}
+
+#[test]
+fn test_read_from_survives_would_block() {
+    let messages = sample_messages();
+    for chunk in [1, 3, 7, 64] {
+        let mut reader = TrickleReader {
+            data: messages.iter().flat_map(codec::encode_frame).collect(),
+            position: 0,
+            chunk,
+            ready: false,
+        };
+        let mut decoder = FrameDecoder::new();
+        let mut decoded = Vec::new();
+        let mut would_block = 0;
+
+        loop {
+            match decoder.read_from(&mut reader) {
+                Ok(0) => break,
+                Ok(_) => drain(&mut decoder, &mut decoded),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => would_block += 1,
+                Err(e) => panic!("Unexpected read error: {}", e),
+            }
+        }
+
+        assert!(would_block > 0);
+        assert_eq!(
+            decoded, messages,
+            "Frames read {} bytes at a time were lost",
+            chunk
+        );
+    }
+}
+
+#[test]
+fn test_bad_frames_are_reported_and_skipped() {
+    let messages = sample_messages();
+    let mut stream = Vec::new();
+    stream.extend_from_slice(&0u32.to_be_bytes()); // Empty frame
+    stream.extend_from_slice(&codec::encode_frame(&messages[0]));
+    stream.extend_from_slice(&3u32.to_be_bytes()); // Body that doesn't decode
+    stream.extend_from_slice(&[0xff, 0xff, 0xff]);
+    stream.extend_from_slice(&codec::encode_frame(&messages[1]));
+
+    let mut decoder = FrameDecoder::new();
+    decoder.extend(&stream);
+
+    let error = decoder.next_frame().unwrap().unwrap_err();
+    assert_eq!(error.code, ErrorCode::MalformedMessage);
+    assert_eq!(decoder.next_frame().unwrap().unwrap(), messages[0]);
+    let error = decoder.next_frame().unwrap().unwrap_err();
+    assert_eq!(error.code, ErrorCode::MalformedMessage);
+    assert_eq!(decoder.next_frame().unwrap().unwrap(), messages[1]);
+    assert!(decoder.next_frame().is_none());
+}
+
+#[test]
+fn test_oversized_frame_reported_before_its_body() {
+    let mut decoder = FrameDecoder::new();
+    decoder.extend(&[0x7f, 0xff]);
+    assert!(decoder.next_frame().is_none());
+
+    // The error comes as soon as the length prefix is complete
+    decoder.extend(&[0xff, 0xff]);
+    let error = decoder.next_frame().unwrap().unwrap_err();
+    assert_eq!(error.code, ErrorCode::FrameTooLarge);
+    assert!(error.closes_connection());
+}
+
+#[test]
+fn test_memory_is_charged_as_bytes_arrive() {
+    let budget = Arc::new(MemoryBudget::new(1024 * 1024));
+    let frame = codec::encode_frame(&sample_messages()[2]);
+
+    {
+        let mut decoder = FrameDecoder::with_limits(MAX_FRAME_LENGTH, 0, budget.clone());
+
+        // A prefix announcing the largest frame allowed reserves nothing by itself
+        decoder.extend(&(MAX_FRAME_LENGTH as u32).to_be_bytes());
+        assert!(decoder.next_frame().is_none());
+        assert_eq!(budget.used(), 4);
+        decoder.extend(&[0; 100]);
+        assert!(decoder.next_frame().is_none());
+        assert_eq!(budget.used(), 104);
+    }
+    // Dropping the decoder gives back whatever it held
+    assert_eq!(budget.used(), 0);
+
+    let mut decoder = FrameDecoder::with_limits(MAX_FRAME_LENGTH, 0, budget.clone());
+    decoder.extend(&frame[..10]);
+    assert!(decoder.next_frame().is_none());
+    assert_eq!(budget.used(), 10);
+    decoder.extend(&frame[10..]);
+    assert!(decoder.next_frame().unwrap().is_ok());
+    assert_eq!(budget.used(), 0);
+}
+
+#[test]
+fn test_memory_budget_exhausted() {
+    let budget = Arc::new(MemoryBudget::new(512));
+    let mut first = FrameDecoder::with_limits(MAX_FRAME_LENGTH, 0, budget.clone());
+    let mut second = FrameDecoder::with_limits(MAX_FRAME_LENGTH, 0, budget.clone());
+    let frame = codec::encode_frame(&sample_messages()[2]);
+
+    first.extend(&frame[..400]);
+    assert!(first.next_frame().is_none());
+
+    // Only 112 bytes are left for everyone else
+    second.extend(&frame[..200]);
+    let error = second.next_frame().unwrap().unwrap_err();
+    assert_eq!(error.code, ErrorCode::ResourceExhausted);
+    assert!(error.closes_connection());
+
+    drop(first);
+    assert_eq!(budget.used(), 0);
+}
+
+#[test]
+fn test_configured_max_frame_length() {
+    let budget = Arc::new(MemoryBudget::new(usize::MAX));
+    let mut decoder = FrameDecoder::with_limits(64, 0, budget);
+    let messages = sample_messages();
+
+    decoder.extend(&codec::encode_frame(&messages[0]));
+    assert_eq!(decoder.next_frame().unwrap().unwrap(), messages[0]);
+
+    decoder.extend(&codec::encode_frame(&messages[2]));
+    let error = decoder.next_frame().unwrap().unwrap_err();
+    assert_eq!(error.code, ErrorCode::FrameTooLarge);
+}
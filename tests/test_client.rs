@@ -1,12 +1,31 @@
-use embedded_recruitment_task::message::{client_message, ServerMessage};
+use embedded_recruitment_task::{
+    codec::{self, FrameFormat},
+    compression::{CompressionStats, FrameCompression, DEFAULT_COMPRESSION_THRESHOLD},
+    handshake::PROTOCOL_VERSION,
+    message::{
+        client_message, server_message, Capability, ClientMessage, Compression, Hello, HelloAck,
+        ServerMessage,
+    },
+    tls::TlsConfig,
+    transport::{TlsStream, Transport},
+};
 use log::error;
 use log::info;
-use prost::Message;
-use std::io::Read;
-use std::io::Write;
+use rcgen::{BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+use rustls::{
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+    RootCertStore,
+};
+use std::io::{Read, Write};
 use std::{
-    io,
+    fs, io,
     net::{SocketAddr, TcpStream, ToSocketAddrs},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 
@@ -15,7 +34,46 @@ pub struct TestClient {
     ip: String,
     port: u16,
     timeout: Duration,
-    stream: Option<TcpStream>,
+    stream: Option<Transport>,
+    /// Set to connect over TLS
+    tls: Option<Arc<rustls::ClientConfig>>,
+    format: FrameFormat,
+    /// `Hello` sent ahead of the first message, unless the test sends its own
+    pending_hello: Option<Hello>,
+    /// Whether the reply to `pending_hello` has yet to be read
+    awaiting_ack: bool,
+}
+
+/// The `Hello` a client sends unless a test asks for something else: the current protocol
+/// version, request IDs, and neither compression nor checksums
+pub fn default_hello() -> Hello {
+    Hello {
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: vec![Capability::RequestIds as i32],
+        ..Default::default()
+    }
+}
+
+/// Sends the default `Hello` over `stream` and reads the `HelloAck`, for tests that write
+/// frames by hand
+pub fn handshake(stream: &mut TcpStream) -> io::Result<HelloAck> {
+    exchange_hello(stream, default_hello())
+}
+
+fn exchange_hello<S: Read + Write>(stream: &mut S, hello: Hello) -> io::Result<HelloAck> {
+    let request = ClientMessage {
+        request_id: 0,
+        message: Some(client_message::Message::Hello(hello)),
+    };
+    codec::write_frame(stream, &request)?;
+    let response: ServerMessage = codec::read_frame(stream, codec::MAX_FRAME_LENGTH)?;
+    match response.message {
+        Some(server_message::Message::HelloAck(ack)) => Ok(ack),
+        other => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Expected HelloAck, but received {:?}", other),
+        )),
+    }
 }
 
 impl TestClient {
@@ -25,9 +83,39 @@ impl TestClient {
             port,
             timeout: Duration::from_millis(timeout_ms),
             stream: None,
+            tls: None,
+            format: FrameFormat::default(),
+            pending_hello: None,
+            awaiting_ack: false,
+        }
+    }
+
+    /// Wraps a stream that is already connected, TCP or Unix, leaving the handshake to the
+    /// test
+    pub fn from_stream(stream: impl Into<Transport>) -> Self {
+        let stream = stream.into();
+        let peer = match &stream {
+            Transport::Tcp(stream) => stream.peer_addr().ok(),
+            _ => None,
+        };
+        TestClient {
+            ip: peer.map(|addr| addr.ip().to_string()).unwrap_or_default(),
+            port: peer.map(|addr| addr.port()).unwrap_or_default(),
+            timeout: Duration::from_millis(1000),
+            stream: Some(stream),
+            tls: None,
+            format: FrameFormat::default(),
+            pending_hello: None,
+            awaiting_ack: false,
         }
     }
 
+    /// Connects over TLS, trusting the servers `config` trusts
+    pub fn with_tls(mut self, config: Arc<rustls::ClientConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
     // connect the client to the server
     pub fn connect(&mut self) -> io::Result<()> {
         println!("Connecting to {}:{}", self.ip, self.port);
@@ -45,7 +133,19 @@ impl TestClient {
 
         // Connect to the server with a timeout
         let stream = TcpStream::connect_timeout(&socket_addrs[0], self.timeout)?;
-        self.stream = Some(stream);
+        self.stream = Some(match &self.tls {
+            Some(config) => {
+                let name = ServerName::try_from(self.ip.clone())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                let session = rustls::ClientConnection::new(config.clone(), name)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                Transport::Tls(TlsStream::new(stream, session))
+            }
+            None => Transport::Tcp(stream),
+        });
+        self.format = FrameFormat::default();
+        self.pending_hello = Some(default_hello());
+        self.awaiting_ack = false;
 
         println!("Connected to the server!");
         Ok(())
@@ -62,19 +162,24 @@ impl TestClient {
     }
 
     pub fn send(&mut self, message: client_message::Message) -> io::Result<()> {
-        if let Some(ref mut stream) = self.stream {
-            // Encode the message to a buffer
-            let mut message_buf = Vec::new();
-            message.encode(&mut message_buf);
-            // Write length prefix (4 bytes, big-endian)
-            let length = message_buf.len() as u32;
+        self.send_with_id(0, message)
+    }
 
-            stream.write_all(&length.to_be_bytes())?;
-            // Write the message
-            stream.write_all(&message_buf)?;
-            stream.flush()?;
+    /// Sends `message` tagged with `request_id`, so its reply can be matched out of order
+    pub fn send_with_id(
+        &mut self,
+        request_id: u64,
+        message: client_message::Message,
+    ) -> io::Result<()> {
+        self.send_pending_hello()?;
+        if let Some(ref mut stream) = self.stream {
+            let request = ClientMessage {
+                request_id,
+                message: Some(message),
+            };
+            codec::write_frame_with(stream, &request, &self.format)?;
 
-            println!("Sent message: {:?}", message);
+            println!("Sent message: {:?}", request);
             Ok(())
         } else {
             Err(io::Error::new(
@@ -84,42 +189,104 @@ impl TestClient {
         }
     }
 
-    pub fn receive(&mut self) -> io::Result<ServerMessage> {
-        if let Some(ref mut stream) = self.stream {
-            println!("Receiving message from the server");
-
-            // Read message length (4 bytes)
-            let mut length_buf = [0u8; 4];
-            stream.read_exact(&mut length_buf)?;
+    /// Sends the default `Hello` ahead of the first message. Its `HelloAck` is read with
+    /// the first reply.
+    fn send_pending_hello(&mut self) -> io::Result<()> {
+        if let (Some(hello), Some(stream)) = (self.pending_hello.take(), self.stream.as_mut()) {
+            let request = ClientMessage {
+                request_id: 0,
+                message: Some(client_message::Message::Hello(hello)),
+            };
+            codec::write_frame(stream, &request)?;
+            self.awaiting_ack = true;
+        }
+        Ok(())
+    }
 
-            let message_length = u32::from_be_bytes(length_buf) as usize;
+    /// Sends a `Hello` offering `offered` and switches to the compression the server picks
+    pub fn hello(&mut self, offered: &[Compression]) -> io::Result<Compression> {
+        let ack = self.hello_with(Hello {
+            compression: offered.iter().map(|&algorithm| algorithm as i32).collect(),
+            ..default_hello()
+        })?;
+        Ok(ack.compression())
+    }
 
-            println!("Length: {}", message_length.to_string());
+    /// Sends `hello` in place of the default one and switches to the frame format the server
+    /// agrees on
+    pub fn hello_with(&mut self, hello: Hello) -> io::Result<HelloAck> {
+        self.pending_hello = None;
+        let Some(ref mut stream) = self.stream else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "No active connection",
+            ));
+        };
+        let ack = exchange_hello(stream, hello)?;
+        self.agree(&ack);
+        Ok(ack)
+    }
 
-            // Sanity check the message length
-            if message_length == 0 || message_length > 1024 * 1024 {
-                // 1MB limit
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "Invalid message length received",
-                ));
-            }
+    fn agree(&mut self, ack: &HelloAck) {
+        let compression = (ack.compression() != Compression::None).then(|| {
+            FrameCompression::new(
+                ack.compression(),
+                DEFAULT_COMPRESSION_THRESHOLD,
+                Arc::new(CompressionStats::default()),
+            )
+        });
+        self.format = FrameFormat {
+            compression,
+            checksum: ack
+                .capabilities()
+                .any(|capability| capability == Capability::Checksums),
+        };
+    }
 
-            // Read the exact message bytes
-            let mut message_buf = vec![0u8; message_length];
+    /// Sizes of the frames sent and received since compression was agreed on
+    pub fn compression_stats(&self) -> Option<&CompressionStats> {
+        self.format
+            .compression
+            .as_ref()
+            .map(FrameCompression::stats)
+    }
 
-            stream.read_exact(&mut message_buf)?;
-            println!("Message buffer :{:?}", message_buf);
+    /// Sends `payload` behind an arbitrary length prefix, for exercising malformed frames
+    pub fn send_raw(&mut self, length: u32, payload: &[u8]) -> io::Result<()> {
+        self.send_pending_hello()?;
+        if let Some(ref mut stream) = self.stream {
+            stream.write_all(&length.to_be_bytes())?;
+            stream.write_all(payload)?;
+            stream.flush()
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "No active connection",
+            ))
+        }
+    }
 
-            info!("Received {} bytes from the server", message_length);
+    pub fn receive(&mut self) -> io::Result<ServerMessage> {
+        if self.awaiting_ack {
+            self.awaiting_ack = false;
+            // A refused handshake, or a server turning the connection away, answers the
+            // Hello with an error the test should see
+            let response = self.read()?;
+            match response.message {
+                Some(server_message::Message::HelloAck(ack)) => self.agree(&ack),
+                _ => return Ok(response),
+            }
+        }
+        self.read()
+    }
 
-            // Decode the received message
-            ServerMessage::decode(&message_buf[..]).map_err(|e| {
-                io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("Failed to decode ServerMessage: {}", e),
-                )
-            })
+    fn read(&mut self) -> io::Result<ServerMessage> {
+        if let Some(ref mut stream) = self.stream {
+            println!("Receiving message from the server");
+            let message: ServerMessage =
+                codec::read_frame_with(stream, codec::MAX_FRAME_LENGTH, &self.format)?;
+            info!("Received {:?} from the server", message);
+            Ok(message)
         } else {
             error!("No active connection");
             Err(io::Error::new(
@@ -129,3 +296,85 @@ impl TestClient {
         }
     }
 }
+
+/// Serial number of the directories [`TestPki`] writes to
+static PKI_DIRS: AtomicUsize = AtomicUsize::new(0);
+
+/// A throwaway CA with a server certificate for `localhost` and a client certificate, all
+/// written as PEM files to a fresh directory
+pub struct TestPki {
+    pub dir: PathBuf,
+}
+
+impl TestPki {
+    pub fn generate() -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "embedded-recruitment-task-pki-{}-{}",
+            std::process::id(),
+            PKI_DIRS.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir).expect("Failed to create PKI directory");
+
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params
+            .distinguished_name
+            .push(DnType::CommonName, "Test CA");
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+
+        for (name, subject, usage) in [
+            ("server", "localhost", ExtendedKeyUsagePurpose::ServerAuth),
+            ("client", "test-client", ExtendedKeyUsagePurpose::ClientAuth),
+        ] {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec![subject.to_string()]).unwrap();
+            params.distinguished_name.push(DnType::CommonName, subject);
+            params.extended_key_usages = vec![usage];
+            let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+            fs::write(dir.join(format!("{}.pem", name)), cert.pem()).unwrap();
+            fs::write(dir.join(format!("{}.key", name)), key.serialize_pem()).unwrap();
+        }
+        TestPki { dir }
+    }
+
+    /// Server side, optionally requiring client certificates
+    pub fn server_config(&self, client_auth: bool) -> TlsConfig {
+        let config = TlsConfig::new(self.dir.join("server.pem"), self.dir.join("server.key"));
+        match client_auth {
+            true => config.with_client_auth(self.dir.join("ca.pem")),
+            false => config,
+        }
+    }
+
+    /// Client side trusting the CA, optionally presenting the client certificate
+    pub fn client_config(&self, client_cert: bool) -> Arc<rustls::ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(self.dir.join("ca.pem")).unwrap() {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let builder =
+            rustls::ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots);
+        let config = if client_cert {
+            let chain = CertificateDer::pem_file_iter(self.dir.join("client.pem"))
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            let key = PrivateKeyDer::from_pem_file(self.dir.join("client.key")).unwrap();
+            builder.with_client_auth_cert(chain, key).unwrap()
+        } else {
+            builder.with_no_client_auth()
+        };
+        Arc::new(config)
+    }
+}
+
+impl Drop for TestPki {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}